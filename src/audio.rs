@@ -0,0 +1,544 @@
+mod envelope;
+mod length;
+mod noise;
+mod resampler;
+mod sink;
+mod square;
+mod wave;
+
+use crate::audio::length::LengthCounter;
+use crate::audio::noise::NoiseChannel;
+use crate::audio::resampler::Resampler;
+use crate::audio::square::SquareChannel;
+use crate::audio::wave::WaveChannel;
+use crate::state::{StateReader, StateWriter};
+
+pub use crate::audio::sink::{AudioOutput, AudioSink, FileSink, NullSink};
+
+pub(crate) const AUDIO_BEGIN: usize = 0xFF10;
+pub(crate) const AUDIO_END: usize = 0xFF3F;
+
+const NR10_ADDRESS: usize = 0xFF10;
+const NR11_ADDRESS: usize = 0xFF11;
+const NR14_ADDRESS: usize = 0xFF14;
+const NR21_ADDRESS: usize = 0xFF16;
+const NR24_ADDRESS: usize = 0xFF19;
+const NR30_ADDRESS: usize = 0xFF1A;
+const NR31_ADDRESS: usize = 0xFF1B;
+const NR34_ADDRESS: usize = 0xFF1E;
+const NR41_ADDRESS: usize = 0xFF20;
+const NR44_ADDRESS: usize = 0xFF23;
+const NR50_ADDRESS: usize = 0xFF24;
+const NR51_ADDRESS: usize = 0xFF25;
+const NR52_ADDRESS: usize = 0xFF26;
+const WAVE_RAM_BEGIN: usize = 0xFF30;
+const WAVE_RAM_END: usize = 0xFF3F;
+
+const NR52_POWER: u8 = 1 << 7;
+
+// Bits that can't be read back always read as 1, from NR10 to NR52.
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,
+    0x00, 0x00, 0x70,
+];
+
+pub(crate) const CPU_FREQUENCY: u32 = 4_194_304;
+// The channels are stepped once per M-cycle.
+const INTERNAL_RATE: u32 = CPU_FREQUENCY / 4;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+pub const CHANNEL_COUNT: usize = 4;
+pub const CHANNEL_NAMES: [&str; CHANNEL_COUNT] = ["square1", "square2", "wave", "noise"];
+
+/// What a channel is playing, for display.
+#[derive(Copy, Clone, Debug)]
+pub struct ChannelStatus {
+    pub playing: bool,
+    /// Pitch in Hz, or shift rate of the LFSR for the noise channel.
+    pub frequency: f32,
+    /// From 0 to 15.
+    pub volume: u8,
+}
+
+/// The audio processing unit.
+///
+/// Four channels are mixed to the left and right outputs according to
+/// NR51, and their volume is set by NR50. Length counters, envelopes and
+/// the sweep are clocked by the frame sequencer, itself clocked at 512 Hz
+/// by the timer divider. The output is resampled from the M-cycle rate to
+/// `sample_rate` and waits in the resampler until it is taken.
+pub struct APU {
+    square1: SquareChannel,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    registers: [u8; 0x17],
+    powered: bool,
+    frame_sequencer_step: u8,
+    // Muted channels are left out of the mix, but not out of channel taps.
+    muted: [bool; CHANNEL_COUNT],
+    sample_rate: u32,
+    resampler: Resampler,
+    // Resample each channel on its own once channel taps are turned on.
+    channel_resamplers: Option<Box<[Resampler; CHANNEL_COUNT]>>,
+}
+
+impl APU {
+    pub(crate) fn new(sample_rate: u32) -> APU {
+        APU {
+            square1: SquareChannel::new(true),
+            square2: SquareChannel::new(false),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            registers: [0; 0x17],
+            powered: false,
+            frame_sequencer_step: 0,
+            muted: [false; CHANNEL_COUNT],
+            sample_rate,
+            resampler: Resampler::new(INTERNAL_RATE, sample_rate),
+            channel_resamplers: None,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.resampler = self.new_resampler();
+        if self.channel_resamplers.is_some() {
+            self.tap_channels(true);
+        }
+    }
+
+    fn new_resampler(&self) -> Resampler {
+        Resampler::new(INTERNAL_RATE, self.sample_rate)
+    }
+
+    /// Stereo samples, from -1.0 to 1.0, produced since the last call.
+    pub(crate) fn take_samples(&mut self) -> Vec<[f32; 2]> {
+        self.resampler.take_output()
+    }
+
+    pub fn muted(&self) -> [bool; CHANNEL_COUNT] {
+        self.muted
+    }
+
+    pub fn set_muted(&mut self, muted: [bool; CHANNEL_COUNT]) {
+        self.muted = muted;
+    }
+
+    pub fn toggle_mute(&mut self, channel: usize) {
+        self.muted[channel] = !self.muted[channel];
+    }
+
+    /// Mutes every other channel, or unmutes them all if the channel was
+    /// already playing alone.
+    pub fn toggle_solo(&mut self, channel: usize) {
+        let alone = std::array::from_fn(|index| index != channel);
+        self.muted = if self.muted == alone { [false; CHANNEL_COUNT] } else { alone };
+    }
+
+    pub fn channels_tapped(&self) -> bool {
+        self.channel_resamplers.is_some()
+    }
+
+    /// Starts or stops keeping each channel's stereo samples on the side.
+    pub fn tap_channels(&mut self, enabled: bool) {
+        self.channel_resamplers = enabled.then(|| Box::new(std::array::from_fn(|_| self.new_resampler())));
+    }
+
+    /// Each channel's stereo samples produced since the last call, in the
+    /// order square 1, square 2, wave and noise. Empty unless channel taps
+    /// are turned on.
+    pub(crate) fn take_channel_samples(&mut self) -> Vec<[[f32; 2]; CHANNEL_COUNT]> {
+        let Some(resamplers) = &mut self.channel_resamplers else {
+            return Vec::new();
+        };
+        let outputs = resamplers.each_mut().map(Resampler::take_output);
+        (0..outputs[0].len()).map(|index| std::array::from_fn(|channel| outputs[channel][index])).collect()
+    }
+
+    pub(crate) fn read(&self, address: usize) -> u8 {
+        match address {
+            NR52_ADDRESS => {
+                let channels = [self.square1.enabled, self.square2.enabled, self.wave.enabled, self.noise.enabled];
+                let status = channels.iter().enumerate().fold(0, |status, (index, &on)| status | (on as u8) << index);
+                READ_MASKS[NR52_ADDRESS - NR10_ADDRESS] | (self.powered as u8) << 7 | status
+            }
+            NR10_ADDRESS..=NR51_ADDRESS => self.registers[address - NR10_ADDRESS] | READ_MASKS[address - NR10_ADDRESS],
+            WAVE_RAM_BEGIN..=WAVE_RAM_END => self.wave.read_wave_ram(address - WAVE_RAM_BEGIN),
+            _ => 0xFF,
+        }
+    }
+
+    pub(crate) fn write(&mut self, address: usize, value: u8) {
+        // The next step of the frame sequencer doesn't clock lengths.
+        let extra_length_clock = !self.frame_sequencer_step.is_multiple_of(2);
+        match address {
+            NR52_ADDRESS => self.set_power(value & NR52_POWER != 0),
+            WAVE_RAM_BEGIN..=WAVE_RAM_END => self.wave.write_wave_ram(address - WAVE_RAM_BEGIN, value),
+            // Registers can't be written while the APU is off, except for
+            // the lengths on the DMG.
+            NR11_ADDRESS | NR21_ADDRESS | NR41_ADDRESS if !self.powered => {
+                self.length_counter(address).load(value & 0x3F);
+            }
+            NR31_ADDRESS if !self.powered => self.wave.length.load(value),
+            _ if !self.powered => {}
+            NR10_ADDRESS..=NR51_ADDRESS => {
+                self.registers[address - NR10_ADDRESS] = value;
+                match address {
+                    NR10_ADDRESS..=NR14_ADDRESS => self.square1.write(address - NR10_ADDRESS, value, extra_length_clock),
+                    NR21_ADDRESS..=NR24_ADDRESS => self.square2.write(address + 1 - NR21_ADDRESS, value, extra_length_clock),
+                    NR30_ADDRESS..=NR34_ADDRESS => self.wave.write(address - NR30_ADDRESS, value, extra_length_clock),
+                    NR41_ADDRESS..=NR44_ADDRESS => self.noise.write(address + 1 - NR41_ADDRESS, value, extra_length_clock),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn length_counter(&mut self, address: usize) -> &mut LengthCounter {
+        match address {
+            NR11_ADDRESS => &mut self.square1.length,
+            NR21_ADDRESS => &mut self.square2.length,
+            NR31_ADDRESS => &mut self.wave.length,
+            _ => &mut self.noise.length,
+        }
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if on && !self.powered {
+            self.frame_sequencer_step = 0;
+        } else if !on && self.powered {
+            // Turning the APU off clears every register, but neither wave
+            // RAM nor, on the DMG, the length counters.
+            let wave_ram = self.wave.wave_ram;
+            let lengths = [self.square1.length.counter, self.square2.length.counter, self.wave.length.counter, self.noise.length.counter];
+            self.square1 = SquareChannel::new(true);
+            self.square2 = SquareChannel::new(false);
+            self.wave = WaveChannel::new();
+            self.wave.wave_ram = wave_ram;
+            self.noise = NoiseChannel::new();
+            self.square1.length.counter = lengths[0];
+            self.square2.length.counter = lengths[1];
+            self.wave.length.counter = lengths[2];
+            self.noise.length.counter = lengths[3];
+            self.registers = [0; 0x17];
+        }
+        self.powered = on;
+    }
+
+    /// Advances the APU by `cycles` clock cycles. `frame_sequencer_ticks`
+    /// is how many times the 512 Hz divider bit fell meanwhile.
+    pub(crate) fn step(&mut self, cycles: u16, frame_sequencer_ticks: u8) {
+        if self.powered {
+            for _ in 0..frame_sequencer_ticks {
+                self.clock_frame_sequencer();
+            }
+        }
+        for _ in 0..cycles / 4 {
+            if self.powered {
+                self.square1.step(4);
+                self.square2.step(4);
+                self.wave.step(4);
+                self.noise.step(4);
+            }
+            let channels = self.mix_channels();
+            let mixed = channels
+                .iter()
+                .zip(self.muted)
+                .filter(|(_, muted)| !muted)
+                .fold([0.0; 2], |sum, (channel, _)| [sum[0] + channel[0], sum[1] + channel[1]]);
+            self.resampler.push(mixed);
+            if let Some(resamplers) = &mut self.channel_resamplers {
+                for (resampler, &channel) in resamplers.iter_mut().zip(channels.iter()) {
+                    resampler.push(channel);
+                }
+            }
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step.is_multiple_of(2) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.square1.clock_sweep();
+        }
+        if step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    pub fn channel_statuses(&self) -> [ChannelStatus; CHANNEL_COUNT] {
+        [
+            ChannelStatus { playing: self.square1.enabled, frequency: self.square1.pitch(), volume: self.square1.envelope.volume },
+            ChannelStatus { playing: self.square2.enabled, frequency: self.square2.pitch(), volume: self.square2.envelope.volume },
+            ChannelStatus { playing: self.wave.enabled, frequency: self.wave.pitch(), volume: self.wave.volume() },
+            ChannelStatus { playing: self.noise.enabled, frequency: self.noise.pitch(), volume: self.noise.envelope.volume },
+        ]
+    }
+
+    /// What each channel's DAC outputs, from -1.0 to 1.0.
+    pub(crate) fn channel_outputs(&self) -> [f32; CHANNEL_COUNT] {
+        let channels = [
+            (self.square1.dac_enabled(), self.square1.output()),
+            (self.square2.dac_enabled(), self.square2.output()),
+            (self.wave.dac_enabled(), self.wave.output()),
+            (self.noise.dac_enabled(), self.noise.output()),
+        ];
+        // A DAC that is off outputs nothing, one that is on maps 0..15 to
+        // 1.0..-1.0.
+        channels.map(|(dac_enabled, output)| if dac_enabled { 1.0 - output as f32 / 7.5 } else { 0.0 })
+    }
+
+    /// Each channel's contribution to the left and right outputs, which
+    /// add up to the mixed sample.
+    fn mix_channels(&self) -> [[f32; 2]; CHANNEL_COUNT] {
+        let mut channels = [[0.0; 2]; CHANNEL_COUNT];
+        if !self.powered {
+            return channels;
+        }
+        let outputs = self.channel_outputs();
+        let nr50 = self.registers[NR50_ADDRESS - NR10_ADDRESS];
+        let nr51 = self.registers[NR51_ADDRESS - NR10_ADDRESS];
+        // NR51 has the right enable bits in its low nibble, the left ones
+        // in its high nibble, and NR50 the volumes the other way round.
+        for (side, (panning, volume)) in [(nr51 >> 4, (nr50 >> 4) & 0x07), (nr51, nr50 & 0x07)].into_iter().enumerate() {
+            for (channel, output) in outputs.iter().enumerate() {
+                if panning >> channel & 1 != 0 {
+                    channels[channel][side] = output / CHANNEL_COUNT as f32 * (volume + 1) as f32 / 8.0;
+                }
+            }
+        }
+        channels
+    }
+
+    /// Saves the hardware state. Mutes, channel taps and resampling belong
+    /// to the host and are left as they are.
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.registers);
+        state.bool(self.powered);
+        state.u8(self.frame_sequencer_step);
+        self.square1.save_state(state);
+        self.square2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes(&mut self.registers)?;
+        self.powered = state.bool()?;
+        self.frame_sequencer_step = state.u8()?;
+        self.square1.load_state(state)?;
+        self.square2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)
+    }
+}
+
+impl Default for APU {
+    fn default() -> APU {
+        APU::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NR12_ADDRESS: usize = 0xFF12;
+    const NR32_ADDRESS: usize = 0xFF1C;
+    const NR33_ADDRESS: usize = 0xFF1D;
+
+    fn powered_apu() -> APU {
+        let mut apu = APU::default();
+        apu.write(NR52_ADDRESS, NR52_POWER);
+        apu
+    }
+
+    fn clock_frame_sequencer(apu: &mut APU, times: u8) {
+        apu.step(0, times);
+    }
+
+    fn square1_playing(apu: &APU) -> bool {
+        apu.read(NR52_ADDRESS) & 1 != 0
+    }
+
+    /// How many length clocks it takes square 1 to stop.
+    fn square1_length(apu: &mut APU) -> u32 {
+        let mut clocks = 0;
+        while square1_playing(apu) {
+            // Only even steps clock lengths.
+            clock_frame_sequencer(apu, 2);
+            clocks += 1;
+        }
+        clocks
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = powered_apu();
+        apu.write(WAVE_RAM_BEGIN, 0x12);
+        for address in NR10_ADDRESS..NR52_ADDRESS {
+            apu.write(address, 0xFF);
+        }
+        apu.write(NR52_ADDRESS, 0);
+
+        for address in NR10_ADDRESS..NR52_ADDRESS {
+            assert_eq!(apu.read(address), READ_MASKS[address - NR10_ADDRESS], "{:X}", address);
+        }
+        assert_eq!(apu.read(NR52_ADDRESS), 0x70);
+        assert_eq!(apu.read(WAVE_RAM_BEGIN), 0x12);
+    }
+
+    #[test]
+    fn registers_ignore_writes_while_powered_off() {
+        let mut apu = APU::default();
+        apu.write(NR50_ADDRESS, 0x77);
+        apu.write(NR12_ADDRESS, 0xF0);
+        apu.write(NR14_ADDRESS, 0x80);
+        assert_eq!(apu.read(NR50_ADDRESS), 0x00);
+        assert_eq!(apu.read(NR52_ADDRESS), 0x70);
+    }
+
+    #[test]
+    fn lengths_survive_power_off_and_can_be_written_while_off() {
+        let mut apu = APU::default();
+        apu.write(NR11_ADDRESS, 0xC0 | 60);
+        // Duty bits are not written.
+        assert_eq!(apu.read(NR11_ADDRESS), 0x3F);
+        apu.write(NR52_ADDRESS, NR52_POWER);
+        apu.write(NR12_ADDRESS, 0xF0);
+        apu.write(NR14_ADDRESS, 0xC0);
+        assert_eq!(square1_length(&mut apu), 4);
+    }
+
+    #[test]
+    fn lengths_are_clocked_at_256_hz_by_the_divider() {
+        let mut timer = crate::timer::Timer::new();
+        let mut apu = powered_apu();
+        apu.write(NR12_ADDRESS, 0xF0);
+        apu.write(NR11_ADDRESS, 62);
+        apu.write(NR14_ADDRESS, 0xC0);
+        let mut cycles = 0;
+        while square1_playing(&apu) {
+            timer.step(4);
+            apu.step(4, timer.take_frame_sequencer_ticks());
+            cycles += 4;
+        }
+        // Steps 0 and 2 clock lengths, on the first and third falling edge
+        // of DIV bit 12.
+        assert_eq!(cycles, 3 * 8192);
+    }
+
+    #[test]
+    fn enabling_length_clocks_it_if_the_next_step_does_not() {
+        let mut apu = powered_apu();
+        apu.write(NR12_ADDRESS, 0xF0);
+        apu.write(NR11_ADDRESS, 62);
+        apu.write(NR14_ADDRESS, 0x80);
+        // Step 0 done, the next one doesn't clock lengths.
+        clock_frame_sequencer(&mut apu, 1);
+        apu.write(NR14_ADDRESS, 0x40);
+        assert!(square1_playing(&apu));
+        apu.write(NR14_ADDRESS, 0x00);
+        apu.write(NR14_ADDRESS, 0x40);
+        assert!(!square1_playing(&apu));
+    }
+
+    #[test]
+    fn enabling_length_right_before_a_length_step_does_not_clock_it() {
+        let mut apu = powered_apu();
+        apu.write(NR12_ADDRESS, 0xF0);
+        apu.write(NR11_ADDRESS, 63);
+        apu.write(NR14_ADDRESS, 0x80);
+        clock_frame_sequencer(&mut apu, 2);
+        apu.write(NR14_ADDRESS, 0x40);
+        assert!(square1_playing(&apu));
+    }
+
+    #[test]
+    fn trigger_reloads_an_empty_length_minus_the_extra_clock() {
+        let mut apu = powered_apu();
+        apu.write(NR12_ADDRESS, 0xF0);
+        apu.write(NR11_ADDRESS, 63);
+        apu.write(NR14_ADDRESS, 0xC0);
+        assert_eq!(square1_length(&mut apu), 1);
+        clock_frame_sequencer(&mut apu, 1);
+        apu.write(NR14_ADDRESS, 0xC0);
+        assert_eq!(square1_length(&mut apu), 63);
+
+        // This time the next step clocks lengths.
+        clock_frame_sequencer(&mut apu, 1);
+        apu.write(NR14_ADDRESS, 0xC0);
+        assert_eq!(square1_length(&mut apu), 64);
+    }
+
+    #[test]
+    fn envelope_zombie_mode() {
+        let mut apu = powered_apu();
+        let volume = |apu: &APU| apu.channel_statuses()[0].volume;
+        apu.write(NR12_ADDRESS, 0x18);
+        apu.write(NR14_ADDRESS, 0x80);
+        assert_eq!(volume(&apu), 1);
+        // Period 0 and still running: +1.
+        apu.write(NR12_ADDRESS, 0x18);
+        assert_eq!(volume(&apu), 2);
+        // +1, then the direction changes: 16 - 3.
+        apu.write(NR12_ADDRESS, 0x10);
+        assert_eq!(volume(&apu), 13);
+        apu.write(NR12_ADDRESS, 0x11);
+        assert_eq!(volume(&apu), 14);
+        // Decreasing with a period: +2, wrapping at 16.
+        apu.write(NR12_ADDRESS, 0x11);
+        assert_eq!(volume(&apu), 0);
+    }
+
+    #[test]
+    fn wave_ram_is_only_reachable_as_the_channel_reads_it() {
+        let mut apu = powered_apu();
+        for index in 0..WAVE_RAM_END - WAVE_RAM_BEGIN + 1 {
+            apu.write(WAVE_RAM_BEGIN + index, index as u8);
+        }
+        apu.write(NR30_ADDRESS, 0x80);
+        apu.write(NR32_ADDRESS, 0x20);
+        apu.write(NR33_ADDRESS, 0x00);
+        apu.write(NR34_ADDRESS, 0x87);
+
+        let mut reads = Vec::new();
+        for _ in 0..2048 {
+            apu.step(4, 0);
+            reads.push(apu.read(WAVE_RAM_END));
+        }
+        // The channel reads a sample every 512 cycles, that is every 128
+        // reads, two samples per byte.
+        let hits: Vec<u8> = reads.into_iter().filter(|&read| read != 0xFF).collect();
+        assert_eq!(hits, [0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7]);
+
+        apu.write(NR30_ADDRESS, 0x00);
+        assert_eq!(apu.read(WAVE_RAM_END), 15);
+    }
+
+    #[test]
+    fn solo_mutes_the_other_channels_until_pressed_again() {
+        let mut apu = APU::default();
+        apu.toggle_mute(0);
+        apu.toggle_solo(2);
+        assert_eq!(apu.muted(), [true, true, false, true]);
+        apu.toggle_solo(2);
+        assert_eq!(apu.muted(), [false; CHANNEL_COUNT]);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::state::{StateReader, StateWriter};
+
+/// Raises or lowers the volume of a channel over time, clocked at 64 Hz.
+pub(crate) struct Envelope {
+    pub(crate) initial_volume: u8,
+    pub(crate) increase: bool,
+    pub(crate) period: u8,
+    pub(crate) volume: u8,
+    timer: u8,
+    // Cleared once the volume can't go further, the envelope then stays
+    // still until the next trigger.
+    running: bool,
+}
+
+impl Envelope {
+    pub(crate) fn new() -> Envelope {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+            running: false,
+        }
+    }
+
+    /// Reads NRx2.
+    ///
+    /// Writing while the channel plays pokes the volume in "zombie mode":
+    /// it goes up by 1 if the envelope was still running with a period of
+    /// 0, by 2 otherwise if it was decreasing, and is flipped to 16 minus
+    /// itself when the direction changes, all of that wrapping at 16.
+    pub(crate) fn write(&mut self, value: u8, channel_enabled: bool) {
+        let increase = value & 0x08 != 0;
+        if channel_enabled {
+            if self.period == 0 && self.running {
+                self.volume += 1;
+            } else if !self.increase {
+                self.volume += 2;
+            }
+            if increase != self.increase {
+                self.volume = 16u8.wrapping_sub(self.volume);
+            }
+            self.volume &= 0x0F;
+        }
+        self.initial_volume = value >> 4;
+        self.increase = increase;
+        self.period = value & 0x07;
+    }
+
+    /// The DAC is off when NRx2 has neither a volume nor an increasing
+    /// envelope, which also turns the channel off.
+    pub(crate) fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    pub(crate) fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+        self.running = true;
+    }
+
+    pub(crate) fn clock(&mut self) {
+        if self.period == 0 || !self.running {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            } else {
+                self.running = false;
+            }
+        }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.initial_volume);
+        state.bool(self.increase);
+        state.u8(self.period);
+        state.u8(self.volume);
+        state.u8(self.timer);
+        state.bool(self.running);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.initial_volume = state.u8()?;
+        self.increase = state.bool()?;
+        self.period = state.u8()?;
+        self.volume = state.u8()?;
+        self.timer = state.u8()?;
+        self.running = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triggered(value: u8) -> Envelope {
+        let mut envelope = Envelope::new();
+        envelope.write(value, false);
+        envelope.trigger();
+        envelope
+    }
+
+    fn volumes(envelope: &mut Envelope, clocks: usize) -> Vec<u8> {
+        (0..clocks)
+            .map(|_| {
+                envelope.clock();
+                envelope.volume
+            })
+            .collect()
+    }
+
+    #[test]
+    fn volume_steps_once_per_period() {
+        // Volume 3, decreasing every 2 clocks.
+        let mut envelope = triggered(0x32);
+        assert_eq!(envelope.volume, 3);
+        assert_eq!(volumes(&mut envelope, 8), [3, 2, 2, 1, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn volume_stops_at_fifteen() {
+        let mut envelope = triggered(0xD9);
+        assert_eq!(volumes(&mut envelope, 4), [14, 15, 15, 15]);
+    }
+
+    #[test]
+    fn a_period_of_zero_keeps_the_volume() {
+        let mut envelope = triggered(0x80);
+        assert_eq!(volumes(&mut envelope, 16), [8; 16]);
+    }
+
+    #[test]
+    fn dac_needs_a_volume_or_an_increase() {
+        assert!(!triggered(0x07).dac_enabled());
+        assert!(triggered(0x08).dac_enabled());
+        assert!(triggered(0x10).dac_enabled());
+    }
+}
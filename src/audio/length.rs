@@ -0,0 +1,102 @@
+use crate::state::{StateReader, StateWriter};
+
+/// Silences a channel after a given time, clocked at 256 Hz.
+pub(crate) struct LengthCounter {
+    pub(crate) enabled: bool,
+    pub(crate) counter: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    pub(crate) fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    /// NRx1 holds the length to subtract from the maximum.
+    pub(crate) fn load(&mut self, length: u8) {
+        self.counter = self.max - length as u16;
+    }
+
+    /// Writes the enable bit of NRx4, and returns whether the channel has
+    /// to be turned off. `extra_clock` is set when the next frame sequencer
+    /// step won't clock lengths: enabling the counter then clocks it once
+    /// right away.
+    pub(crate) fn set_enabled(&mut self, enabled: bool, extra_clock: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enabled;
+        if !was_enabled && enabled && extra_clock && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    /// A counter that ran out starts over from the maximum, minus the extra
+    /// clock if it applies.
+    pub(crate) fn trigger(&mut self, extra_clock: bool) {
+        if self.counter == 0 {
+            self.counter = self.max;
+            if self.enabled && extra_clock {
+                self.counter -= 1;
+            }
+        }
+    }
+
+    /// Returns whether the channel has to be turned off.
+    pub(crate) fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u16(self.counter);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.enabled = state.bool()?;
+        self.counter = state.u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_after_the_remaining_length() {
+        let mut length = LengthCounter::new(64);
+        length.load(60);
+        length.set_enabled(true, false);
+        assert_eq!((0..4).map(|_| length.clock()).collect::<Vec<_>>(), [false, false, false, true]);
+        // It only expires once.
+        assert!(!length.clock());
+        assert_eq!(length.counter, 0);
+    }
+
+    #[test]
+    fn disabled_counters_do_not_count() {
+        let mut length = LengthCounter::new(256);
+        length.load(255);
+        assert!(!length.clock());
+        assert_eq!(length.counter, 1);
+    }
+
+    #[test]
+    fn triggering_reloads_an_expired_counter() {
+        let mut length = LengthCounter::new(256);
+        length.trigger(false);
+        assert_eq!(length.counter, 256);
+        length.load(10);
+        length.trigger(false);
+        assert_eq!(length.counter, 246);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::audio::envelope::Envelope;
+use crate::audio::length::LengthCounter;
+use crate::state::{StateReader, StateWriter};
+
+const DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Channel 4, white noise out of a linear feedback shift register.
+pub(crate) struct NoiseChannel {
+    pub(crate) enabled: bool,
+    pub(crate) length: LengthCounter,
+    pub(crate) envelope: Envelope,
+    shift: u8,
+    short_mode: bool,
+    divisor: u8,
+    timer: i32,
+    lfsr: u16,
+}
+
+impl NoiseChannel {
+    pub(crate) fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            shift: 0,
+            short_mode: false,
+            divisor: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    /// Writes NR41 to NR44, `register` going from 1 to 4 as there is no
+    /// NR40.
+    /// `extra_length_clock` is set when the next frame sequencer step won't
+    /// clock lengths.
+    pub(crate) fn write(&mut self, register: usize, value: u8, extra_length_clock: bool) {
+        match register {
+            0 => {}
+            1 => self.length.load(value & 0x3F),
+            2 => {
+                self.envelope.write(value, self.enabled);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.shift = value >> 4;
+                self.short_mode = value & 0x08 != 0;
+                self.divisor = value & 0x07;
+            }
+            _ => {
+                if self.length.set_enabled(value & 0x40 != 0, extra_length_clock) {
+                    self.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.trigger(extra_length_clock);
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self, extra_length_clock: bool) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(extra_length_clock);
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn period(&self) -> i32 {
+        DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    pub(crate) fn step(&mut self, cycles: u16) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.short_mode {
+                // The feedback also goes to bit 6, making a 7-bit register
+                // with a more metallic sound.
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+    }
+
+    pub(crate) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// The value fed to the DAC, from 0 to 15.
+    pub(crate) fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 != 0 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+
+    /// How many times per second the LFSR is shifted.
+    pub(crate) fn pitch(&self) -> f32 {
+        4194304.0 / self.period() as f32
+    }
+
+    pub(crate) fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.u8(self.shift);
+        state.bool(self.short_mode);
+        state.u8(self.divisor);
+        state.i32(self.timer);
+        state.u16(self.lfsr);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.enabled = state.bool()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.shift = state.u8()?;
+        self.short_mode = state.bool()?;
+        self.divisor = state.u8()?;
+        self.timer = state.i32()?;
+        self.lfsr = state.u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Noise shifting its LFSR every 8 clock cycles, the fastest it goes.
+    fn triggered(short_mode: bool) -> NoiseChannel {
+        let mut channel = NoiseChannel::new();
+        channel.write(2, 0xF0, false);
+        channel.write(3, if short_mode { 0x08 } else { 0 }, false);
+        channel.write(4, 0x80, false);
+        channel
+    }
+
+    // How many shifts it takes for the LFSR to be back where it started.
+    fn lfsr_period(channel: &mut NoiseChannel, mask: u16) -> usize {
+        let start = channel.lfsr & mask;
+        (1..=0x8000)
+            .find(|_| {
+                channel.step(8);
+                channel.lfsr & mask == start
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn lfsr_shifts_xor_of_the_two_lowest_bits_in() {
+        let mut channel = triggered(false);
+        channel.step(8);
+        assert_eq!(channel.lfsr, 0x3FFF);
+        channel.step(8 * 13);
+        assert_eq!(channel.lfsr, 0x0001);
+        // Bits 0 and 1 finally differ, a 1 goes in at the top.
+        channel.step(8);
+        assert_eq!(channel.lfsr, 0x4000);
+    }
+
+    #[test]
+    fn long_mode_repeats_every_32767_shifts() {
+        assert_eq!(lfsr_period(&mut triggered(false), 0x7FFF), 32767);
+    }
+
+    #[test]
+    fn short_mode_repeats_every_127_shifts() {
+        let mut channel = triggered(true);
+        // Let the 7 lowest bits settle into their own cycle.
+        channel.step(8 * 16);
+        assert_eq!(lfsr_period(&mut channel, 0x7F), 127);
+    }
+
+    #[test]
+    fn output_is_the_volume_when_bit_0_is_clear() {
+        let mut channel = triggered(false);
+        assert_eq!(channel.output(), 0);
+        for _ in 0..15 {
+            channel.step(8);
+        }
+        assert_eq!(channel.lfsr & 1, 0);
+        assert_eq!(channel.output(), 15);
+    }
+
+    #[test]
+    fn shift_and_divisor_set_the_period() {
+        let mut channel = NoiseChannel::new();
+        channel.write(3, 0x23, false);
+        assert_eq!(channel.period(), 48 << 2);
+        assert_eq!(channel.pitch(), 4194304.0 / 192.0);
+    }
+}
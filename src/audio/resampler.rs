@@ -0,0 +1,126 @@
+use std::f64::consts::PI;
+
+// Each step is spread over this many output samples.
+const TAPS: usize = 16;
+// Steps are placed with a precision of 1/PHASES of an output sample.
+const PHASES: usize = 32;
+// Fraction of the output Nyquist frequency kept, the rest is filtered out.
+const CUTOFF: f64 = 0.9;
+
+/// Converts stereo samples from the APU rate to the host rate.
+///
+/// The channels output steps: their level only changes now and then. Every
+/// change is drawn as a band-limited step, a windowed sinc integrated over
+/// time, instead of a sharp edge, so nothing above the output Nyquist
+/// frequency folds back as aliasing. Only changes cost work, so the input
+/// can run at the full M-cycle rate.
+pub(crate) struct Resampler {
+    // Output samples per input sample.
+    step: f64,
+    kernel: Box<[[f64; TAPS]; PHASES]>,
+    // Position of the next input sample in `deltas`, in output samples.
+    time: f64,
+    // Level changes still to be integrated, one per output sample.
+    deltas: Vec<[f64; 2]>,
+    level: [f32; 2],
+    integrator: [f64; 2],
+    output: Vec<[f32; 2]>,
+}
+
+impl Resampler {
+    pub(crate) fn new(input_rate: u32, output_rate: u32) -> Resampler {
+        Resampler {
+            step: output_rate as f64 / input_rate as f64,
+            kernel: Box::new(kernel()),
+            time: 0.0,
+            deltas: vec![[0.0; 2]; TAPS],
+            level: [0.0; 2],
+            integrator: [0.0; 2],
+            output: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, sample: [f32; 2]) {
+        if sample != self.level {
+            let position = self.time.floor();
+            let phase = ((self.time - position) * PHASES as f64) as usize;
+            let position = position as usize;
+            let delta = [(sample[0] - self.level[0]) as f64, (sample[1] - self.level[1]) as f64];
+            for (tap, weight) in self.kernel[phase].iter().enumerate() {
+                self.deltas[position + tap][0] += delta[0] * weight;
+                self.deltas[position + tap][1] += delta[1] * weight;
+            }
+            self.level = sample;
+        }
+        self.time += self.step;
+        // Samples before the current position won't change anymore.
+        let ready = self.time as usize;
+        if ready > 0 {
+            self.deltas.resize(self.deltas.len().max(ready + TAPS), [0.0; 2]);
+            self.flush(ready);
+        }
+    }
+
+    fn flush(&mut self, ready: usize) {
+        for delta in self.deltas.drain(..ready) {
+            self.integrator[0] += delta[0];
+            self.integrator[1] += delta[1];
+            self.output.push([self.integrator[0] as f32, self.integrator[1] as f32]);
+        }
+        self.time -= ready as f64;
+    }
+
+    /// The samples at the host rate produced since the last call. They lag
+    /// half a kernel behind the input.
+    pub(crate) fn take_output(&mut self) -> Vec<[f32; 2]> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+/// A windowed sinc for every phase, each normalised so that a step keeps
+/// its height.
+fn kernel() -> [[f64; TAPS]; PHASES] {
+    let mut kernel = [[0.0; TAPS]; PHASES];
+    for (phase, weights) in kernel.iter_mut().enumerate() {
+        for (tap, weight) in weights.iter_mut().enumerate() {
+            let x = tap as f64 - (TAPS / 2) as f64 + 1.0 - phase as f64 / PHASES as f64;
+            let sinc = if x == 0.0 { 1.0 } else { (PI * CUTOFF * x).sin() / (PI * CUTOFF * x) };
+            // Blackman window spanning the taps.
+            let position = (x + (TAPS / 2) as f64) / TAPS as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * position).cos() + 0.08 * (4.0 * PI * position).cos();
+            *weight = sinc * window.max(0.0);
+        }
+        let sum: f64 = weights.iter().sum();
+        weights.iter_mut().for_each(|weight| *weight /= sum);
+    }
+    kernel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resample(resampler: &mut Resampler, input: impl Iterator<Item = f32>) -> Vec<f32> {
+        for sample in input {
+            resampler.push([sample; 2]);
+        }
+        resampler.take_output().iter().map(|sample| sample[0]).collect()
+    }
+
+    #[test]
+    fn steps_settle_at_their_level() {
+        let mut resampler = Resampler::new(1_048_576, 48_000);
+        let output = resample(&mut resampler, std::iter::repeat_n(0.5, 10_000));
+        assert_eq!(output.len(), 457);
+        assert!(output[TAPS..].iter().all(|sample| (sample - 0.5).abs() < 1e-4));
+    }
+
+    #[test]
+    fn frequencies_above_nyquist_are_filtered_out() {
+        let mut resampler = Resampler::new(1_048_576, 48_000);
+        // A 65536 Hz square wave, way above the 24 kHz Nyquist frequency.
+        let square = (0..100_000).map(|index| if index / 8 % 2 == 0 { 0.5 } else { -0.5 });
+        let output = resample(&mut resampler, square);
+        assert!(output[TAPS..].iter().all(|sample| sample.abs() < 0.05), "{:?}", output);
+    }
+}
@@ -0,0 +1,203 @@
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use crate::recording::WavWriter;
+
+// The resampling ratio never strays further than this from 1.
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+const WAIT_STEP: Duration = Duration::from_millis(1);
+
+/// Where the sound ends up.
+pub trait AudioSink {
+    fn sample_rate(&self) -> u32;
+
+    /// Queues stereo samples going from -1.0 to 1.0.
+    fn push(&mut self, samples: &[[f32; 2]]) -> Result<(), String>;
+
+    /// How many queued samples have not been played yet.
+    fn buffered(&self) -> usize;
+
+    /// Whether samples are played as time goes by, rather than taken as
+    /// fast as they come.
+    fn real_time(&self) -> bool {
+        true
+    }
+}
+
+/// Plays nothing, but takes as long to do it as a sound card would, so it
+/// still paces emulation.
+pub struct NullSink {
+    sample_rate: u32,
+    queued: usize,
+    queued_at: Instant,
+}
+
+impl NullSink {
+    pub fn new(sample_rate: u32) -> NullSink {
+        NullSink { sample_rate, queued: 0, queued_at: Instant::now() }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn push(&mut self, samples: &[[f32; 2]]) -> Result<(), String> {
+        self.queued = self.buffered() + samples.len();
+        self.queued_at = Instant::now();
+        Ok(())
+    }
+
+    fn buffered(&self) -> usize {
+        let played = self.queued_at.elapsed().as_secs_f64() * self.sample_rate as f64;
+        self.queued.saturating_sub(played as usize)
+    }
+}
+
+/// Writes the sound to a WAV file as fast as it comes, it never holds
+/// emulation back.
+pub struct FileSink {
+    sample_rate: u32,
+    writer: WavWriter,
+}
+
+impl FileSink {
+    pub fn create(path: &Path, sample_rate: u32) -> Result<FileSink, String> {
+        Ok(FileSink { sample_rate, writer: WavWriter::create(path, sample_rate)? })
+    }
+}
+
+impl AudioSink for FileSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn push(&mut self, samples: &[[f32; 2]]) -> Result<(), String> {
+        self.writer.write(samples)
+    }
+
+    fn buffered(&self) -> usize {
+        0
+    }
+
+    fn real_time(&self) -> bool {
+        false
+    }
+}
+
+/// Paces emulation on a sink.
+///
+/// Pushing waits until the sink has played its buffer down to the target
+/// fill, so emulation runs at the speed the sound is played. The samples
+/// are then stretched or squeezed by the rate adjustment to bring the fill
+/// back to the target, so the buffer neither runs dry, which clicks, nor
+/// keeps growing, which adds latency. Only what reaches the sink is
+/// adjusted, recordings keep the samples as the APU made them.
+pub struct AudioOutput {
+    sink: Box<dyn AudioSink>,
+    target: usize,
+    // Where the next sample falls between the last pushed sample and the
+    // next one.
+    position: f64,
+    last: [f32; 2],
+}
+
+impl AudioOutput {
+    pub fn new(sink: Box<dyn AudioSink>, latency: Duration) -> AudioOutput {
+        let target = (sink.sample_rate() as f64 * latency.as_secs_f64()).max(1.0) as usize;
+        AudioOutput { sink, target, position: 0.0, last: [0.0; 2] }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sink.sample_rate()
+    }
+
+    pub fn push(&mut self, samples: &[[f32; 2]]) -> Result<(), String> {
+        while self.sink.buffered() > self.target {
+            sleep(WAIT_STEP);
+        }
+        if !self.sink.real_time() {
+            return self.sink.push(samples);
+        }
+        let adjusted = self.adjust(samples, self.rate_adjustment());
+        self.sink.push(&adjusted)
+    }
+
+    // Resamples `ratio` times as many samples. The ratio stays so close to
+    // 1 that linear interpolation is enough.
+    fn adjust(&mut self, samples: &[[f32; 2]], ratio: f64) -> Vec<[f32; 2]> {
+        let step = 1.0 / ratio;
+        let mut adjusted = Vec::with_capacity((samples.len() as f64 * ratio) as usize + 1);
+        for &sample in samples {
+            while self.position < 1.0 {
+                let weight = self.position as f32;
+                adjusted.push([0, 1].map(|side| self.last[side] + (sample[side] - self.last[side]) * weight));
+                self.position += step;
+            }
+            self.position -= 1.0;
+            self.last = sample;
+        }
+        adjusted
+    }
+
+    /// The ratio to resample at: above 1 when the buffer is below its
+    /// target, below 1 when it is above, always 1 for sinks that aren't
+    /// played in real time.
+    pub fn rate_adjustment(&self) -> f64 {
+        if !self.sink.real_time() {
+            return 1.0;
+        }
+        let distance = (self.target as f64 - self.sink.buffered() as f64) / self.target as f64;
+        1.0 + (distance * MAX_RATE_ADJUSTMENT).clamp(-MAX_RATE_ADJUSTMENT, MAX_RATE_ADJUSTMENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_sink_plays_in_real_time() {
+        let mut sink = NullSink::new(1000);
+        sink.push(&[[0.0; 2]; 1000]).unwrap();
+        assert!(sink.buffered() > 900);
+        sleep(Duration::from_millis(200));
+        assert!(sink.buffered() <= 800);
+    }
+
+    #[test]
+    fn rate_adjustment_follows_the_fill() {
+        let mut output = AudioOutput::new(Box::new(NullSink::new(48000)), Duration::from_secs(1));
+        assert_eq!(output.rate_adjustment(), 1.0 + MAX_RATE_ADJUSTMENT);
+        output.push(&vec![[0.0; 2]; 48000 * 3 / 2]).unwrap();
+        let adjustment = output.rate_adjustment();
+        assert!(adjustment < 1.0 && adjustment > 1.0 - MAX_RATE_ADJUSTMENT, "{}", adjustment);
+    }
+
+    #[test]
+    fn samples_are_stretched_to_refill_the_buffer() {
+        let mut output = AudioOutput::new(Box::new(NullSink::new(48000)), Duration::from_secs(1));
+        let ramp: Vec<[f32; 2]> = (0..1000).map(|index| [index as f32; 2]).collect();
+        let adjusted = output.adjust(&ramp, 1.25);
+        assert_eq!(adjusted.len(), 1250);
+        // A sample behind, as the first one is reached from silence.
+        assert!((adjusted[6][0] - 3.8).abs() < 1e-4, "{:?}", &adjusted[..8]);
+        assert_eq!(output.adjust(&ramp, 0.8).len(), 800);
+        assert_eq!(output.adjust(&ramp, 1.0).len(), 1000);
+    }
+
+    #[test]
+    fn file_sink_never_waits() {
+        let path = std::env::temp_dir().join(format!("file-sink-{}.wav", std::process::id()));
+        let mut output = AudioOutput::new(Box::new(FileSink::create(&path, 48000).unwrap()), Duration::from_millis(1));
+        let started = Instant::now();
+        for _ in 0..10 {
+            output.push(&[[0.5; 2]; 4800]).unwrap();
+        }
+        let size = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(size, 44 + 10 * 4800 * 4);
+    }
+}
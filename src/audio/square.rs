@@ -0,0 +1,309 @@
+use crate::audio::envelope::Envelope;
+use crate::audio::length::LengthCounter;
+use crate::state::{StateReader, StateWriter};
+
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+/// Changes the frequency of channel 1 over time, clocked at 128 Hz.
+pub(crate) struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            enabled: false,
+            shadow_frequency: 0,
+        }
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 is treated as 8.
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let change = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.shadow_frequency - change
+        } else {
+            self.shadow_frequency + change
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.period);
+        state.bool(self.negate);
+        state.u8(self.shift);
+        state.u8(self.timer);
+        state.bool(self.enabled);
+        state.u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.period = state.u8()?;
+        self.negate = state.bool()?;
+        self.shift = state.u8()?;
+        self.timer = state.u8()?;
+        self.enabled = state.bool()?;
+        self.shadow_frequency = state.u16()?;
+        Ok(())
+    }
+}
+
+/// Channels 1 and 2, only the first one has a sweep.
+pub(crate) struct SquareChannel {
+    pub(crate) enabled: bool,
+    pub(crate) length: LengthCounter,
+    pub(crate) envelope: Envelope,
+    sweep: Option<Sweep>,
+    duty: u8,
+    duty_position: u8,
+    pub(crate) frequency: u16,
+    timer: i32,
+}
+
+impl SquareChannel {
+    pub(crate) fn new(with_sweep: bool) -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+            duty: 0,
+            duty_position: 0,
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    /// Writes NRx0 to NRx4, `register` going from 0 to 4.
+    /// `extra_length_clock` is set when the next frame sequencer step won't
+    /// clock lengths.
+    pub(crate) fn write(&mut self, register: usize, value: u8, extra_length_clock: bool) {
+        match register {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.period = (value >> 4) & 0x07;
+                    sweep.negate = value & 0x08 != 0;
+                    sweep.shift = value & 0x07;
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value, self.enabled);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                if self.length.set_enabled(value & 0x40 != 0, extra_length_clock) {
+                    self.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.trigger(extra_length_clock);
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self, extra_length_clock: bool) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(extra_length_clock);
+        self.timer = self.period();
+        self.envelope.trigger();
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow_frequency = self.frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            // The overflow check is done right away.
+            if sweep.shift != 0 && sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+
+    pub(crate) fn step(&mut self, cycles: u16) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+    }
+
+    pub(crate) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(crate) fn clock_sweep(&mut self) {
+        let sweep = match self.sweep.as_mut() {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        let frequency = sweep.next_frequency();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow_frequency = frequency;
+            self.frequency = frequency;
+            // The new frequency is checked once more, without being used.
+            if sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// The value fed to the DAC, from 0 to 15.
+    pub(crate) fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let high = DUTY_PATTERNS[self.duty as usize] >> (7 - self.duty_position) & 1;
+        high * self.envelope.volume
+    }
+
+    /// The pitch of the note played, in Hz.
+    pub(crate) fn pitch(&self) -> f32 {
+        131072.0 / (2048 - self.frequency as u32) as f32
+    }
+
+    pub(crate) fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(state);
+        }
+        state.u8(self.duty);
+        state.u8(self.duty_position);
+        state.u16(self.frequency);
+        state.i32(self.timer);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.enabled = state.bool()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.load_state(state)?;
+        }
+        self.duty = state.u8()?;
+        self.duty_position = state.u8()?;
+        self.frequency = state.u16()?;
+        self.timer = state.i32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Square 1 at full volume playing `frequency`, with NR10 set to `sweep`.
+    fn triggered(sweep: u8, frequency: u16) -> SquareChannel {
+        let mut channel = SquareChannel::new(true);
+        channel.write(0, sweep, false);
+        channel.write(2, 0xF0, false);
+        channel.write(3, frequency as u8, false);
+        channel.write(4, 0x80 | (frequency >> 8) as u8, false);
+        channel
+    }
+
+    #[test]
+    fn sweep_overflow_is_checked_on_trigger() {
+        // 1792 + 1792 / 2 is past 2047.
+        assert!(!triggered(0x11, 1792).enabled);
+        // Without a shift there is no check.
+        assert!(triggered(0x10, 1792).enabled);
+    }
+
+    #[test]
+    fn sweep_overflow_is_checked_twice_on_clock() {
+        let mut channel = triggered(0x11, 1024);
+        assert!(channel.enabled);
+        channel.clock_sweep();
+        // 1536 is used, and the next one, 2304, turns the channel off.
+        assert_eq!(channel.frequency, 1536);
+        assert!(!channel.enabled);
+    }
+
+    #[test]
+    fn sweep_goes_down_when_negated_every_period() {
+        let mut channel = triggered(0x29, 1024);
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 1024);
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 512);
+        channel.clock_sweep();
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 256);
+        assert!(channel.enabled);
+    }
+
+    #[test]
+    fn sweep_with_a_period_of_zero_does_nothing() {
+        let mut channel = triggered(0x01, 1024);
+        for _ in 0..16 {
+            channel.clock_sweep();
+        }
+        assert_eq!(channel.frequency, 1024);
+        assert!(channel.enabled);
+    }
+
+    #[test]
+    fn square_2_has_no_sweep() {
+        let mut channel = SquareChannel::new(false);
+        channel.write(0, 0x11, false);
+        channel.write(2, 0xF0, false);
+        channel.write(4, 0x87, false);
+        channel.clock_sweep();
+        assert!(channel.enabled);
+        assert_eq!(channel.frequency, 0x700);
+    }
+
+    #[test]
+    fn duty_patterns_are_played_over_eight_steps() {
+        // 12.5% duty, one step every 4 cycles starting from the first one.
+        let mut channel = triggered(0, 2047);
+        let mut outputs = Vec::new();
+        for _ in 0..8 {
+            channel.step(4);
+            outputs.push(channel.output());
+        }
+        assert_eq!(outputs, [0, 0, 0, 0, 0, 0, 15, 0]);
+    }
+}
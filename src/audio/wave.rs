@@ -0,0 +1,225 @@
+use crate::audio::length::LengthCounter;
+use crate::state::{StateReader, StateWriter};
+
+pub(crate) const WAVE_RAM_SIZE: usize = 16;
+
+/// Channel 3, playing back the 32 4-bit samples of wave RAM.
+pub(crate) struct WaveChannel {
+    pub(crate) enabled: bool,
+    dac_enabled: bool,
+    pub(crate) length: LengthCounter,
+    volume_shift: u8,
+    pub(crate) frequency: u16,
+    timer: i32,
+    position: u8,
+    sample: u8,
+    // A sample byte was read during the last step.
+    just_read: bool,
+    pub(crate) wave_ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl WaveChannel {
+    pub(crate) fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            length: LengthCounter::new(256),
+            volume_shift: 4,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            just_read: false,
+            wave_ram: [0; WAVE_RAM_SIZE],
+        }
+    }
+
+    /// Writes NR30 to NR34, `register` going from 0 to 4.
+    /// `extra_length_clock` is set when the next frame sequencer step won't
+    /// clock lengths.
+    pub(crate) fn write(&mut self, register: usize, value: u8, extra_length_clock: bool) {
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            2 => {
+                // 0 mutes the channel, then 100%, 50% and 25%.
+                self.volume_shift = match (value >> 5) & 0b11 {
+                    0 => 4,
+                    level => level - 1,
+                };
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                if self.length.set_enabled(value & 0x40 != 0, extra_length_clock) {
+                    self.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.trigger(extra_length_clock);
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self, extra_length_clock: bool) {
+        // Retriggering on the DMG right as a sample is read corrupts the
+        // start of wave RAM with the bytes being read.
+        if self.enabled && self.timer <= 2 {
+            let index = (self.position as usize + 1) % 32 / 2;
+            if index < 4 {
+                self.wave_ram[0] = self.wave_ram[index];
+            } else {
+                let block = index & !3;
+                self.wave_ram.copy_within(block..block + 4, 0);
+            }
+        }
+        self.enabled = self.dac_enabled;
+        self.length.trigger(extra_length_clock);
+        // The first sample is read a little later than the next ones.
+        self.timer = self.period() + 6;
+        self.position = 0;
+        self.just_read = false;
+    }
+
+    /// Reads wave RAM. While the channel plays, the DMG only lets through
+    /// accesses made right as the channel reads a byte, and they go to that
+    /// byte whatever the address.
+    pub(crate) fn read_wave_ram(&self, index: usize) -> u8 {
+        if !self.enabled {
+            self.wave_ram[index]
+        } else if self.just_read {
+            self.wave_ram[self.position as usize / 2]
+        } else {
+            0xFF
+        }
+    }
+
+    pub(crate) fn write_wave_ram(&mut self, index: usize, value: u8) {
+        if !self.enabled {
+            self.wave_ram[index] = value;
+        } else if self.just_read {
+            self.wave_ram[self.position as usize / 2] = value;
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+
+    pub(crate) fn step(&mut self, cycles: u16) {
+        if !self.enabled {
+            return;
+        }
+        self.timer -= cycles as i32;
+        self.just_read = false;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.just_read = true;
+            self.position = (self.position + 1) % 32;
+            let byte = self.wave_ram[self.position as usize / 2];
+            // The high nibble is played first.
+            self.sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+
+    pub(crate) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// The value fed to the DAC, from 0 to 15.
+    pub(crate) fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        self.sample >> self.volume_shift
+    }
+
+    /// The pitch of the note played, in Hz.
+    pub(crate) fn pitch(&self) -> f32 {
+        65536.0 / (2048 - self.frequency as u32) as f32
+    }
+
+    /// The volume applied to wave RAM samples, on the same 0 to 15 scale
+    /// as the envelope of the other channels.
+    pub(crate) fn volume(&self) -> u8 {
+        15 >> self.volume_shift
+    }
+
+    pub(crate) fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        self.length.save_state(state);
+        state.u8(self.volume_shift);
+        state.u16(self.frequency);
+        state.i32(self.timer);
+        state.u8(self.position);
+        state.u8(self.sample);
+        state.bool(self.just_read);
+        state.bytes(&self.wave_ram);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.enabled = state.bool()?;
+        self.dac_enabled = state.bool()?;
+        self.length.load_state(state)?;
+        self.volume_shift = state.u8()?;
+        self.frequency = state.u16()?;
+        self.timer = state.i32()?;
+        self.position = state.u8()?;
+        self.sample = state.u8()?;
+        self.just_read = state.bool()?;
+        state.bytes(&mut self.wave_ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing_channel() -> WaveChannel {
+        let mut channel = WaveChannel::new();
+        channel.wave_ram = std::array::from_fn(|index| index as u8);
+        channel.write(0, 0x80, false);
+        channel.write(4, 0x80, false);
+        channel
+    }
+
+    #[test]
+    fn retrigger_while_reading_a_later_byte_copies_its_block() {
+        let mut channel = playing_channel();
+        // About to read sample 10, in byte 5.
+        channel.position = 9;
+        channel.timer = 2;
+        channel.write(4, 0x80, false);
+        assert_eq!(channel.wave_ram[..8], [4, 5, 6, 7, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn retrigger_while_reading_one_of_the_first_bytes_copies_it() {
+        let mut channel = playing_channel();
+        channel.position = 5;
+        channel.timer = 1;
+        channel.write(4, 0x80, false);
+        assert_eq!(channel.wave_ram[..4], [3, 1, 2, 3]);
+    }
+
+    #[test]
+    fn retrigger_between_reads_leaves_wave_ram_alone() {
+        let mut channel = playing_channel();
+        channel.position = 9;
+        channel.timer = 100;
+        channel.write(4, 0x80, false);
+        assert_eq!(channel.wave_ram, std::array::from_fn(|index| index as u8));
+    }
+}
@@ -0,0 +1,32 @@
+const TITLE_BEGIN: usize = 0x134;
+const TITLE_END: usize = 0x143;
+const HEADER_CHECKSUM: usize = 0x14D;
+const GLOBAL_CHECKSUM: usize = 0x14E;
+
+/// The bits of the cartridge header we need to tell games apart.
+pub struct CartridgeHeader {
+    pub title: String,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    pub fn from_rom(rom: &[u8]) -> CartridgeHeader {
+        // The title is padded with zeros, and CGB games reuse its last bytes
+        // for the manufacturer code and the CGB flag, so stop at the first
+        // byte that is not printable.
+        let title = rom[TITLE_BEGIN..=TITLE_END]
+            .iter()
+            .take_while(|&&byte| (0x20..0x7F).contains(&byte))
+            .map(|&byte| byte as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+        CartridgeHeader {
+            title,
+            header_checksum: rom[HEADER_CHECKSUM],
+            // The global checksum is the only big endian value of the header.
+            global_checksum: (rom[GLOBAL_CHECKSUM] as u16) << 8 | rom[GLOBAL_CHECKSUM + 1] as u16,
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+use emulator::cartridge::CartridgeHeader;
+use emulator::graphics::Palette;
+
+/// Settings read from the TOML file given with `--config`.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) palette: PaletteConfig,
+    /// Keys bound to buttons and hotkeys, on top of the defaults.
+    ///
+    /// ```toml
+    /// [keys]
+    /// a = "K"
+    /// b = "J"
+    /// fast-forward = "LeftShift"
+    /// ```
+    pub(crate) keys: HashMap<String, String>,
+}
+
+/// ```toml
+/// [palette]
+/// default = "classic-green"
+///
+/// [palette.roms]
+/// "TETRIS" = "pocket"
+/// "0x3F2B" = "#e0f8d0,#88c070,#346856,#081820"
+/// ```
+///
+/// ROMs are matched by their title or by their global checksum.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PaletteConfig {
+    pub(crate) default: Option<String>,
+    pub(crate) roms: HashMap<String, String>,
+}
+
+impl Config {
+    pub(crate) fn from_file(path: &str) -> Result<Config, String> {
+        let content = std::fs::read_to_string(path).map_err(|error| format!("Could not read config file {}: {}", path, error))?;
+        let config: Config = toml::from_str(&content).map_err(|error| format!("Invalid config file {}: {}", path, error))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Catch mistakes at startup instead of when the matching ROM is loaded.
+    // Keys are checked once merged with --bind, which may resolve clashes.
+    fn validate(&self) -> Result<(), String> {
+        if let Some(spec) = &self.palette.default {
+            Palette::parse(spec)?;
+        }
+        for (rom, spec) in &self.palette.roms {
+            Palette::parse(spec).map_err(|error| format!("Palette for ROM `{}`: {}", rom, error))?;
+        }
+        Ok(())
+    }
+}
+
+impl PaletteConfig {
+    /// The palette to use for a given cartridge, if the config has one.
+    pub(crate) fn palette_for(&self, header: &CartridgeHeader) -> Result<Option<Palette>, String> {
+        // A checksum names one ROM when a title may be shared by several,
+        // so it wins when both are in the config.
+        let spec = self
+            .roms
+            .iter()
+            .find(|(rom, _)| parse_checksum(rom) == Some(header.global_checksum))
+            .map(|(_, spec)| spec)
+            .or_else(|| self.roms.get(&header.title))
+            .or(self.default.as_ref());
+        spec.map(|spec| Palette::parse(spec)).transpose()
+    }
+}
+
+// Checksums are written in hexadecimal, like 0x3F2B.
+fn parse_checksum(rom: &str) -> Option<u16> {
+    let hex = rom.strip_prefix("0x").or_else(|| rom.strip_prefix("0X"))?;
+    u16::from_str_radix(hex, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(title: &str, global_checksum: u16) -> CartridgeHeader {
+        CartridgeHeader { title: title.to_string(), header_checksum: 0, global_checksum }
+    }
+
+    fn config(roms: &[(&str, &str)]) -> PaletteConfig {
+        PaletteConfig {
+            default: Some("light".to_string()),
+            roms: roms.iter().map(|&(rom, spec)| (rom.to_string(), spec.to_string())).collect(),
+        }
+    }
+
+    #[test]
+    fn checksums_win_over_titles() {
+        let config = config(&[("TETRIS", "pocket"), ("0x3F2B", "high-contrast")]);
+        assert_eq!(config.palette_for(&header("TETRIS", 0x3F2B)), Ok(Some(Palette::HIGH_CONTRAST)));
+        assert_eq!(config.palette_for(&header("TETRIS", 0x1234)), Ok(Some(Palette::GREYSCALE)));
+    }
+
+    #[test]
+    fn checksums_are_read_as_hex() {
+        for checksum in ["0x3f2b", "0X3F2B", "0x03F2B"] {
+            let config = config(&[(checksum, "pocket")]);
+            assert_eq!(config.palette_for(&header("TETRIS", 0x3F2B)), Ok(Some(Palette::GREYSCALE)), "{}", checksum);
+        }
+        let config = config(&[("0x3F2", "pocket"), ("0xZZZZ", "pocket")]);
+        assert_eq!(config.palette_for(&header("TETRIS", 0x3F2B)), Ok(Some(Palette::LIGHT)));
+    }
+
+    #[test]
+    fn unknown_roms_get_the_default() {
+        assert_eq!(config(&[("TETRIS", "pocket")]).palette_for(&header("ZELDA", 0)), Ok(Some(Palette::LIGHT)));
+        assert_eq!(PaletteConfig::default().palette_for(&header("ZELDA", 0)), Ok(None));
+    }
+}
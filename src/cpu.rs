@@ -3,9 +3,20 @@ mod instructions;
 
 use std::ops::BitXor;
 pub(crate) use crate::cpu::register::{Registers, FlagRegister};
-use crate::cpu::instructions::{Indirect, Instruction, JumpTest, LoadByteSource, LoadByteTarget, LoadType, LoadWordTarget, MultipleBytesRegister, PrefixTarget};
+use crate::cpu::instructions::{Indirect, Instruction, LoadByteSource, LoadByteTarget, LoadType, LoadWordTarget, PrefixTarget};
 use crate::cpu::instructions::ArithmeticTarget;
 use crate::memory::MemoryBus;
+use crate::state::{StateReader, StateWriter};
+
+// LD B,B does nothing, so test ROMs use it as a software breakpoint.
+const SOFTWARE_BREAKPOINT: u8 = 0x40;
+
+// VBlank, STAT, timer, serial and joypad, in order of priority, each one
+// jumping 8 bytes after the previous one.
+const INTERRUPT_COUNT: u8 = 5;
+const INTERRUPT_VECTORS_BEGIN: u16 = 0x40;
+// Two wait states, pushing PC and jumping.
+const INTERRUPT_DISPATCH_CYCLES: u16 = 20;
 
 pub(crate) struct CPU {
     pub(crate) registers: Registers,
@@ -13,10 +24,111 @@ pub(crate) struct CPU {
     pub(crate) bus: MemoryBus,
     pub(crate) sp: u16,
     pub(crate) is_halted: bool,
+    pub(crate) is_stopped: bool,
+    // The interrupt master enable flag, IME.
+    interrupts_enabled: bool,
+    // Set by EI, which only enables interrupts after the next instruction.
+    enabling_interrupts: bool,
+    // Set by LD B,B until taken.
+    breakpoint_hit: bool,
 }
 
 impl CPU {
+    pub(crate) fn new(bus: MemoryBus) -> CPU {
+        CPU {
+            registers: Registers {
+                a: 0,
+                b: 0,
+                c: 0,
+                d: 0,
+                e: 0,
+                f: FlagRegister {
+                    zero: false,
+                    subtract: false,
+                    half_carry: false,
+                    carry: false,
+                },
+                h: 0,
+                l: 0,
+            },
+            pc: 0x0,
+            sp: 0x00,
+            bus,
+            is_halted: false,
+            is_stopped: false,
+            interrupts_enabled: false,
+            enabling_interrupts: false,
+            breakpoint_hit: false,
+        }
+    }
+
+    /// Saves the registers and everything on the bus.
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        let registers = &self.registers;
+        for value in [registers.a, registers.b, registers.c, registers.d, registers.e, u8::from(registers.f), registers.h, registers.l] {
+            state.u8(value);
+        }
+        state.u16(self.pc);
+        state.u16(self.sp);
+        state.bool(self.is_halted);
+        state.bool(self.is_stopped);
+        state.bool(self.interrupts_enabled);
+        state.bool(self.enabling_interrupts);
+        self.bus.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.registers.a = state.u8()?;
+        self.registers.b = state.u8()?;
+        self.registers.c = state.u8()?;
+        self.registers.d = state.u8()?;
+        self.registers.e = state.u8()?;
+        self.registers.f = state.u8()?.into();
+        self.registers.h = state.u8()?;
+        self.registers.l = state.u8()?;
+        self.pc = state.u16()?;
+        self.sp = state.u16()?;
+        self.is_halted = state.bool()?;
+        self.is_stopped = state.bool()?;
+        self.interrupts_enabled = state.bool()?;
+        self.enabling_interrupts = state.bool()?;
+        self.bus.load_state(state)
+    }
+
+    /// Whether LD B,B ran since the last call.
+    pub(crate) fn take_breakpoint(&mut self) -> bool {
+        std::mem::take(&mut self.breakpoint_hit)
+    }
+
+    /// Runs one instruction, then jumps to an interrupt handler if one is
+    /// due, and returns how many clock cycles it all took.
     pub(crate) fn step(&mut self) -> u16 {
+        if self.is_stopped {
+            // Everything is frozen until a button is pressed.
+            if !self.bus.joypad.any_line_low() {
+                return 4;
+            }
+            self.is_stopped = false;
+        }
+        let cycles = if self.is_halted {
+            // A halted CPU idles but the rest of the hardware keeps running.
+            4
+        } else {
+            // An EI before this instruction takes effect after it, unless
+            // it is a DI.
+            let enabling_interrupts = self.enabling_interrupts;
+            let cycles = self.run_instruction();
+            if enabling_interrupts && self.enabling_interrupts {
+                self.interrupts_enabled = true;
+                self.enabling_interrupts = false;
+            }
+            cycles
+        };
+        self.bus.step(cycles);
+        cycles + self.service_interrupts()
+    }
+
+    fn run_instruction(&mut self) -> u16 {
         let mut instruction_byte = self.bus.read_byte(self.pc);
         // Check if it's a prefix byte
         let is_prefix = instruction_byte == 0xCB;
@@ -24,17 +136,39 @@ impl CPU {
             instruction_byte = self.bus.read_byte(self.pc + 1);
         }
         if let Some(instruction) = Instruction::from_byte(instruction_byte, is_prefix) {
+            self.breakpoint_hit |= !is_prefix && instruction_byte == SOFTWARE_BREAKPOINT;
+            let cycles = instruction.cycles();
             self.pc = self.execute(instruction);
+            cycles
         } else {
             panic!("Unkown instruction found for: 0x{:x}", instruction_byte);
         }
-        return self.pc;
     }
 
-    fn execute(&mut self, instruction: Instruction) -> u16 {
-        if self.is_halted {
-            return self.pc;
+    /// Wakes the CPU up from HALT when an enabled interrupt is requested,
+    /// and jumps to the handler of the one with the highest priority if
+    /// interrupts are enabled. Returns the clock cycles it took.
+    fn service_interrupts(&mut self) -> u16 {
+        let requested = self.bus.interrupt_enable & self.bus.interrupt_flag & ((1 << INTERRUPT_COUNT) - 1);
+        if requested == 0 {
+            return 0;
+        }
+        // HALT ends even with interrupts disabled, the CPU then goes on
+        // with the next instruction.
+        self.is_halted = false;
+        if !self.interrupts_enabled {
+            return 0;
         }
+        let interrupt = requested.trailing_zeros() as u16;
+        self.interrupts_enabled = false;
+        self.bus.interrupt_flag &= !(1 << interrupt);
+        self.push(self.pc);
+        self.pc = INTERRUPT_VECTORS_BEGIN + interrupt * 8;
+        self.bus.step(INTERRUPT_DISPATCH_CYCLES);
+        INTERRUPT_DISPATCH_CYCLES
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> u16 {
         match instruction {
             Instruction::ADD(target) => {
                 match target {
@@ -92,22 +226,11 @@ impl CPU {
                 };
                 self.pc.wrapping_add(1)
             }
-            Instruction::JP(test) => {
-                let jump_condition = match test {
-                    JumpTest::NotZero => !self.registers.f.zero,
-                    JumpTest::NotCarry => !self.registers.f.carry,
-                    JumpTest::Zero => self.registers.f.zero,
-                    JumpTest::Carry => self.registers.f.carry,
-                    JumpTest::Always => true
-                };
-                self.jump(jump_condition)
-            }
             Instruction::LD(load_type) => {
                 match load_type {
                     LoadType::Byte(target, source) => {
                         let source_value = match source {
                             LoadByteSource::A => self.registers.a,
-                            LoadByteSource::D8 => self.read_next_byte(),
                             LoadByteSource::HLI => self.bus.read_byte(self.registers.get_hl()),
                             LoadByteSource::B => self.registers.b,
                             LoadByteSource::C => self.registers.c,
@@ -126,10 +249,7 @@ impl CPU {
                             LoadByteTarget::H => self.registers.h = source_value,
                             LoadByteTarget::L => self.registers.l = source_value,
                         };
-                        match source {
-                            LoadByteSource::D8 => self.pc.wrapping_add(2),
-                            _ => self.pc.wrapping_add(1),
-                        }
+                        self.pc.wrapping_add(1)
                     }
                     LoadType::Word(target) => {
                         let word = self.read_next_word();
@@ -177,50 +297,30 @@ impl CPU {
                             _ => self.pc.wrapping_add(1),
                         }
                     }
-                    _ => { panic!("TODO: implement other load types") }
                 }
             }
-            Instruction::PUSH(target) => {
-                let value = match target {
-                    MultipleBytesRegister::BC => self.registers.get_bc(),
-                    MultipleBytesRegister::AF => self.registers.get_af(),
-                    MultipleBytesRegister::DE => self.registers.get_de(),
-                    MultipleBytesRegister::HL => self.registers.get_hl(),
-                };
-                self.push(value);
+            Instruction::HALT() => {
+                self.is_halted = true;
                 self.pc.wrapping_add(1)
             }
-            Instruction::POP(target) => {
-                let value = self.pop();
-                match target {
-                    MultipleBytesRegister::AF => self.registers.set_af(value),
-                    MultipleBytesRegister::BC => self.registers.set_bc(value),
-                    MultipleBytesRegister::DE => self.registers.set_de(value),
-                    MultipleBytesRegister::HL => self.registers.set_hl(value),
-                }
-
+            Instruction::EI() => {
+                self.enabling_interrupts = true;
                 self.pc.wrapping_add(1)
             }
-            Instruction::CALL(test) => {
-                let jump_condition = match test {
-                    JumpTest::NotZero => !self.registers.f.zero,
-                    _ => { panic!("TODO: support more conditions") }
-                };
-                self.call(jump_condition)
-            }
-            Instruction::RET(test) => {
-                let jump_condition = match test {
-                    JumpTest::NotZero => !self.registers.f.zero,
-                    _ => { panic!("TODO: support more conditions") }
-                };
-                self.return_(jump_condition)
-            }
-            Instruction::NOP() => {
+            Instruction::DI() => {
+                self.interrupts_enabled = false;
+                self.enabling_interrupts = false;
                 self.pc.wrapping_add(1)
             }
-            Instruction::HALT() => {
-                self.is_halted = true;
-                self.pc.wrapping_add(1)
+            Instruction::RETI() => {
+                // Unlike EI, RETI enables interrupts right away.
+                self.interrupts_enabled = true;
+                self.pop()
+            }
+            Instruction::STOP() => {
+                self.is_stopped = true;
+                // STOP is followed by a byte that is skipped.
+                self.pc.wrapping_add(2)
             }
             Instruction::SWAP(target) => {
                 match target {
@@ -252,7 +352,7 @@ impl CPU {
                 }
                 self.pc.wrapping_add(1)
             }
-            _ => panic!("TODO: support more instructions")
+            Instruction::INC(_) => panic!("TODO: support INC"),
         }
     }
 
@@ -292,21 +392,6 @@ impl CPU {
         self.sub(nbr);
     }
 
-    fn jump(&self, should_jump: bool) -> u16 {
-        if should_jump {
-            // Gameboy is little endian so read pc + 2 as most significant bit
-            // and pc + 1 as least significant bit
-            let least_significant_byte = self.bus.read_byte(self.pc + 1) as u16;
-            let most_significant_byte = self.bus.read_byte(self.pc + 2) as u16;
-            (most_significant_byte << 8) | least_significant_byte
-        } else {
-            // If we don't jump we need to still move the program
-            // counter forward by 3 since the jump instruction is
-            // 3 bytes wide (1 byte for tag and 2 bytes for jump address)
-            self.pc.wrapping_add(3)
-        }
-    }
-
     fn read_next_byte(&self) -> u8 {
         self.bus.read_byte(self.pc + 1)
     }
@@ -327,24 +412,6 @@ impl CPU {
         first_byte << 8 | last_byte
     }
 
-    fn call(&mut self, should_jump: bool) -> u16 {
-        let next_pc = self.pc.wrapping_add(3);
-        if should_jump {
-            self.push(next_pc);
-            self.read_next_word()
-        } else {
-            next_pc
-        }
-    }
-
-    fn return_(&mut self, should_jump: bool) -> u16 {
-        if should_jump {
-            self.pop()
-        } else {
-            self.pc.wrapping_add(1)
-        }
-    }
-
     fn read_next_word(&self) -> u16 {
         let last_byte = self.bus.read_byte(self.pc + 1) as u16;
         let first_byte = self.bus.read_byte(self.pc + 2) as u16;
@@ -368,4 +435,135 @@ impl CPU {
         self.registers.f.carry = false;
         self.registers.f.half_carry = false;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VBLANK: u8 = 0b00001;
+    const TIMER: u8 = 0b00100;
+    const JOYPAD: u8 = 0b10000;
+
+    // XOR A everywhere but for `code` at the start of the ROM, with the
+    // stack at the top of the zero page.
+    fn cpu(code: &[u8]) -> CPU {
+        let mut rom = vec![0xAF; 0x8000];
+        rom[..code.len()].copy_from_slice(code);
+        let mut cpu = CPU::new(MemoryBus::new(None, rom));
+        cpu.sp = 0xFFFE;
+        cpu
+    }
+
+    fn request(cpu: &mut CPU, enabled: u8, requested: u8) {
+        cpu.bus.interrupt_enable = enabled;
+        cpu.bus.interrupt_flag = requested;
+    }
+
+    fn stacked_pc(cpu: &CPU) -> u16 {
+        (cpu.bus.read_byte(cpu.sp.wrapping_add(1)) as u16) << 8 | cpu.bus.read_byte(cpu.sp) as u16
+    }
+
+    #[test]
+    fn interrupts_are_enabled_after_the_instruction_following_ei() {
+        let mut cpu = cpu(&[0xFB]);
+        request(&mut cpu, VBLANK, VBLANK);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.pc, 1);
+        // The next instruction runs, then the interrupt is dispatched.
+        assert_eq!(cpu.step(), 4 + INTERRUPT_DISPATCH_CYCLES);
+        assert_eq!(cpu.pc, 0x40);
+        assert_eq!(cpu.sp, 0xFFFC);
+        assert_eq!(stacked_pc(&cpu), 2);
+        assert_eq!(cpu.bus.interrupt_flag, 0);
+        // Handlers run with interrupts disabled.
+        request(&mut cpu, VBLANK, VBLANK);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.pc, 0x41);
+    }
+
+    #[test]
+    fn the_lowest_interrupt_bit_goes_first() {
+        let mut cpu = cpu(&[0xFB]);
+        request(&mut cpu, 0x1F, TIMER | JOYPAD);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(cpu.bus.interrupt_flag, JOYPAD);
+    }
+
+    #[test]
+    fn only_enabled_interrupts_are_dispatched() {
+        let mut cpu = cpu(&[0xFB]);
+        request(&mut cpu, TIMER, VBLANK | JOYPAD);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 2);
+        assert_eq!(cpu.bus.interrupt_flag, VBLANK | JOYPAD);
+    }
+
+    #[test]
+    fn di_disables_interrupts_and_cancels_ei() {
+        let mut cpu = cpu(&[0xFB, 0xF3]);
+        request(&mut cpu, VBLANK, VBLANK);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.pc, 3);
+        assert!(!cpu.interrupts_enabled);
+    }
+
+    #[test]
+    fn reti_returns_and_enables_interrupts_at_once() {
+        let mut cpu = cpu(&[0xD9]);
+        cpu.push(0x1234);
+        assert_eq!(cpu.step(), 16);
+        assert_eq!((cpu.pc, cpu.sp), (0x1234, 0xFFFE));
+        assert!(cpu.interrupts_enabled);
+    }
+
+    #[test]
+    fn halt_waits_for_an_enabled_interrupt() {
+        let mut cpu = cpu(&[0xFB, 0x76]);
+        cpu.step();
+        cpu.step();
+        assert!(cpu.is_halted);
+        for _ in 0..10 {
+            assert_eq!(cpu.step(), 4);
+        }
+        assert_eq!(cpu.pc, 2);
+        request(&mut cpu, VBLANK, VBLANK);
+        assert_eq!(cpu.step(), 4 + INTERRUPT_DISPATCH_CYCLES);
+        assert!(!cpu.is_halted);
+        assert_eq!(cpu.pc, 0x40);
+        assert_eq!(stacked_pc(&cpu), 2);
+    }
+
+    #[test]
+    fn halt_ends_without_a_dispatch_when_interrupts_are_disabled() {
+        let mut cpu = cpu(&[0x76]);
+        cpu.step();
+        assert!(cpu.is_halted);
+        request(&mut cpu, JOYPAD, JOYPAD);
+        assert_eq!(cpu.step(), 4);
+        assert!(!cpu.is_halted);
+        cpu.step();
+        assert_eq!(cpu.pc, 2);
+        assert_eq!(cpu.bus.interrupt_flag, JOYPAD);
+    }
+
+    #[test]
+    fn states_keep_the_interrupt_master_enable() {
+        let mut cpu = cpu(&[0xFB]);
+        cpu.step();
+        let mut state = StateWriter::new();
+        cpu.save_state(&mut state);
+        let bytes = state.finish();
+
+        let mut loaded = self::cpu(&[]);
+        loaded.load_state(&mut StateReader::new(&bytes).unwrap()).unwrap();
+        request(&mut loaded, VBLANK, VBLANK);
+        loaded.step();
+        assert_eq!(loaded.pc, 0x40);
+    }
+}
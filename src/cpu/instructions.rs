@@ -4,15 +4,12 @@ pub(crate) enum Instruction {
     SUB(ArithmeticTarget),
     SBC(ArithmeticTarget),
     INC(IncTarget),
-    RLC(PrefixTarget),
-    JP(JumpTest),
     LD(LoadType),
-    PUSH(MultipleBytesRegister),
-    POP(MultipleBytesRegister),
-    CALL(JumpTest),
-    RET(JumpTest),
-    NOP(),
     HALT(),
+    STOP(),
+    EI(),
+    DI(),
+    RETI(),
     SWAP(PrefixTarget),
     XOR(ArithmeticTarget)
 }
@@ -29,20 +26,12 @@ pub(crate) enum PrefixTarget {
     A, B, C, D, E, H, L, HLI
 }
 
-pub(crate) enum JumpTest {
-    NotZero,
-    Zero,
-    NotCarry,
-    Carry,
-    Always
-}
-
 pub(crate) enum LoadByteTarget {
     A, B, C, D, E, H, L, HLI
 }
 
 pub(crate) enum LoadByteSource {
-    A, B, C, D, E, H, L, D8, HLI
+    A, B, C, D, E, H, L, HLI
 }
 
 pub(crate) enum LoadType {
@@ -55,10 +44,7 @@ pub(crate) enum LoadWordTarget {
     BC, DE, HL, SP
 }
 
-pub(crate) enum MultipleBytesRegister {
-    AF, BC, DE, HL
-}
-
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum Indirect {
     BCIndirect,
     DEIndirect,
@@ -69,6 +55,35 @@ pub(crate) enum Indirect {
 }
 
 impl Instruction {
+    /// How many clock cycles the instruction takes.
+    pub(crate) fn cycles(&self) -> u16 {
+        match self {
+            Instruction::ADD(target) | Instruction::ADC(target) | Instruction::SUB(target)
+            | Instruction::SBC(target) | Instruction::XOR(target) => match target {
+                ArithmeticTarget::HLI | ArithmeticTarget::D8 => 8,
+                _ => 4,
+            },
+            Instruction::INC(target) => match target {
+                IncTarget::HLI => 12,
+                IncTarget::BC | IncTarget::DE | IncTarget::HL | IncTarget::SP => 8,
+                _ => 4,
+            },
+            Instruction::SWAP(target) => match target {
+                PrefixTarget::HLI => 16,
+                _ => 8,
+            },
+            Instruction::LD(load_type) => match load_type {
+                LoadType::Byte(LoadByteTarget::HLI, _) | LoadType::Byte(_, LoadByteSource::HLI) => 8,
+                LoadType::Byte(_, _) => 4,
+                LoadType::Word(_) => 12,
+                LoadType::IndirectFromA(Indirect::WordIndirect) => 16,
+                LoadType::IndirectFromA(_) => 8,
+            },
+            Instruction::RETI() => 16,
+            Instruction::HALT() | Instruction::STOP() | Instruction::EI() | Instruction::DI() => 4,
+        }
+    }
+
     pub(crate) fn from_byte(byte: u8, is_prefix: bool) -> Option<Instruction> {
         if is_prefix {
             Instruction::from_byte_prefixed(byte)
@@ -148,6 +163,12 @@ impl Instruction {
             0x9e => Some(Instruction::SBC(ArithmeticTarget::HLI)),
             0xde => Some(Instruction::SBC(ArithmeticTarget::D8)),
 
+            0x10 => Some(Instruction::STOP()),
+            0x76 => Some(Instruction::HALT()),
+            0xfb => Some(Instruction::EI()),
+            0xf3 => Some(Instruction::DI()),
+            0xd9 => Some(Instruction::RETI()),
+
             0x01 => Some(Instruction::LD(LoadType::Word(LoadWordTarget::BC))),
             0x11 => Some(Instruction::LD(LoadType::Word(LoadWordTarget::DE))),
             0x21 => Some(Instruction::LD(LoadType::Word(LoadWordTarget::HL))),
@@ -163,6 +184,78 @@ impl Instruction {
             0xae => Some(Instruction::XOR(ArithmeticTarget::HLI)),
             0xee => Some(Instruction::XOR(ArithmeticTarget::D8)),
 
+            // 0x76 sits in the middle of these, it is HALT.
+            0x40 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::B))),
+            0x41 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::C))),
+            0x42 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::D))),
+            0x43 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::E))),
+            0x44 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::H))),
+            0x45 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::L))),
+            0x46 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::HLI))),
+            0x47 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::A))),
+
+            0x48 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::C, LoadByteSource::B))),
+            0x49 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::C, LoadByteSource::C))),
+            0x4a => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::C, LoadByteSource::D))),
+            0x4b => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::C, LoadByteSource::E))),
+            0x4c => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::C, LoadByteSource::H))),
+            0x4d => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::C, LoadByteSource::L))),
+            0x4e => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::C, LoadByteSource::HLI))),
+            0x4f => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::C, LoadByteSource::A))),
+
+            0x50 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::D, LoadByteSource::B))),
+            0x51 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::D, LoadByteSource::C))),
+            0x52 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::D, LoadByteSource::D))),
+            0x53 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::D, LoadByteSource::E))),
+            0x54 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::D, LoadByteSource::H))),
+            0x55 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::D, LoadByteSource::L))),
+            0x56 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::D, LoadByteSource::HLI))),
+            0x57 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::D, LoadByteSource::A))),
+
+            0x58 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::E, LoadByteSource::B))),
+            0x59 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::E, LoadByteSource::C))),
+            0x5a => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::E, LoadByteSource::D))),
+            0x5b => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::E, LoadByteSource::E))),
+            0x5c => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::E, LoadByteSource::H))),
+            0x5d => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::E, LoadByteSource::L))),
+            0x5e => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::E, LoadByteSource::HLI))),
+            0x5f => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::E, LoadByteSource::A))),
+
+            0x60 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::H, LoadByteSource::B))),
+            0x61 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::H, LoadByteSource::C))),
+            0x62 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::H, LoadByteSource::D))),
+            0x63 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::H, LoadByteSource::E))),
+            0x64 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::H, LoadByteSource::H))),
+            0x65 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::H, LoadByteSource::L))),
+            0x66 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::H, LoadByteSource::HLI))),
+            0x67 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::H, LoadByteSource::A))),
+
+            0x68 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::L, LoadByteSource::B))),
+            0x69 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::L, LoadByteSource::C))),
+            0x6a => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::L, LoadByteSource::D))),
+            0x6b => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::L, LoadByteSource::E))),
+            0x6c => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::L, LoadByteSource::H))),
+            0x6d => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::L, LoadByteSource::L))),
+            0x6e => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::L, LoadByteSource::HLI))),
+            0x6f => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::L, LoadByteSource::A))),
+
+            0x70 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::B))),
+            0x71 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::C))),
+            0x72 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::D))),
+            0x73 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::E))),
+            0x74 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::H))),
+            0x75 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::L))),
+            0x77 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::A))),
+
+            0x78 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::A, LoadByteSource::B))),
+            0x79 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::A, LoadByteSource::C))),
+            0x7a => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::A, LoadByteSource::D))),
+            0x7b => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::A, LoadByteSource::E))),
+            0x7c => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::A, LoadByteSource::H))),
+            0x7d => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::A, LoadByteSource::L))),
+            0x7e => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::A, LoadByteSource::HLI))),
+            0x7f => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::A, LoadByteSource::A))),
+
             0xe2 => Some(Instruction::LD(LoadType::IndirectFromA(Indirect::LastByteIndirect))),
             0x02 => Some(Instruction::LD(LoadType::IndirectFromA(Indirect::BCIndirect))),
             0x12 => Some(Instruction::LD(LoadType::IndirectFromA(Indirect::DEIndirect))),
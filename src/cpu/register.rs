@@ -23,10 +23,6 @@ pub(crate) struct FlagRegister {
 }
 
 impl Registers {
-    pub(crate) fn get_af(&self) -> u16 {
-        (self.a as u16) << 8 | u8::from(self.f) as u16
-    }
-
     pub(crate) fn get_bc(&self) -> u16 {
         (self.b as u16) << 8 | self.c as u16
     }
@@ -71,10 +67,10 @@ impl std::convert::From<FlagRegister> for u8  {
 
 impl std::convert::From<u8> for FlagRegister {
     fn from(byte: u8) -> Self {
-        let zero = (byte >> ZERO_FLAG_BYTE_POSITION) != 0;
-        let subtract = (byte >> SUBTRACT_FLAG_BYTE_POSITION) != 0;
-        let half_carry = (byte >> HALF_CARRY_FLAG_BYTE_POSITION) != 0;
-        let carry = (byte >> CARRY_FLAG_BYTE_POSITION) != 0;
+        let zero = (byte >> ZERO_FLAG_BYTE_POSITION) & 1 != 0;
+        let subtract = (byte >> SUBTRACT_FLAG_BYTE_POSITION) & 1 != 0;
+        let half_carry = (byte >> HALF_CARRY_FLAG_BYTE_POSITION) & 1 != 0;
+        let carry = (byte >> CARRY_FLAG_BYTE_POSITION) & 1 != 0;
 
         FlagRegister {
             zero,
@@ -0,0 +1,178 @@
+/// How the native picture is stretched to fill the window.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum ScalingMode {
+    /// Only whole multiples of the native size, every pixel stays sharp.
+    Integer,
+    /// Fills as much of the window as the aspect ratio allows, blending
+    /// neighbouring pixels.
+    Smooth,
+}
+
+impl ScalingMode {
+    pub(crate) fn from_name(name: &str) -> Option<ScalingMode> {
+        match name {
+            "integer" => Some(ScalingMode::Integer),
+            "smooth" => Some(ScalingMode::Smooth),
+            _ => None,
+        }
+    }
+}
+
+const LETTERBOX_COLOR: u32 = 0xFF000000;
+
+/// Fits pictures of any size into a window of any size, keeping their
+/// aspect ratio and filling the remaining space with black bars.
+pub(crate) struct Display {
+    mode: ScalingMode,
+    buffer: Vec<u32>,
+}
+
+impl Display {
+    pub(crate) fn new(mode: ScalingMode) -> Display {
+        Display {
+            mode,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Scales `source` to a `width` by `height` picture and returns it.
+    pub(crate) fn present(&mut self, source: &[u32], source_width: usize, source_height: usize, width: usize, height: usize) -> &[u32] {
+        let width = width.max(1);
+        let height = height.max(1);
+        self.buffer.clear();
+        self.buffer.resize(width * height, LETTERBOX_COLOR);
+
+        let (target_width, target_height) = match self.mode {
+            ScalingMode::Integer => {
+                let factor = (width / source_width).min(height / source_height).max(1);
+                (source_width * factor, source_height * factor)
+            }
+            ScalingMode::Smooth => {
+                if width * source_height > height * source_width {
+                    (height * source_width / source_height, height)
+                } else {
+                    (width, width * source_height / source_width)
+                }
+            }
+        };
+        let target_width = target_width.min(width);
+        let target_height = target_height.min(height);
+        let left = (width - target_width) / 2;
+        let top = (height - target_height) / 2;
+
+        for y in 0..target_height {
+            let row = &mut self.buffer[(top + y) * width + left..(top + y) * width + left + target_width];
+            match self.mode {
+                ScalingMode::Integer => {
+                    let source_row = &source[(y * source_height / target_height) * source_width..];
+                    for (x, pixel) in row.iter_mut().enumerate() {
+                        *pixel = source_row[x * source_width / target_width];
+                    }
+                }
+                ScalingMode::Smooth => {
+                    for (x, pixel) in row.iter_mut().enumerate() {
+                        *pixel = bilinear(source, source_width, source_height, x, y, target_width, target_height);
+                    }
+                }
+            }
+        }
+        &self.buffer
+    }
+}
+
+fn bilinear(source: &[u32], source_width: usize, source_height: usize, x: usize, y: usize, target_width: usize, target_height: usize) -> u32 {
+    // Work in 1/256th of a source pixel, sampling at the centre of the
+    // destination pixel.
+    let fixed_x = ((2 * x + 1) * source_width * 256 / (2 * target_width)).saturating_sub(128);
+    let fixed_y = ((2 * y + 1) * source_height * 256 / (2 * target_height)).saturating_sub(128);
+    let x0 = (fixed_x >> 8).min(source_width - 1);
+    let y0 = (fixed_y >> 8).min(source_height - 1);
+    let x1 = (x0 + 1).min(source_width - 1);
+    let y1 = (y0 + 1).min(source_height - 1);
+    let weight_x = (fixed_x & 0xFF) as u32;
+    let weight_y = (fixed_y & 0xFF) as u32;
+
+    let top = blend(source[y0 * source_width + x0], source[y0 * source_width + x1], weight_x);
+    let bottom = blend(source[y1 * source_width + x0], source[y1 * source_width + x1], weight_x);
+    blend(top, bottom, weight_y)
+}
+
+/// Mixes two 0xAARRGGBB colours, `weight` being how much of `b` to use out
+/// of 256.
+pub(crate) fn blend(a: u32, b: u32, weight: u32) -> u32 {
+    let mut result = 0;
+    for shift in [0, 8, 16, 24] {
+        let channel_a = (a >> shift) & 0xFF;
+        let channel_b = (b >> shift) & 0xFF;
+        let channel = (channel_a * (256 - weight) + channel_b * weight) >> 8;
+        result |= channel << shift;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: u32 = 0xFF000000;
+    const WHITE: u32 = 0xFFFFFFFF;
+    const RED: u32 = 0xFFFF0000;
+    const BLUE: u32 = 0xFF0000FF;
+
+    fn rows(pixels: &[u32], width: usize) -> Vec<Vec<u32>> {
+        pixels.chunks(width).map(<[u32]>::to_vec).collect()
+    }
+
+    #[test]
+    fn integer_scaling_repeats_pixels_in_the_middle_of_odd_windows() {
+        let mut display = Display::new(ScalingMode::Integer);
+        // Twice the size fits, with one spare column on each side and one
+        // spare row left at the bottom.
+        let pixels = display.present(&[RED, BLUE, WHITE, RED], 2, 2, 6, 5);
+        let x = LETTERBOX_COLOR;
+        assert_eq!(
+            rows(pixels, 6),
+            [
+                [x, RED, RED, BLUE, BLUE, x],
+                [x, RED, RED, BLUE, BLUE, x],
+                [x, WHITE, WHITE, RED, RED, x],
+                [x, WHITE, WHITE, RED, RED, x],
+                [x, x, x, x, x, x],
+            ]
+        );
+    }
+
+    #[test]
+    fn integer_scaling_skips_pixels_in_windows_smaller_than_the_picture() {
+        let mut display = Display::new(ScalingMode::Integer);
+        // Every other pixel is kept, rather than the left half.
+        assert_eq!(display.present(&[RED, BLUE, WHITE, BLACK], 4, 1, 2, 1), [RED, WHITE]);
+        assert_eq!(display.present(&[RED, BLUE, WHITE, RED], 2, 2, 1, 0), [RED]);
+    }
+
+    #[test]
+    fn smooth_scaling_fills_one_side_and_letterboxes_the_other() {
+        let mut display = Display::new(ScalingMode::Smooth);
+        let pixels = display.present(&[RED, RED], 2, 1, 5, 4);
+        // 5 by 2 fits, it is centred with a black bar above and below.
+        let x = LETTERBOX_COLOR;
+        assert_eq!(rows(pixels, 5), [[x; 5], [RED; 5], [RED; 5], [x; 5]]);
+    }
+
+    #[test]
+    fn bilinear_filtering_blends_inside_and_clamps_at_the_edges() {
+        let mut display = Display::new(ScalingMode::Smooth);
+        let pixels = display.present(&[BLACK, WHITE], 2, 1, 4, 2);
+        // The outer pixels sample past the centre of the edge pixels and
+        // keep their colour instead of wrapping around or going dark.
+        assert_eq!(pixels[..4], [BLACK, 0xFF3F3F3F, 0xFFBFBFBF, WHITE]);
+        assert_eq!(pixels[4..], pixels[..4]);
+    }
+
+    #[test]
+    fn blend_mixes_every_channel() {
+        assert_eq!(blend(BLACK, WHITE, 0), BLACK);
+        assert_eq!(blend(BLACK, WHITE, 128), 0xFF7F7F7F);
+        assert_eq!(blend(0x00FF0000, 0xFF0000FF, 64), 0x3FBF003F);
+    }
+}
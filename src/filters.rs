@@ -0,0 +1,262 @@
+use crate::display::blend;
+
+/// A picture of 0xAARRGGBB pixels on its way to the window.
+pub(crate) struct Frame {
+    pub(crate) pixels: Vec<u32>,
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+}
+
+impl Frame {
+    fn pixel(&self, x: usize, y: usize) -> u32 {
+        self.pixels[y * self.width + x]
+    }
+}
+
+/// A post-processing step run on every finished frame.
+pub(crate) trait VideoFilter {
+    fn apply(&mut self, frame: Frame) -> Frame;
+}
+
+impl dyn VideoFilter {
+    pub(crate) fn from_name(name: &str) -> Option<Box<dyn VideoFilter>> {
+        match name {
+            "grid" => Some(Box::new(DotMatrix)),
+            "ghosting" => Some(Box::new(Ghosting { previous: None })),
+            "scale2x" => Some(Box::new(Scale2x)),
+            "color-correction" => Some(Box::new(ColorCorrection)),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) const FILTER_NAMES: [&str; 4] = ["grid", "ghosting", "scale2x", "color-correction"];
+
+/// Runs filters one after the other, in the order they were given.
+pub(crate) struct FilterChain {
+    filters: Vec<Box<dyn VideoFilter>>,
+}
+
+impl FilterChain {
+    pub(crate) fn new(filters: Vec<Box<dyn VideoFilter>>) -> FilterChain {
+        FilterChain { filters }
+    }
+
+    pub(crate) fn apply(&mut self, frame: Frame) -> Frame {
+        self.filters.iter_mut().fold(frame, |frame, filter| filter.apply(frame))
+    }
+}
+
+/// Draws every pixel as a 3x3 dot separated from its neighbours by a
+/// darker line, like the DMG screen seen up close.
+pub(crate) struct DotMatrix;
+
+const DOT_SIZE: usize = 3;
+const DOT_GAP_DARKENING: u32 = 64;
+
+impl VideoFilter for DotMatrix {
+    fn apply(&mut self, frame: Frame) -> Frame {
+        let width = frame.width * DOT_SIZE;
+        let height = frame.height * DOT_SIZE;
+        let mut pixels = vec![0; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = frame.pixel(x / DOT_SIZE, y / DOT_SIZE);
+                let is_gap = x % DOT_SIZE == DOT_SIZE - 1 || y % DOT_SIZE == DOT_SIZE - 1;
+                pixels[y * width + x] = if is_gap {
+                    blend(pixel, 0xFF000000, DOT_GAP_DARKENING)
+                } else {
+                    pixel
+                };
+            }
+        }
+        Frame { pixels, width, height }
+    }
+}
+
+/// Mixes every frame with the previous one, the way the slow DMG LCD
+/// does. Games rely on it to make flickering sprites look transparent.
+pub(crate) struct Ghosting {
+    previous: Option<Vec<u32>>,
+}
+
+impl VideoFilter for Ghosting {
+    fn apply(&mut self, mut frame: Frame) -> Frame {
+        let current = frame.pixels.clone();
+        if let Some(previous) = self.previous.as_ref().filter(|previous| previous.len() == current.len()) {
+            for (pixel, &previous) in frame.pixels.iter_mut().zip(previous) {
+                *pixel = blend(*pixel, previous, 128);
+            }
+        }
+        self.previous = Some(current);
+        frame
+    }
+}
+
+/// The Scale2x pixel art upscaler: doubles the size of the picture and
+/// rounds diagonal edges instead of just repeating pixels.
+pub(crate) struct Scale2x;
+
+impl VideoFilter for Scale2x {
+    fn apply(&mut self, frame: Frame) -> Frame {
+        let width = frame.width * 2;
+        let height = frame.height * 2;
+        let mut pixels = vec![0; width * height];
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let e = frame.pixel(x, y);
+                let b = frame.pixel(x, y.saturating_sub(1));
+                let d = frame.pixel(x.saturating_sub(1), y);
+                let f = frame.pixel((x + 1).min(frame.width - 1), y);
+                let h = frame.pixel(x, (y + 1).min(frame.height - 1));
+
+                let (e0, e1, e2, e3) = if b != h && d != f {
+                    (
+                        if d == b { d } else { e },
+                        if b == f { f } else { e },
+                        if d == h { d } else { e },
+                        if h == f { f } else { e },
+                    )
+                } else {
+                    (e, e, e, e)
+                };
+                let top = 2 * y * width + 2 * x;
+                let bottom = top + width;
+                pixels[top] = e0;
+                pixels[top + 1] = e1;
+                pixels[bottom] = e2;
+                pixels[bottom + 1] = e3;
+            }
+        }
+        Frame { pixels, width, height }
+    }
+}
+
+/// Bleeds the channels into each other like the GBC LCD does, CGB games
+/// were designed to look right on it and are too saturated without it.
+pub(crate) struct ColorCorrection;
+
+impl VideoFilter for ColorCorrection {
+    fn apply(&mut self, mut frame: Frame) -> Frame {
+        for pixel in frame.pixels.iter_mut() {
+            let red = (*pixel >> 16) & 0xFF;
+            let green = (*pixel >> 8) & 0xFF;
+            let blue = *pixel & 0xFF;
+            let corrected_red = (red * 26 + green * 4 + blue * 2) / 32;
+            let corrected_green = (green * 24 + blue * 8) / 32;
+            let corrected_blue = (red * 6 + green * 4 + blue * 22) / 32;
+            *pixel = (*pixel & 0xFF000000) | corrected_red << 16 | corrected_green << 8 | corrected_blue;
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: u32 = 0xFFFF0000;
+    const WHITE: u32 = 0xFFFFFFFF;
+
+    fn frame(pixels: &[u32], width: usize) -> Frame {
+        Frame { pixels: pixels.to_vec(), width, height: pixels.len() / width }
+    }
+
+    // The rule as written by the author of Scale2x, neighbours past the
+    // edges being the edge pixels themselves.
+    fn reference_scale2x(frame: &Frame) -> Vec<u32> {
+        let pixel = |x: isize, y: isize| {
+            frame.pixel(x.clamp(0, frame.width as isize - 1) as usize, y.clamp(0, frame.height as isize - 1) as usize)
+        };
+        let width = frame.width * 2;
+        let mut pixels = vec![0; width * frame.height * 2];
+        for y in 0..frame.height as isize {
+            for x in 0..frame.width as isize {
+                let (b, d, e, f, h) = (pixel(x, y - 1), pixel(x - 1, y), pixel(x, y), pixel(x + 1, y), pixel(x, y + 1));
+                let top = 2 * y as usize * width + 2 * x as usize;
+                pixels[top] = if d == b && b != f && d != h { d } else { e };
+                pixels[top + 1] = if b == f && b != d && f != h { f } else { e };
+                pixels[top + width] = if d == h && d != b && h != f { d } else { e };
+                pixels[top + width + 1] = if h == f && d != h && b != f { f } else { e };
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn scale2x_follows_the_reference_rule() {
+        // Few colours, so that many neighbours are equal.
+        let mut seed = 0x2545F491u32;
+        let pixels: Vec<u32> = (0..12 * 9)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                [RED, WHITE, 0xFF000000][seed as usize % 3]
+            })
+            .collect();
+        let source = frame(&pixels, 12);
+        let scaled = Scale2x.apply(frame(&pixels, 12));
+        assert_eq!((scaled.width, scaled.height), (24, 18));
+        assert_eq!(scaled.pixels, reference_scale2x(&source));
+    }
+
+    #[test]
+    fn scale2x_rounds_diagonals() {
+        let x = RED;
+        let o = WHITE;
+        let scaled = Scale2x.apply(frame(&[x, o, o, x], 2));
+        // The inner corners of each pixel are cut along the diagonal.
+        let rows: Vec<&[u32]> = scaled.pixels.chunks(4).collect();
+        assert_eq!(rows, [[x, x, o, o], [x, o, x, o], [o, x, o, x], [o, o, x, x]]);
+    }
+
+    #[test]
+    fn dot_matrix_darkens_the_gaps() {
+        let dots = DotMatrix.apply(frame(&[RED], 1));
+        let gap = 0xFFBF0000;
+        assert_eq!((dots.width, dots.height), (3, 3));
+        assert_eq!(dots.pixels, [RED, RED, gap, RED, RED, gap, gap, gap, gap]);
+    }
+
+    #[test]
+    fn ghosting_mixes_with_the_previous_frame() {
+        let mut ghosting = Ghosting { previous: None };
+        assert_eq!(ghosting.apply(frame(&[WHITE], 1)).pixels, [WHITE]);
+        assert_eq!(ghosting.apply(frame(&[0xFF000000], 1)).pixels, [0xFF7F7F7F]);
+        // A frame of another size starts over.
+        assert_eq!(ghosting.apply(frame(&[RED, RED], 2)).pixels, [RED, RED]);
+    }
+
+    #[test]
+    fn colour_correction_bleeds_channels_and_keeps_white() {
+        let corrected = ColorCorrection.apply(frame(&[WHITE, RED], 2));
+        assert_eq!(corrected.pixels, [WHITE, 0xFFCF002F]);
+    }
+
+    // Appends its digit to every pixel, to tell in which order filters ran.
+    struct Digit(u32);
+
+    impl VideoFilter for Digit {
+        fn apply(&mut self, mut frame: Frame) -> Frame {
+            frame.pixels.iter_mut().for_each(|pixel| *pixel = *pixel * 10 + self.0);
+            frame
+        }
+    }
+
+    #[test]
+    fn chains_run_filters_in_the_given_order() {
+        let mut chain = FilterChain::new(vec![Box::new(Digit(1)), Box::new(Digit(2)), Box::new(Digit(3))]);
+        assert_eq!(chain.apply(frame(&[0], 1)).pixels, [123]);
+        let mut chain = FilterChain::new(vec![Box::new(Digit(1)), Box::new(Scale2x), Box::new(DotMatrix)]);
+        let frame = chain.apply(frame(&[0], 1));
+        assert_eq!((frame.width, frame.height), (6, 6));
+        assert_eq!(frame.pixels[0], 1);
+    }
+
+    #[test]
+    fn every_name_makes_a_filter() {
+        assert!(FILTER_NAMES.iter().all(|name| <dyn VideoFilter>::from_name(name).is_some()));
+        assert!(<dyn VideoFilter>::from_name("crt").is_none());
+    }
+}
@@ -0,0 +1,347 @@
+use crate::audio::{APU, CHANNEL_COUNT};
+use crate::cpu::CPU;
+use crate::graphics::FrameBuffer;
+use crate::infrared::InfraredEndpoint;
+use crate::joypad::Button;
+use crate::memory::MemoryBus;
+use crate::serial::SerialEndpoint;
+use crate::state::{StateReader, StateWriter};
+use crate::trace::Tracer;
+
+/// Clock cycles the LCD takes to draw one frame.
+pub const ONE_FRAME_IN_CYCLES: usize = 70224;
+
+// What the DMG boot ROM leaves in the IO registers: the APU powered with
+// square 1 still set up from the chime, the LCD on, the background palette
+// and a pending VBlank interrupt.
+const POST_BOOT_IO: [(u16, u8); 8] = [
+    (0xFF26, 0x80),
+    (0xFF11, 0x80),
+    (0xFF12, 0xF3),
+    (0xFF24, 0x77),
+    (0xFF25, 0xF3),
+    (0xFF40, 0x91),
+    (0xFF47, 0xFC),
+    (0xFF0F, 0x01),
+];
+// LY as gameboy-doctor's reference logs see it.
+const DOCTOR_LY: u8 = 0x90;
+
+/// The CPU registers, as seen between two instructions.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CpuRegisters {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+/// A whole Game Boy, to be driven by a frontend, a tool or a test.
+///
+/// Nothing here paces emulation: it runs as fast as it is stepped, and
+/// the sound and the pictures it produces are for the caller to present.
+pub struct GameBoy {
+    // Boxed, the whole machine is too big to be moved around on the stack.
+    cpu: Box<CPU>,
+    // Kept to build a scratch machine when checking a save state.
+    boot: Option<Vec<u8>>,
+    rom: Vec<u8>,
+    frame_cycles: usize,
+    frames: u64,
+    cycles: u64,
+    tracer: Option<Tracer>,
+}
+
+impl GameBoy {
+    /// Powers on with the cartridge `rom` inserted, running the boot ROM
+    /// first if there is one. Without one, the cartridge starts at 0x0100
+    /// in the state the DMG boot ROM leaves behind.
+    pub fn new(boot: Option<Vec<u8>>, rom: Vec<u8>) -> GameBoy {
+        let mut cpu = Box::new(CPU::new(MemoryBus::new(boot.clone(), rom.clone())));
+        if boot.is_none() {
+            GameBoy::skip_boot_rom(&mut cpu);
+        }
+        GameBoy {
+            cpu,
+            boot,
+            rom,
+            frame_cycles: 0,
+            frames: 0,
+            cycles: 0,
+            tracer: None,
+        }
+    }
+
+    fn skip_boot_rom(cpu: &mut CPU) {
+        let registers = &mut cpu.registers;
+        registers.set_af(0x01B0);
+        registers.set_bc(0x0013);
+        registers.set_de(0x00D8);
+        registers.set_hl(0x014D);
+        cpu.sp = 0xFFFE;
+        cpu.pc = 0x0100;
+        for (address, value) in POST_BOOT_IO {
+            cpu.bus.write_byte(address, value);
+        }
+    }
+
+    /// Makes LY read 0x90 whatever line is drawn, as gameboy-doctor's
+    /// reference logs were made with, so that traces can be compared.
+    pub fn set_doctor_mode(&mut self, enabled: bool) {
+        self.cpu.bus.graphics.ly_stub = enabled.then_some(DOCTOR_LY);
+    }
+
+    /// Runs one instruction and returns how many clock cycles it took.
+    pub fn step_instruction(&mut self) -> u16 {
+        // A halted or stopped CPU runs no instruction.
+        if self.tracer.is_some() && !self.cpu.is_halted && !self.cpu.is_stopped {
+            let registers = self.registers();
+            let pcmem = std::array::from_fn(|offset| self.read_memory(registers.pc.wrapping_add(offset as u16)));
+            if let Some(tracer) = &mut self.tracer {
+                tracer.trace(&registers, pcmem, self.cycles);
+            }
+        }
+        let cycles = self.cpu.step();
+        self.cycles += cycles as u64;
+        self.frame_cycles += cycles as usize;
+        if self.frame_cycles >= ONE_FRAME_IN_CYCLES {
+            self.frame_cycles -= ONE_FRAME_IN_CYCLES;
+            self.frames += 1;
+        }
+        cycles
+    }
+
+    /// Runs until the end of the current frame.
+    pub fn step_frame(&mut self) {
+        let frames = self.frames;
+        while self.frames == frames {
+            self.step_instruction();
+        }
+    }
+
+    /// How many frames were run since power on.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// How many clock cycles were run since power on.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Where the CPU is about to read its next instruction.
+    pub fn pc(&self) -> u16 {
+        self.cpu.pc
+    }
+
+    pub fn registers(&self) -> CpuRegisters {
+        let registers = &self.cpu.registers;
+        CpuRegisters {
+            a: registers.a,
+            f: u8::from(registers.f),
+            b: registers.b,
+            c: registers.c,
+            d: registers.d,
+            e: registers.e,
+            h: registers.h,
+            l: registers.l,
+            sp: self.cpu.sp,
+            pc: self.cpu.pc,
+        }
+    }
+
+    /// Whether the CPU ran LD B,B since the last call. Test ROMs use it as a
+    /// software breakpoint, to tell they are done.
+    pub fn take_breakpoint(&mut self) -> bool {
+        self.cpu.take_breakpoint()
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.cpu.bus.set_button(button, pressed);
+    }
+
+    /// The picture being drawn. It is complete after `step_frame`.
+    pub fn frame_buffer(&self) -> &FrameBuffer {
+        &self.cpu.bus.graphics.frame_buffer
+    }
+
+    /// The stereo samples produced since the last call, at the sample rate
+    /// of the APU.
+    pub fn take_audio_samples(&mut self) -> Vec<[f32; 2]> {
+        self.cpu.bus.audio.take_samples()
+    }
+
+    /// The samples of every channel produced since the last call, empty
+    /// unless the channels are tapped.
+    pub fn take_channel_samples(&mut self) -> Vec<[[f32; 2]; CHANNEL_COUNT]> {
+        self.cpu.bus.audio.take_channel_samples()
+    }
+
+    /// The sound hardware, to read what it plays.
+    pub fn audio(&self) -> &APU {
+        &self.cpu.bus.audio
+    }
+
+    /// The sound hardware, to change the sample rate, mutes or taps.
+    pub fn audio_mut(&mut self) -> &mut APU {
+        &mut self.cpu.bus.audio
+    }
+
+    /// Plugs something in the link port.
+    pub fn connect_serial(&mut self, endpoint: Box<dyn SerialEndpoint>) {
+        self.cpu.bus.serial.connect(endpoint);
+    }
+
+    /// Puts something in front of the infrared port.
+    pub fn connect_infrared(&mut self, endpoint: Box<dyn InfraredEndpoint>) {
+        self.cpu.bus.infrared.connect(endpoint);
+    }
+
+    /// Reads a byte without side effects, 0xFF where nothing is mapped yet.
+    pub fn read_memory(&self, address: u16) -> u8 {
+        self.cpu.bus.try_read_byte(address).unwrap_or(0xFF)
+    }
+
+    /// Starts logging every instruction, in place of any previous trace.
+    pub fn start_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Stops logging instructions and tells whether the whole trace was
+    /// written, `None` if there was no trace.
+    pub fn stop_trace(&mut self) -> Option<Result<(), String>> {
+        self.tracer.take().map(Tracer::finish)
+    }
+
+    /// The whole address space, with 0xFF where nothing is mapped yet.
+    pub fn dump_memory(&self) -> Vec<u8> {
+        self.cpu.bus.dump()
+    }
+
+    /// Saves the state of the hardware. Plugged endpoints and the sound
+    /// settings are not part of it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        self.cpu.save_state(&mut state);
+        state.u64(self.frame_cycles as u64);
+        state.u64(self.frames);
+        state.u64(self.cycles);
+        state.finish()
+    }
+
+    /// Restores a state made by `save_state`. A state that can't be read
+    /// leaves the machine untouched.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut scratch = CPU::new(MemoryBus::new(self.boot.clone(), self.rom.clone()));
+        GameBoy::read_state(&mut scratch, bytes)?;
+        (self.frame_cycles, self.frames, self.cycles) = GameBoy::read_state(&mut self.cpu, bytes)?;
+        Ok(())
+    }
+
+    // Loads the state of `cpu` and returns the frame cycles, frames and
+    // cycles counted so far.
+    fn read_state(cpu: &mut CPU, bytes: &[u8]) -> Result<(usize, u64, u64), String> {
+        let mut state = StateReader::new(bytes)?;
+        cpu.load_state(&mut state)?;
+        let frame_cycles = state.u64()? as usize;
+        let frames = state.u64()?;
+        let cycles = state.u64()?;
+        state.finish()?;
+        Ok((frame_cycles, frames, cycles))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // XOR A over and over, about the only thing the CPU runs throughout.
+    // It runs out of ROM a bit before the end of the second frame.
+    fn game_boy() -> GameBoy {
+        GameBoy::new(None, vec![0xAF; 0x8000])
+    }
+
+    #[test]
+    fn step_frame_runs_a_whole_frame() {
+        let mut game_boy = game_boy();
+        game_boy.step_instruction();
+        game_boy.step_frame();
+        assert_eq!(game_boy.frames(), 1);
+        assert!(game_boy.cycles() >= ONE_FRAME_IN_CYCLES as u64);
+        assert!(game_boy.cycles() < ONE_FRAME_IN_CYCLES as u64 + 4);
+    }
+
+    #[test]
+    fn loading_a_state_goes_back_in_time() {
+        let mut game_boy = game_boy();
+        game_boy.step_frame();
+        let state = game_boy.save_state();
+        let memory = game_boy.dump_memory();
+        game_boy.set_button(Button::Start, true);
+        for _ in 0..1000 {
+            game_boy.step_instruction();
+        }
+        assert_ne!(game_boy.dump_memory(), memory);
+
+        game_boy.load_state(&state).unwrap();
+        assert_eq!(game_boy.dump_memory(), memory);
+        assert_eq!(game_boy.save_state(), state);
+    }
+
+    #[test]
+    fn loading_a_state_restores_the_counters() {
+        let mut game_boy = game_boy();
+        game_boy.step_instruction();
+        let state = game_boy.save_state();
+        game_boy.step_frame();
+        assert_eq!(game_boy.frames(), 1);
+
+        game_boy.load_state(&state).unwrap();
+        assert_eq!((game_boy.frames(), game_boy.cycles(), game_boy.frame_cycles), (0, 4, 4));
+    }
+
+    #[test]
+    fn without_a_boot_rom_the_cartridge_starts_after_boot() {
+        let game_boy = game_boy();
+        let registers = CpuRegisters { a: 0x01, f: 0xB0, b: 0, c: 0x13, d: 0, e: 0xD8, h: 0x01, l: 0x4D, sp: 0xFFFE, pc: 0x0100 };
+        assert_eq!(game_boy.registers(), registers);
+        assert_eq!(game_boy.read_memory(0xFF40), 0x91);
+        assert_eq!(game_boy.read_memory(0xFF47), 0xFC);
+        assert_eq!(game_boy.read_memory(0xFF0F), 0xE1);
+        assert_eq!(game_boy.read_memory(0xFF26) & 0x80, 0x80);
+
+        let game_boy = GameBoy::new(Some(vec![0; 0x100]), vec![0xAF; 0x8000]);
+        assert_eq!(game_boy.pc(), 0);
+        assert_eq!(game_boy.registers().sp, 0);
+    }
+
+    #[test]
+    fn doctor_mode_pins_ly() {
+        let mut game_boy = game_boy();
+        game_boy.set_doctor_mode(true);
+        for _ in 0..2000 {
+            game_boy.step_instruction();
+            assert_eq!(game_boy.read_memory(0xFF44), 0x90);
+        }
+        game_boy.set_doctor_mode(false);
+        assert_ne!(game_boy.read_memory(0xFF44), 0x90);
+    }
+
+    #[test]
+    fn broken_states_are_refused() {
+        let mut game_boy = game_boy();
+        game_boy.step_frame();
+        let state = game_boy.save_state();
+        let memory = game_boy.dump_memory();
+
+        assert!(game_boy.load_state(b"garbage").is_err());
+        assert!(game_boy.load_state(&state[..state.len() - 1]).is_err());
+        assert_eq!(game_boy.dump_memory(), memory);
+    }
+}
@@ -1,9 +1,61 @@
-use crate::WINDOW_DIMENSIONS;
+mod frame_buffer;
+mod palette;
+
+pub use crate::graphics::frame_buffer::{ColorMode, FrameBuffer, NUMBER_OF_PIXELS, SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use crate::graphics::palette::{Argb8888, Palette, PaletteCycle, PaletteStage, RawIndices, Rgb, Rgba8888};
+use crate::state::{StateReader, StateWriter};
 
 pub(crate) const VRAM_BEGIN: usize = 0x8000;
 pub(crate) const VRAM_END: usize = 0x9FFF;
 pub(crate) const VRAM_SIZE: usize = VRAM_END - VRAM_BEGIN + 1;
 
+pub(crate) const LCDC_ADDRESS: usize = 0xFF40;
+pub(crate) const STAT_ADDRESS: usize = 0xFF41;
+pub(crate) const SCY_ADDRESS: usize = 0xFF42;
+pub(crate) const SCX_ADDRESS: usize = 0xFF43;
+pub(crate) const LY_ADDRESS: usize = 0xFF44;
+pub(crate) const LYC_ADDRESS: usize = 0xFF45;
+pub(crate) const BGP_ADDRESS: usize = 0xFF47;
+pub(crate) const OBP0_ADDRESS: usize = 0xFF48;
+pub(crate) const OBP1_ADDRESS: usize = 0xFF49;
+pub(crate) const WY_ADDRESS: usize = 0xFF4A;
+pub(crate) const WX_ADDRESS: usize = 0xFF4B;
+
+const LCDC_LCD_ENABLE: u8 = 1 << 7;
+const LCDC_WINDOW_TILE_MAP: u8 = 1 << 6;
+const LCDC_WINDOW_ENABLE: u8 = 1 << 5;
+const LCDC_TILE_DATA: u8 = 1 << 4;
+const LCDC_BACKGROUND_TILE_MAP: u8 = 1 << 3;
+const LCDC_BACKGROUND_ENABLE: u8 = 1;
+
+const STAT_LYC_INTERRUPT: u8 = 1 << 6;
+const STAT_OAM_INTERRUPT: u8 = 1 << 5;
+const STAT_VBLANK_INTERRUPT: u8 = 1 << 4;
+const STAT_HBLANK_INTERRUPT: u8 = 1 << 3;
+const STAT_COINCIDENCE: u8 = 1 << 2;
+const STAT_WRITABLE_BITS: u8 = 0b0111_1000;
+
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+const HBLANK_DOTS: u16 = 204;
+const LINE_DOTS: u16 = OAM_SCAN_DOTS + DRAWING_DOTS + HBLANK_DOTS;
+const LAST_LINE: u8 = 153;
+
+const TILE_MAP_0: usize = 0x1800;
+const TILE_MAP_1: usize = 0x1C00;
+
+pub(crate) const VBLANK_INTERRUPT: u8 = 1;
+pub(crate) const STAT_INTERRUPT: u8 = 1 << 1;
+
+/// The value of the two lowest bits of STAT.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
 #[derive(Copy,Clone)]
 pub(crate) enum TilePixelValue {
     Zero,
@@ -20,15 +72,237 @@ pub(crate) fn empty_tile() -> Tile {
 pub(crate) struct GPU{
     pub(crate) vram: [u8; VRAM_SIZE],
     pub(crate) tile_set: [Tile; 384],
-    pub canvas_buffer: [u32; WINDOW_DIMENSIONS[0] * WINDOW_DIMENSIONS[1] * 4],
+    /// The last complete frame, this is what is shown on the screen.
+    pub(crate) frame_buffer: FrameBuffer,
+    /// The frame being drawn line by line.
+    back_buffer: FrameBuffer,
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    mode: Mode,
+    dots: u16,
+    window_line: u8,
+    // The LCD needs a whole frame to warm up after being turned on, the
+    // first one is never shown.
+    skip_frame: bool,
+    // Read in place of LY when set, LY keeps counting underneath.
+    pub(crate) ly_stub: Option<u8>,
 }
 
 impl GPU {
-    fn read_vram(&self, address: usize) -> u8 {
-        self.vram[address]
+    pub(crate) fn new() -> GPU {
+        GPU {
+            vram: [0; VRAM_SIZE],
+            tile_set: [empty_tile(); 384],
+            frame_buffer: FrameBuffer::new(ColorMode::Shade),
+            back_buffer: FrameBuffer::new(ColorMode::Shade),
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            mode: Mode::HBlank,
+            dots: 0,
+            window_line: 0,
+            skip_frame: false,
+            ly_stub: None,
+        }
+    }
+
+    pub(crate) fn lcd_enabled(&self) -> bool {
+        self.lcdc & LCDC_LCD_ENABLE != 0
+    }
+
+    pub(crate) fn read_register(&self, address: usize) -> u8 {
+        match address {
+            LCDC_ADDRESS => self.lcdc,
+            // Bit 7 is not wired and always reads as 1.
+            STAT_ADDRESS => 0x80 | self.stat | self.mode as u8,
+            SCY_ADDRESS => self.scy,
+            SCX_ADDRESS => self.scx,
+            LY_ADDRESS => self.ly_stub.unwrap_or(self.ly),
+            LYC_ADDRESS => self.lyc,
+            BGP_ADDRESS => self.bgp,
+            OBP0_ADDRESS => self.obp0,
+            OBP1_ADDRESS => self.obp1,
+            WY_ADDRESS => self.wy,
+            WX_ADDRESS => self.wx,
+            _ => panic!("Not a GPU register {:x}", address),
+        }
+    }
+
+    pub(crate) fn write_register(&mut self, address: usize, value: u8) {
+        match address {
+            LCDC_ADDRESS => {
+                let was_enabled = self.lcd_enabled();
+                self.lcdc = value;
+                match (was_enabled, self.lcd_enabled()) {
+                    (true, false) => self.turn_off(),
+                    (false, true) => self.turn_on(),
+                    _ => {}
+                }
+            }
+            STAT_ADDRESS => self.stat = (self.stat & !STAT_WRITABLE_BITS) | (value & STAT_WRITABLE_BITS),
+            SCY_ADDRESS => self.scy = value,
+            SCX_ADDRESS => self.scx = value,
+            // LY is read only.
+            LY_ADDRESS => {}
+            LYC_ADDRESS => {
+                self.lyc = value;
+                if self.lcd_enabled() {
+                    self.compare_ly();
+                }
+            }
+            BGP_ADDRESS => self.bgp = value,
+            OBP0_ADDRESS => self.obp0 = value,
+            OBP1_ADDRESS => self.obp1 = value,
+            WY_ADDRESS => self.wy = value,
+            WX_ADDRESS => self.wx = value,
+            _ => panic!("Not a GPU register {:x}", address),
+        }
+    }
+
+    fn turn_off(&mut self) {
+        // The PPU stops where it is, LY goes back to 0 and the screen goes
+        // blank until it is turned back on.
+        self.ly = 0;
+        self.dots = 0;
+        self.window_line = 0;
+        self.mode = Mode::HBlank;
+        self.frame_buffer.clear(0);
+        self.back_buffer.clear(0);
+    }
+
+    fn turn_on(&mut self) {
+        self.mode = Mode::OamScan;
+        self.skip_frame = true;
+        self.compare_ly();
+    }
+
+    /// Advances the PPU by `cycles` clock cycles and returns the interrupts
+    /// it requested, as IF bits.
+    pub(crate) fn step(&mut self, cycles: u16) -> u8 {
+        if !self.lcd_enabled() {
+            return 0;
+        }
+        let mut interrupts = 0;
+        self.dots += cycles;
+        loop {
+            match self.mode {
+                Mode::OamScan if self.dots >= OAM_SCAN_DOTS => {
+                    self.dots -= OAM_SCAN_DOTS;
+                    self.mode = Mode::Drawing;
+                }
+                Mode::Drawing if self.dots >= DRAWING_DOTS => {
+                    self.dots -= DRAWING_DOTS;
+                    self.render_line();
+                    self.mode = Mode::HBlank;
+                    interrupts |= self.stat_interrupt(STAT_HBLANK_INTERRUPT);
+                }
+                Mode::HBlank if self.dots >= HBLANK_DOTS => {
+                    self.dots -= HBLANK_DOTS;
+                    self.ly += 1;
+                    if self.ly as usize == SCREEN_HEIGHT {
+                        self.mode = Mode::VBlank;
+                        self.finish_frame();
+                        interrupts |= VBLANK_INTERRUPT | self.stat_interrupt(STAT_VBLANK_INTERRUPT);
+                    } else {
+                        self.mode = Mode::OamScan;
+                        interrupts |= self.stat_interrupt(STAT_OAM_INTERRUPT);
+                    }
+                    interrupts |= self.compare_ly();
+                }
+                Mode::VBlank if self.dots >= LINE_DOTS => {
+                    self.dots -= LINE_DOTS;
+                    if self.ly == LAST_LINE {
+                        self.ly = 0;
+                        self.window_line = 0;
+                        self.mode = Mode::OamScan;
+                        interrupts |= self.stat_interrupt(STAT_OAM_INTERRUPT);
+                    } else {
+                        self.ly += 1;
+                    }
+                    interrupts |= self.compare_ly();
+                }
+                _ => break,
+            }
+        }
+        interrupts
+    }
+
+    fn stat_interrupt(&self, source: u8) -> u8 {
+        if self.stat & source != 0 { STAT_INTERRUPT } else { 0 }
+    }
+
+    fn compare_ly(&mut self) -> u8 {
+        if self.ly == self.lyc {
+            self.stat |= STAT_COINCIDENCE;
+            self.stat_interrupt(STAT_LYC_INTERRUPT)
+        } else {
+            self.stat &= !STAT_COINCIDENCE;
+            0
+        }
+    }
+
+    fn finish_frame(&mut self) {
+        if self.skip_frame {
+            self.skip_frame = false;
+        } else {
+            std::mem::swap(&mut self.frame_buffer, &mut self.back_buffer);
+        }
+    }
+
+    fn render_line(&mut self) {
+        let y = self.ly as usize;
+        let window_visible = self.lcdc & LCDC_WINDOW_ENABLE != 0 && self.ly >= self.wy && self.wx <= 166;
+        for x in 0..SCREEN_WIDTH {
+            let color = if self.lcdc & LCDC_BACKGROUND_ENABLE == 0 {
+                0
+            } else if window_visible && x + 7 >= self.wx as usize {
+                let map = if self.lcdc & LCDC_WINDOW_TILE_MAP != 0 { TILE_MAP_1 } else { TILE_MAP_0 };
+                self.tile_map_pixel(map, x + 7 - self.wx as usize, self.window_line as usize)
+            } else {
+                let map = if self.lcdc & LCDC_BACKGROUND_TILE_MAP != 0 { TILE_MAP_1 } else { TILE_MAP_0 };
+                self.tile_map_pixel(map, (x + self.scx as usize) % 256, (y + self.scy as usize) % 256)
+            };
+            let shade = (self.bgp >> (color * 2)) & 0b11;
+            self.back_buffer.set(x, y, shade as u16);
+        }
+        if window_visible {
+            self.window_line += 1;
+        }
+    }
+
+    fn tile_map_pixel(&self, map: usize, x: usize, y: usize) -> u8 {
+        let tile_number = self.vram[map + (y / 8) * 32 + x / 8];
+        let tile_index = if self.lcdc & LCDC_TILE_DATA != 0 {
+            tile_number as usize
+        } else {
+            // Tiles are numbered from -128 to 127 around 0x9000.
+            (256 + tile_number as i8 as isize) as usize
+        };
+        self.tile_set[tile_index][y % 8][x % 8] as u8
     }
 
-    fn write_vram(&mut self, index: usize, value: u8) {
+    pub(crate) fn read_vram(&self, index: usize) -> u8 {
+        self.vram[index]
+    }
+
+    pub(crate) fn write_vram(&mut self, index: usize, value: u8) {
         self.vram[index] = value;
         // If our index is greater than 0x1800, we're not writing to the tile set storage
         // so we can just return.
@@ -89,4 +363,160 @@ impl GPU {
         }
 
     }
-}
\ No newline at end of file
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.vram);
+        for register in [self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0, self.obp1, self.wy, self.wx] {
+            state.u8(register);
+        }
+        state.u8(self.mode as u8);
+        state.u16(self.dots);
+        state.u8(self.window_line);
+        state.bool(self.skip_frame);
+        self.frame_buffer.save_state(state);
+        self.back_buffer.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        let mut vram = [0; VRAM_SIZE];
+        state.bytes(&mut vram)?;
+        // Going through write_vram decodes the tile set again.
+        for (index, &byte) in vram.iter().enumerate() {
+            self.write_vram(index, byte);
+        }
+        for register in [&mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx, &mut self.ly, &mut self.lyc, &mut self.bgp, &mut self.obp0, &mut self.obp1, &mut self.wy, &mut self.wx] {
+            *register = state.u8()?;
+        }
+        self.mode = match state.u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            _ => Mode::Drawing,
+        };
+        self.dots = state.u16()?;
+        self.window_line = state.u8()?;
+        self.skip_frame = state.bool()?;
+        self.frame_buffer.load_state(state)?;
+        self.back_buffer.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::palette::RawIndices;
+
+    const FRAME_DOTS: u32 = LINE_DOTS as u32 * (LAST_LINE as u32 + 1);
+    const LCD_ON: u8 = LCDC_LCD_ENABLE | LCDC_TILE_DATA | LCDC_BACKGROUND_ENABLE;
+    const LCD_OFF: u8 = LCDC_TILE_DATA | LCDC_BACKGROUND_ENABLE;
+
+    fn run(gpu: &mut GPU, dots: u32) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..dots / 4 {
+            interrupts |= gpu.step(4);
+        }
+        interrupts
+    }
+
+    fn stat_mode(gpu: &GPU) -> u8 {
+        gpu.read_register(STAT_ADDRESS) & 0b11
+    }
+
+    // Fills tile 0, used everywhere by the empty tile map, with colour 3.
+    fn black_screen() -> GPU {
+        let mut gpu = GPU::new();
+        for index in 0..16 {
+            gpu.write_vram(index, 0xFF);
+        }
+        gpu.write_register(BGP_ADDRESS, 0b1110_0100);
+        gpu
+    }
+
+    fn presented(gpu: &GPU) -> Vec<u16> {
+        let mut pixels = vec![0; NUMBER_OF_PIXELS];
+        RawIndices.render(&gpu.frame_buffer, &mut pixels);
+        pixels
+    }
+
+    #[test]
+    fn modes_follow_the_line_timing() {
+        let mut gpu = GPU::new();
+        gpu.write_register(LCDC_ADDRESS, LCD_ON);
+        assert_eq!(stat_mode(&gpu), Mode::OamScan as u8);
+        run(&mut gpu, OAM_SCAN_DOTS as u32);
+        assert_eq!(stat_mode(&gpu), Mode::Drawing as u8);
+        run(&mut gpu, DRAWING_DOTS as u32);
+        assert_eq!(stat_mode(&gpu), Mode::HBlank as u8);
+        run(&mut gpu, HBLANK_DOTS as u32);
+        assert_eq!(gpu.read_register(LY_ADDRESS), 1);
+        assert_eq!(stat_mode(&gpu), Mode::OamScan as u8);
+        run(&mut gpu, LINE_DOTS as u32 * 143);
+        assert_eq!(gpu.read_register(LY_ADDRESS), 144);
+        assert_eq!(stat_mode(&gpu), Mode::VBlank as u8);
+    }
+
+    #[test]
+    fn turning_the_lcd_off_resets_ly_and_stops_the_ppu() {
+        let mut gpu = GPU::new();
+        gpu.write_register(LCDC_ADDRESS, LCD_ON);
+        run(&mut gpu, LINE_DOTS as u32 * 10 + 100);
+        assert_eq!(gpu.read_register(LY_ADDRESS), 10);
+
+        gpu.write_register(LCDC_ADDRESS, LCD_OFF);
+        assert_eq!(gpu.read_register(LY_ADDRESS), 0);
+        assert_eq!(stat_mode(&gpu), Mode::HBlank as u8);
+
+        assert_eq!(run(&mut gpu, FRAME_DOTS), 0);
+        assert_eq!(gpu.read_register(LY_ADDRESS), 0);
+        assert_eq!(stat_mode(&gpu), Mode::HBlank as u8);
+    }
+
+    #[test]
+    fn writes_to_ly_are_ignored() {
+        let mut gpu = GPU::new();
+        gpu.write_register(LCDC_ADDRESS, LCD_ON);
+        run(&mut gpu, LINE_DOTS as u32 * 3);
+        gpu.write_register(LY_ADDRESS, 42);
+        assert_eq!(gpu.read_register(LY_ADDRESS), 3);
+    }
+
+    #[test]
+    fn coincidence_flag_is_checked_when_the_lcd_turns_on() {
+        let mut gpu = GPU::new();
+        gpu.write_register(LYC_ADDRESS, 0);
+        assert_eq!(gpu.read_register(STAT_ADDRESS) & STAT_COINCIDENCE, 0);
+        gpu.write_register(LCDC_ADDRESS, LCD_ON);
+        assert_ne!(gpu.read_register(STAT_ADDRESS) & STAT_COINCIDENCE, 0);
+        run(&mut gpu, LINE_DOTS as u32);
+        assert_eq!(gpu.read_register(STAT_ADDRESS) & STAT_COINCIDENCE, 0);
+    }
+
+    #[test]
+    fn first_frame_after_enabling_the_lcd_is_not_shown() {
+        let mut gpu = black_screen();
+        gpu.write_register(LCDC_ADDRESS, LCD_ON);
+        let interrupts = run(&mut gpu, FRAME_DOTS);
+        assert_ne!(interrupts & VBLANK_INTERRUPT, 0);
+        assert!(presented(&gpu).iter().all(|&shade| shade == 0));
+
+        run(&mut gpu, FRAME_DOTS);
+        assert!(presented(&gpu).iter().all(|&shade| shade == 3));
+    }
+
+    #[test]
+    fn screen_is_blank_while_the_lcd_is_off() {
+        let mut gpu = black_screen();
+        gpu.write_register(LCDC_ADDRESS, LCD_ON);
+        run(&mut gpu, FRAME_DOTS * 2);
+        assert!(presented(&gpu).iter().all(|&shade| shade == 3));
+
+        gpu.write_register(LCDC_ADDRESS, LCD_OFF);
+        assert!(presented(&gpu).iter().all(|&shade| shade == 0));
+
+        // Loading screens turn the LCD back on once VRAM is ready, what was
+        // drawn before must not come back for the warm up frame.
+        gpu.write_register(LCDC_ADDRESS, LCD_ON);
+        run(&mut gpu, FRAME_DOTS);
+        assert!(presented(&gpu).iter().all(|&shade| shade == 0));
+    }
+}
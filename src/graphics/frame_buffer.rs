@@ -0,0 +1,101 @@
+use crate::state::{StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+pub const NUMBER_OF_PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+
+/// Tells how the values stored in a `FrameBuffer` have to be read.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ColorMode {
+    /// 2-bit DMG shades, from 0 (lightest) to 3 (darkest).
+    Shade,
+    /// 15-bit CGB colours laid out as 0bxBBBBBGGGGGRRRRR.
+    Cgb,
+}
+
+/// The picture produced by the GPU, one value per pixel.
+///
+/// It only knows about shades or CGB colours: turning them into something
+/// a screen or an image file understands is the job of a `PaletteStage`.
+pub struct FrameBuffer {
+    mode: ColorMode,
+    pixels: [u16; NUMBER_OF_PIXELS],
+}
+
+impl FrameBuffer {
+    pub(crate) fn new(mode: ColorMode) -> FrameBuffer {
+        FrameBuffer {
+            mode,
+            pixels: [0; NUMBER_OF_PIXELS],
+        }
+    }
+
+    pub fn mode(&self) -> ColorMode {
+        self.mode
+    }
+
+    pub fn pixels(&self) -> &[u16] {
+        &self.pixels
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u16 {
+        self.pixels[y * SCREEN_WIDTH + x]
+    }
+
+    pub(crate) fn set(&mut self, x: usize, y: usize, value: u16) {
+        self.pixels[y * SCREEN_WIDTH + x] = match self.mode {
+            ColorMode::Shade => value & 0b11,
+            ColorMode::Cgb => value & 0x7FFF,
+        };
+    }
+
+    pub(crate) fn clear(&mut self, value: u16) {
+        self.pixels = [value; NUMBER_OF_PIXELS];
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.mode == ColorMode::Cgb);
+        self.pixels.iter().for_each(|&pixel| state.u16(pixel));
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.mode = if state.bool()? { ColorMode::Cgb } else { ColorMode::Shade };
+        for pixel in self.pixels.iter_mut() {
+            *pixel = state.u16()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_masked_to_the_colour_mode() {
+        let mut shades = FrameBuffer::new(ColorMode::Shade);
+        shades.set(159, 143, 0b110);
+        assert_eq!(shades.get(159, 143), 0b10);
+        assert_eq!(shades.pixels()[NUMBER_OF_PIXELS - 1], 0b10);
+
+        let mut colours = FrameBuffer::new(ColorMode::Cgb);
+        colours.set(1, 1, 0xFFFF);
+        assert_eq!(colours.get(1, 1), 0x7FFF);
+        assert_eq!(colours.pixels()[SCREEN_WIDTH + 1], 0x7FFF);
+    }
+
+    #[test]
+    fn states_keep_the_mode_and_pixels() {
+        let mut frame = FrameBuffer::new(ColorMode::Cgb);
+        frame.clear(0x0421);
+        frame.set(3, 2, 0x7C00);
+        let mut state = StateWriter::new();
+        frame.save_state(&mut state);
+        let bytes = state.finish();
+
+        let mut loaded = FrameBuffer::new(ColorMode::Shade);
+        loaded.load_state(&mut StateReader::new(&bytes).unwrap()).unwrap();
+        assert_eq!(loaded.mode(), ColorMode::Cgb);
+        assert_eq!(loaded.pixels(), frame.pixels());
+    }
+}
@@ -0,0 +1,224 @@
+use crate::graphics::frame_buffer::{ColorMode, FrameBuffer};
+
+/// A 24-bit colour stored as 0xRRGGBB.
+pub type Rgb = u32;
+
+/// The four colours used to display DMG shades, lightest first.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Palette {
+    pub shades: [Rgb; 4],
+}
+
+impl Palette {
+    /// The pea soup screen of the original DMG.
+    pub const CLASSIC_GREEN: Palette = Palette {
+        shades: [0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F],
+    };
+    /// The plain grey screen of the Game Boy Pocket.
+    pub const GREYSCALE: Palette = Palette {
+        shades: [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000],
+    };
+    /// The backlit screen of the Game Boy Light.
+    pub const LIGHT: Palette = Palette {
+        shades: [0x00B581, 0x009A71, 0x00694A, 0x004F3B],
+    };
+    pub const HIGH_CONTRAST: Palette = Palette {
+        shades: [0xFFFFFF, 0xFFD800, 0x0040FF, 0x000000],
+    };
+
+    pub const PRESETS: [(&'static str, Palette); 4] = [
+        ("classic-green", Palette::CLASSIC_GREEN),
+        ("pocket", Palette::GREYSCALE),
+        ("light", Palette::LIGHT),
+        ("high-contrast", Palette::HIGH_CONTRAST),
+    ];
+
+    /// Reads either the name of a preset or four comma separated hex
+    /// colours, lightest first, like `#e0f8d0,#88c070,#346856,#081820`.
+    pub fn parse(spec: &str) -> Result<Palette, String> {
+        let spec = spec.trim();
+        if let Some((_, palette)) = Palette::PRESETS.iter().find(|(name, _)| name.eq_ignore_ascii_case(spec)) {
+            return Ok(*palette);
+        }
+        let colors = spec.split(',').map(parse_hex_color).collect::<Result<Vec<Rgb>, String>>()?;
+        if colors.len() != 4 {
+            let names = Palette::PRESETS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ");
+            return Err(format!(
+                "Palette `{}` should be one of {} or four hex colours, got {} colours",
+                spec,
+                names,
+                colors.len()
+            ));
+        }
+        let mut shades = [0; 4];
+        shades.copy_from_slice(&colors);
+        Ok(Palette { shades })
+    }
+
+    pub fn rgb(&self, mode: ColorMode, value: u16) -> Rgb {
+        match mode {
+            ColorMode::Shade => self.shades[(value & 0b11) as usize],
+            ColorMode::Cgb => cgb_to_rgb(value),
+        }
+    }
+}
+
+fn parse_hex_color(color: &str) -> Result<Rgb, String> {
+    let digits = color.trim().trim_start_matches('#');
+    if digits.len() != 6 {
+        return Err(format!("Colour `{}` should be written as #RRGGBB", color.trim()));
+    }
+    Rgb::from_str_radix(digits, 16).map_err(|_| format!("Colour `{}` is not a valid hex value", color.trim()))
+}
+
+/// The palettes that can be switched between at runtime, the one picked
+/// by the user comes first and is followed by the presets.
+pub struct PaletteCycle {
+    palettes: Vec<Palette>,
+    current: usize,
+}
+
+impl PaletteCycle {
+    pub fn new(selected: Palette) -> PaletteCycle {
+        let mut palettes = vec![selected];
+        palettes.extend(Palette::PRESETS.iter().map(|(_, palette)| *palette).filter(|palette| *palette != selected));
+        PaletteCycle { palettes, current: 0 }
+    }
+
+    pub fn current(&self) -> Palette {
+        self.palettes[self.current]
+    }
+
+    // Not an iterator, it goes round forever.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Palette {
+        self.current = (self.current + 1) % self.palettes.len();
+        self.current()
+    }
+}
+
+fn cgb_to_rgb(value: u16) -> Rgb {
+    // Each channel is 5 bits wide, copying the top bits into the bottom
+    // ones spreads 0..=31 evenly over 0..=255.
+    let expand = |channel: u16| -> u32 {
+        let channel = (channel & 0x1F) as u32;
+        channel << 3 | channel >> 2
+    };
+    let red = expand(value);
+    let green = expand(value >> 5);
+    let blue = expand(value >> 10);
+    red << 16 | green << 8 | blue
+}
+
+/// Converts the content of a `FrameBuffer` to whatever the consumer needs.
+pub trait PaletteStage {
+    type Pixel: Copy;
+
+    fn map(&self, mode: ColorMode, value: u16) -> Self::Pixel;
+
+    fn render(&self, frame: &FrameBuffer, output: &mut [Self::Pixel]) {
+        let mode = frame.mode();
+        for (pixel, &value) in output.iter_mut().zip(frame.pixels()) {
+            *pixel = self.map(mode, value);
+        }
+    }
+}
+
+/// 0xAARRGGBB pixels, as expected by minifb.
+pub struct Argb8888(pub Palette);
+
+impl PaletteStage for Argb8888 {
+    type Pixel = u32;
+
+    fn map(&self, mode: ColorMode, value: u16) -> u32 {
+        0xFF000000 | self.0.rgb(mode, value)
+    }
+}
+
+/// [R, G, B, A] bytes, as expected by image encoders.
+pub struct Rgba8888(pub Palette);
+
+impl PaletteStage for Rgba8888 {
+    type Pixel = [u8; 4];
+
+    fn map(&self, mode: ColorMode, value: u16) -> [u8; 4] {
+        let rgb = self.0.rgb(mode, value);
+        [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 0xFF]
+    }
+}
+
+/// The raw shades or CGB colours, handy to compare frames in tests.
+pub struct RawIndices;
+
+impl PaletteStage for RawIndices {
+    type Pixel = u16;
+
+    fn map(&self, _mode: ColorMode, value: u16) -> u16 {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(mode: ColorMode, values: [u16; 2]) -> FrameBuffer {
+        let mut frame = FrameBuffer::new(mode);
+        frame.set(0, 0, values[0]);
+        frame.set(1, 0, values[1]);
+        frame
+    }
+
+    #[test]
+    fn presets_are_found_by_name() {
+        assert_eq!(Palette::parse("pocket"), Ok(Palette::GREYSCALE));
+        assert_eq!(Palette::parse(" Classic-Green "), Ok(Palette::CLASSIC_GREEN));
+    }
+
+    #[test]
+    fn four_hex_colours_make_a_palette() {
+        let palette = Palette::parse("#e0f8d0, #88c070,346856,#081820").unwrap();
+        assert_eq!(palette.shades, [0xE0F8D0, 0x88C070, 0x346856, 0x081820]);
+    }
+
+    #[test]
+    fn bad_palettes_are_refused() {
+        assert!(Palette::parse("sepia").unwrap_err().contains("`sepia` should be written as #RRGGBB"));
+        assert!(Palette::parse("#ffffff,#000000").unwrap_err().contains("got 2 colours"));
+        assert!(Palette::parse("#fff,#aaa,#555,#000").unwrap_err().contains("#RRGGBB"));
+        assert!(Palette::parse("#ffffff,#aaaaaa,#55555g,#000000").unwrap_err().contains("not a valid hex value"));
+    }
+
+    #[test]
+    fn argb_pixels_are_opaque_palette_colours() {
+        let frame = frame(ColorMode::Shade, [1, 3]);
+        let mut output = [0; 3];
+        Argb8888(Palette::CLASSIC_GREEN).render(&frame, &mut output);
+        assert_eq!(output, [0xFF8BAC0F, 0xFF0F380F, 0xFF9BBC0F]);
+    }
+
+    #[test]
+    fn rgba_pixels_are_bytes_in_order() {
+        let frame = frame(ColorMode::Shade, [1, 2]);
+        let mut output = [[0; 4]; 2];
+        Rgba8888(Palette::HIGH_CONTRAST).render(&frame, &mut output);
+        assert_eq!(output, [[0xFF, 0xD8, 0x00, 0xFF], [0x00, 0x40, 0xFF, 0xFF]]);
+    }
+
+    #[test]
+    fn cgb_colours_ignore_the_palette() {
+        // Full red, then full blue with half green.
+        let frame = frame(ColorMode::Cgb, [0x001F, 0x7C00 | 0x10 << 5]);
+        let mut output = [[0; 4]; 2];
+        Rgba8888(Palette::GREYSCALE).render(&frame, &mut output);
+        assert_eq!(output, [[0xFF, 0x00, 0x00, 0xFF], [0x00, 0x84, 0xFF, 0xFF]]);
+    }
+
+    #[test]
+    fn raw_indices_are_left_untouched() {
+        let frame = frame(ColorMode::Cgb, [0x1234, 0x7FFF]);
+        let mut output = [0; 2];
+        RawIndices.render(&frame, &mut output);
+        assert_eq!(output, [0x1234, 0x7FFF]);
+    }
+}
@@ -0,0 +1,185 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use crate::link::Side;
+use crate::state::{StateReader, StateWriter};
+
+pub(crate) const RP_ADDRESS: usize = 0xFF56;
+
+const RP_LED: u8 = 1;
+const RP_NO_LIGHT: u8 = 1 << 1;
+const RP_READ_ENABLE: u8 = 0b1100_0000;
+const RP_UNUSED: u8 = 0b0011_1100;
+
+/// Whatever faces the infrared port of the Game Boy.
+pub trait InfraredEndpoint {
+    fn set_led(&mut self, on: bool);
+
+    fn receives_light(&self) -> bool;
+}
+
+/// Nothing in front of the port, no light ever comes in.
+pub struct Darkness;
+
+impl InfraredEndpoint for Darkness {
+    fn set_led(&mut self, _on: bool) {}
+
+    fn receives_light(&self) -> bool {
+        false
+    }
+}
+
+/// A mirror in front of the port: the Game Boy sees its own LED.
+#[derive(Default)]
+pub struct Loopback {
+    led: bool,
+}
+
+impl Loopback {
+    pub fn new() -> Loopback {
+        Loopback { led: false }
+    }
+}
+
+impl InfraredEndpoint for Loopback {
+    fn set_led(&mut self, on: bool) {
+        self.led = on;
+    }
+
+    fn receives_light(&self) -> bool {
+        self.led
+    }
+}
+
+/// One of two Game Boys of the same process facing each other.
+pub struct InfraredEnd {
+    leds: Rc<[Cell<bool>; 2]>,
+    side: Side,
+}
+
+impl InfraredEnd {
+    pub fn pair() -> (InfraredEnd, InfraredEnd) {
+        let leds = Rc::new([Cell::new(false), Cell::new(false)]);
+        (
+            InfraredEnd { leds: leds.clone(), side: Side::Left },
+            InfraredEnd { leds, side: Side::Right },
+        )
+    }
+}
+
+impl InfraredEndpoint for InfraredEnd {
+    fn set_led(&mut self, on: bool) {
+        self.leds[self.side.index()].set(on);
+    }
+
+    fn receives_light(&self) -> bool {
+        self.leds[self.side.other().index()].get()
+    }
+}
+
+/// The CGB RP register.
+pub(crate) struct Infrared {
+    rp: u8,
+    endpoint: Box<dyn InfraredEndpoint>,
+}
+
+impl Infrared {
+    pub(crate) fn new() -> Infrared {
+        Infrared {
+            rp: 0,
+            endpoint: Box::new(Darkness),
+        }
+    }
+
+    pub(crate) fn connect(&mut self, mut endpoint: Box<dyn InfraredEndpoint>) {
+        endpoint.set_led(self.rp & RP_LED != 0);
+        self.endpoint = endpoint;
+    }
+
+    pub(crate) fn read(&self) -> u8 {
+        // Bit 1 reads 0 when light is received, but only when reading is
+        // enabled.
+        let receiving = self.rp & RP_READ_ENABLE == RP_READ_ENABLE && self.endpoint.receives_light();
+        let light = if receiving { 0 } else { RP_NO_LIGHT };
+        RP_UNUSED | self.rp | light
+    }
+
+    pub(crate) fn write(&mut self, value: u8) {
+        self.rp = value & (RP_READ_ENABLE | RP_LED);
+        self.endpoint.set_led(value & RP_LED != 0);
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.rp);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.rp = state.u8()?;
+        // The endpoint is not saved, it has to see the restored LED.
+        self.endpoint.set_led(self.rp & RP_LED != 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const READ_ENABLE_LED_OFF: u8 = RP_READ_ENABLE;
+
+    #[test]
+    fn nothing_is_received_in_the_dark() {
+        let mut infrared = Infrared::new();
+        infrared.write(READ_ENABLE_LED_OFF | RP_LED);
+        assert_eq!(infrared.read(), 0xFF);
+    }
+
+    #[test]
+    fn loopback_sees_its_own_led() {
+        let mut infrared = Infrared::new();
+        infrared.connect(Box::new(Loopback::new()));
+        infrared.write(READ_ENABLE_LED_OFF | RP_LED);
+        assert_eq!(infrared.read() & RP_NO_LIGHT, 0);
+        infrared.write(READ_ENABLE_LED_OFF);
+        assert_eq!(infrared.read() & RP_NO_LIGHT, RP_NO_LIGHT);
+    }
+
+    #[test]
+    fn light_is_only_read_when_enabled() {
+        let mut infrared = Infrared::new();
+        infrared.connect(Box::new(Loopback::new()));
+        infrared.write(RP_LED);
+        assert_eq!(infrared.read(), RP_UNUSED | RP_NO_LIGHT | RP_LED);
+    }
+
+    #[test]
+    fn paired_ports_see_each_other() {
+        let (left_end, right_end) = InfraredEnd::pair();
+        let mut left = Infrared::new();
+        let mut right = Infrared::new();
+        left.connect(Box::new(left_end));
+        right.connect(Box::new(right_end));
+        left.write(READ_ENABLE_LED_OFF);
+        right.write(READ_ENABLE_LED_OFF | RP_LED);
+        assert_eq!(left.read() & RP_NO_LIGHT, 0);
+        assert_eq!(right.read() & RP_NO_LIGHT, RP_NO_LIGHT);
+    }
+
+    #[test]
+    fn loaded_states_turn_the_led_back_on() {
+        let (left_end, right_end) = InfraredEnd::pair();
+        let mut left = Infrared::new();
+        let mut right = Infrared::new();
+        left.connect(Box::new(left_end));
+        right.connect(Box::new(right_end));
+        left.write(READ_ENABLE_LED_OFF);
+        right.write(RP_LED);
+        let mut state = StateWriter::new();
+        right.save_state(&mut state);
+        let bytes = state.finish();
+
+        right.write(0);
+        assert_eq!(left.read() & RP_NO_LIGHT, RP_NO_LIGHT);
+        right.load_state(&mut StateReader::new(&bytes).unwrap()).unwrap();
+        assert_eq!(left.read() & RP_NO_LIGHT, 0);
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use minifb::Key;
+use emulator::joypad::Button;
+
+/// Emulator controls that are not part of the Game Boy.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum Hotkey {
+    Pause,
+    Reset,
+    FastForward,
+    Screenshot,
+    SaveState,
+    LoadState,
+    Palette,
+    RecordAudio,
+    /// Mutes or unmutes a sound channel, from 0 for square 1 to 3 for noise.
+    Mute(usize),
+    /// Plays a sound channel alone, or all of them again.
+    Solo(usize),
+    Visualiser,
+    Quit,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum Action {
+    Button(Button),
+    Hotkey(Hotkey),
+}
+
+const ACTIONS: [(&str, Action); 26] = [
+    ("right", Action::Button(Button::Right)),
+    ("left", Action::Button(Button::Left)),
+    ("up", Action::Button(Button::Up)),
+    ("down", Action::Button(Button::Down)),
+    ("a", Action::Button(Button::A)),
+    ("b", Action::Button(Button::B)),
+    ("select", Action::Button(Button::Select)),
+    ("start", Action::Button(Button::Start)),
+    ("pause", Action::Hotkey(Hotkey::Pause)),
+    ("reset", Action::Hotkey(Hotkey::Reset)),
+    ("fast-forward", Action::Hotkey(Hotkey::FastForward)),
+    ("screenshot", Action::Hotkey(Hotkey::Screenshot)),
+    ("save-state", Action::Hotkey(Hotkey::SaveState)),
+    ("load-state", Action::Hotkey(Hotkey::LoadState)),
+    ("palette", Action::Hotkey(Hotkey::Palette)),
+    ("record-audio", Action::Hotkey(Hotkey::RecordAudio)),
+    ("mute-square1", Action::Hotkey(Hotkey::Mute(0))),
+    ("mute-square2", Action::Hotkey(Hotkey::Mute(1))),
+    ("mute-wave", Action::Hotkey(Hotkey::Mute(2))),
+    ("mute-noise", Action::Hotkey(Hotkey::Mute(3))),
+    ("solo-square1", Action::Hotkey(Hotkey::Solo(0))),
+    ("solo-square2", Action::Hotkey(Hotkey::Solo(1))),
+    ("solo-wave", Action::Hotkey(Hotkey::Solo(2))),
+    ("solo-noise", Action::Hotkey(Hotkey::Solo(3))),
+    ("visualiser", Action::Hotkey(Hotkey::Visualiser)),
+    ("quit", Action::Hotkey(Hotkey::Quit)),
+];
+
+const DEFAULT_BINDINGS: [(Action, Key); 26] = [
+    (Action::Button(Button::Right), Key::Right),
+    (Action::Button(Button::Left), Key::Left),
+    (Action::Button(Button::Up), Key::Up),
+    (Action::Button(Button::Down), Key::Down),
+    (Action::Button(Button::A), Key::X),
+    (Action::Button(Button::B), Key::Z),
+    (Action::Button(Button::Select), Key::Backspace),
+    (Action::Button(Button::Start), Key::Enter),
+    (Action::Hotkey(Hotkey::Pause), Key::Space),
+    (Action::Hotkey(Hotkey::Reset), Key::R),
+    (Action::Hotkey(Hotkey::FastForward), Key::Tab),
+    (Action::Hotkey(Hotkey::Screenshot), Key::F12),
+    (Action::Hotkey(Hotkey::SaveState), Key::F5),
+    (Action::Hotkey(Hotkey::LoadState), Key::F8),
+    (Action::Hotkey(Hotkey::Palette), Key::P),
+    (Action::Hotkey(Hotkey::RecordAudio), Key::F9),
+    (Action::Hotkey(Hotkey::Mute(0)), Key::Key1),
+    (Action::Hotkey(Hotkey::Mute(1)), Key::Key2),
+    (Action::Hotkey(Hotkey::Mute(2)), Key::Key3),
+    (Action::Hotkey(Hotkey::Mute(3)), Key::Key4),
+    (Action::Hotkey(Hotkey::Solo(0)), Key::Key5),
+    (Action::Hotkey(Hotkey::Solo(1)), Key::Key6),
+    (Action::Hotkey(Hotkey::Solo(2)), Key::Key7),
+    (Action::Hotkey(Hotkey::Solo(3)), Key::Key8),
+    (Action::Hotkey(Hotkey::Visualiser), Key::V),
+    (Action::Hotkey(Hotkey::Quit), Key::Escape),
+];
+
+/// Which keyboard key triggers each action.
+pub(crate) struct KeyBindings {
+    bindings: HashMap<Action, Key>,
+}
+
+impl KeyBindings {
+    /// Starts from the default bindings, applies the ones from the config
+    /// file and then the ones given on the command line as `action=key`.
+    pub(crate) fn new(config: &HashMap<String, String>, overrides: &[&str]) -> Result<KeyBindings, String> {
+        let mut bindings: HashMap<Action, Key> = DEFAULT_BINDINGS.iter().copied().collect();
+        for (action, key) in config {
+            bindings.insert(parse_action(action)?, parse_key(action, key)?);
+        }
+        for binding in overrides {
+            let (action, key) = binding
+                .split_once('=')
+                .ok_or_else(|| format!("Key binding `{}` should be written as action=key", binding))?;
+            bindings.insert(parse_action(action.trim())?, parse_key(action.trim(), key.trim())?);
+        }
+        // Go through actions in a fixed order so the error is always the same.
+        let mut actions_by_key: HashMap<Key, &str> = HashMap::new();
+        for (name, action) in ACTIONS {
+            let key = bindings[&action];
+            if let Some(other) = actions_by_key.insert(key, name) {
+                return Err(format!("Key {:?} is bound to both `{}` and `{}`", key, other, name));
+            }
+        }
+        Ok(KeyBindings { bindings })
+    }
+
+    pub(crate) fn buttons(&self) -> impl Iterator<Item = (Button, Key)> + '_ {
+        self.bindings.iter().filter_map(|(action, key)| match action {
+            Action::Button(button) => Some((*button, *key)),
+            Action::Hotkey(_) => None,
+        })
+    }
+
+    pub(crate) fn key(&self, hotkey: Hotkey) -> Key {
+        self.bindings[&Action::Hotkey(hotkey)]
+    }
+}
+
+fn parse_action(name: &str) -> Result<Action, String> {
+    if let Some(button) = Button::from_name(name) {
+        return Ok(Action::Button(button));
+    }
+    ACTIONS
+        .iter()
+        .find(|(action, _)| action.eq_ignore_ascii_case(name))
+        .map(|(_, action)| *action)
+        .ok_or_else(|| {
+            let names = ACTIONS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ");
+            format!("Unknown action `{}`, expected one of {}", name, names)
+        })
+}
+
+fn parse_key(action: &str, name: &str) -> Result<Key, String> {
+    // Digits are spelled Key0 to Key9 by minifb, accept them bare as well.
+    let name = if name.len() == 1 && name.chars().all(|c| c.is_ascii_digit()) {
+        format!("Key{}", name)
+    } else {
+        name.to_string()
+    };
+    KEYS.iter()
+        .find(|key| format!("{:?}", key).eq_ignore_ascii_case(&name))
+        .copied()
+        .ok_or_else(|| format!("Unknown key `{}` bound to `{}`, expected a name like A, 1, F1, Space, Enter, Up or NumPad0", name, action))
+}
+
+const KEYS: [Key; 106] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+    Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I,
+    Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R,
+    Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8,
+    Key::F9, Key::F10, Key::F11, Key::F12, Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal,
+    Key::LeftBracket, Key::Minus, Key::Period, Key::RightBracket,
+    Key::Semicolon, Key::Slash,
+    Key::Backspace, Key::Delete, Key::End, Key::Enter, Key::Escape, Key::Home,
+    Key::Insert, Key::Menu, Key::PageDown, Key::PageUp, Key::Pause, Key::Space,
+    Key::Tab, Key::NumLock, Key::CapsLock, Key::ScrollLock,
+    Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4,
+    Key::NumPad5, Key::NumPad6, Key::NumPad7, Key::NumPad8, Key::NumPad9,
+    Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk, Key::NumPadMinus,
+    Key::NumPadPlus, Key::NumPadEnter,
+    Key::LeftAlt, Key::RightAlt, Key::LeftSuper, Key::RightSuper,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bindings(config: &[(&str, &str)], overrides: &[&str]) -> Result<KeyBindings, String> {
+        let config = config.iter().map(|&(action, key)| (action.to_string(), key.to_string())).collect();
+        KeyBindings::new(&config, overrides)
+    }
+
+    fn button_key(bindings: &KeyBindings, button: Button) -> Key {
+        bindings.buttons().find(|&(bound, _)| bound == button).unwrap().1
+    }
+
+    #[test]
+    fn keys_are_found_by_name() {
+        assert_eq!(parse_key("a", "K"), Ok(Key::K));
+        assert_eq!(parse_key("a", "numpad0"), Ok(Key::NumPad0));
+        assert_eq!(parse_key("a", "LeftShift"), Ok(Key::LeftShift));
+        assert_eq!(parse_key("a", "F12"), Ok(Key::F12));
+    }
+
+    #[test]
+    fn bare_digits_are_digit_keys() {
+        assert_eq!(parse_key("mute-wave", "3"), Ok(Key::Key3));
+        assert_eq!(parse_key("mute-wave", "Key3"), Ok(Key::Key3));
+    }
+
+    #[test]
+    fn unknown_keys_name_the_action() {
+        let error = parse_key("start", "Hyper").unwrap_err();
+        assert!(error.starts_with("Unknown key `Hyper` bound to `start`"));
+    }
+
+    #[test]
+    fn defaults_are_used_without_config() {
+        let bindings = bindings(&[], &[]).unwrap();
+        assert_eq!(button_key(&bindings, Button::A), Key::X);
+        assert_eq!(bindings.key(Hotkey::Mute(2)), Key::Key3);
+    }
+
+    #[test]
+    fn the_command_line_wins_over_the_config() {
+        let bindings = bindings(&[("a", "K"), ("b", "J")], &["A=L", "fast-forward = LeftShift"]).unwrap();
+        assert_eq!(button_key(&bindings, Button::A), Key::L);
+        assert_eq!(button_key(&bindings, Button::B), Key::J);
+        assert_eq!(bindings.key(Hotkey::FastForward), Key::LeftShift);
+        assert_eq!(bindings.key(Hotkey::Pause), Key::Space);
+    }
+
+    #[test]
+    fn keys_bound_twice_are_refused() {
+        assert_eq!(bindings(&[("a", "Space")], &[]).err().unwrap(), "Key Space is bound to both `a` and `pause`");
+        assert!(bindings(&[], &["start=Z"]).is_err());
+        // Moving the other action away makes it fine again.
+        assert!(bindings(&[("a", "Space")], &["pause=P", "palette=O"]).is_ok());
+    }
+
+    #[test]
+    fn malformed_bindings_are_refused() {
+        assert!(bindings(&[], &["a"]).err().unwrap().contains("action=key"));
+        assert!(bindings(&[("jump", "Space")], &[]).err().unwrap().starts_with("Unknown action `jump`"));
+    }
+}
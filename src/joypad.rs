@@ -0,0 +1,191 @@
+use crate::state::{StateReader, StateWriter};
+
+pub(crate) const JOYPAD_ADDRESS: usize = 0xFF00;
+
+pub(crate) const JOYPAD_INTERRUPT: u8 = 1 << 4;
+
+const SELECT_DIRECTIONS: u8 = 1 << 4;
+const SELECT_ACTIONS: u8 = 1 << 5;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    /// Reads a button written the way key bindings name it, like `start`.
+    pub fn from_name(name: &str) -> Option<Button> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "right" => Button::Right,
+            "left" => Button::Left,
+            "up" => Button::Up,
+            "down" => Button::Down,
+            "a" => Button::A,
+            "b" => Button::B,
+            "select" => Button::Select,
+            "start" => Button::Start,
+            _ => return None,
+        })
+    }
+
+    // Directions and actions share the same four lines of P1, which group
+    // is read depends on the select bits.
+    fn line(&self) -> u8 {
+        match self {
+            Button::Right | Button::A => 1,
+            Button::Left | Button::B => 1 << 1,
+            Button::Up | Button::Select => 1 << 2,
+            Button::Down | Button::Start => 1 << 3,
+        }
+    }
+
+    fn is_direction(&self) -> bool {
+        matches!(self, Button::Right | Button::Left | Button::Up | Button::Down)
+    }
+}
+
+/// The P1 register. Everything is active low: a selected group has its
+/// bit cleared, and a pressed button pulls its line to 0.
+pub(crate) struct Joypad {
+    select: u8,
+    directions: u8,
+    actions: u8,
+}
+
+impl Joypad {
+    pub(crate) fn new() -> Joypad {
+        Joypad {
+            select: SELECT_DIRECTIONS | SELECT_ACTIONS,
+            directions: 0,
+            actions: 0,
+        }
+    }
+
+    pub(crate) fn read(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    /// Returns the interrupts requested by the write, as IF bits.
+    pub(crate) fn write(&mut self, value: u8) -> u8 {
+        let lines = self.lines();
+        self.select = value & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+        self.interrupt(lines)
+    }
+
+    /// Returns the interrupts requested by the change, as IF bits.
+    pub(crate) fn set_button(&mut self, button: Button, pressed: bool) -> u8 {
+        let lines = self.lines();
+        let group = if button.is_direction() { &mut self.directions } else { &mut self.actions };
+        if pressed {
+            *group |= button.line();
+        } else {
+            *group &= !button.line();
+        }
+        self.interrupt(lines)
+    }
+
+    /// Whether a button of a selected group is pressed, which is what takes
+    /// the CPU out of STOP.
+    pub(crate) fn any_line_low(&self) -> bool {
+        self.lines() != 0x0F
+    }
+
+    fn lines(&self) -> u8 {
+        let mut pressed = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            pressed |= self.directions;
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            pressed |= self.actions;
+        }
+        !pressed & 0x0F
+    }
+
+    fn interrupt(&self, previous_lines: u8) -> u8 {
+        // Only a line going from high to low requests the interrupt.
+        if previous_lines & !self.lines() != 0 { JOYPAD_INTERRUPT } else { 0 }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.select);
+        state.u8(self.directions);
+        state.u8(self.actions);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.select = state.u8()?;
+        self.directions = state.u8()?;
+        self.actions = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::memory::MemoryBus;
+
+    #[test]
+    fn reads_show_the_selected_group() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::Start, true);
+        joypad.set_button(Button::Left, true);
+        // Nothing selected, every line is high.
+        assert_eq!(joypad.read(), 0xFF);
+        // A cleared bit selects its group.
+        joypad.write(SELECT_DIRECTIONS);
+        assert_eq!(joypad.read(), 0xC0 | SELECT_DIRECTIONS | 0b0111);
+        joypad.write(SELECT_ACTIONS);
+        assert_eq!(joypad.read(), 0xC0 | SELECT_ACTIONS | 0b1101);
+        joypad.write(0);
+        assert_eq!(joypad.read(), 0xC0 | 0b0101);
+    }
+
+    #[test]
+    fn only_lines_going_low_interrupt() {
+        let mut joypad = Joypad::new();
+        // Unselected buttons don't pull any line.
+        assert_eq!(joypad.set_button(Button::A, true), 0);
+        assert_eq!(joypad.write(SELECT_DIRECTIONS), JOYPAD_INTERRUPT);
+        assert_eq!(joypad.set_button(Button::A, false), 0);
+        assert_eq!(joypad.set_button(Button::B, true), JOYPAD_INTERRUPT);
+        // B already pulls the line low, Left shares it.
+        joypad.write(0);
+        assert_eq!(joypad.set_button(Button::Left, true), 0);
+        assert_eq!(joypad.set_button(Button::Start, true), JOYPAD_INTERRUPT);
+        assert_eq!(joypad.set_button(Button::Start, false), 0);
+    }
+
+    #[test]
+    fn selected_buttons_wake_up_from_stop() {
+        // STOP and the byte after it, then XOR A.
+        let mut rom = vec![0xAF; 0x8000];
+        rom[..2].copy_from_slice(&[0x10, 0x00]);
+        let mut cpu = CPU::new(MemoryBus::new(None, rom));
+        cpu.step();
+        assert!(cpu.is_stopped);
+        cpu.bus.write_byte(JOYPAD_ADDRESS as u16, SELECT_DIRECTIONS);
+        cpu.bus.set_button(Button::Up, true);
+        cpu.step();
+        assert!(cpu.is_stopped && cpu.pc == 2);
+        cpu.bus.set_button(Button::Select, true);
+        cpu.step();
+        assert!(!cpu.is_stopped);
+        assert_eq!(cpu.pc, 3);
+    }
+
+    #[test]
+    fn buttons_are_found_by_name() {
+        assert_eq!(Button::from_name("start"), Some(Button::Start));
+        assert_eq!(Button::from_name("A"), Some(Button::A));
+        assert_eq!(Button::from_name("turbo"), None);
+    }
+}
@@ -0,0 +1,28 @@
+#![allow(clippy::upper_case_acronyms)]
+
+//! A Game Boy emulator core. `GameBoy` runs a cartridge, the modules hold
+//! what goes around it: palettes to show its frames, sinks and recorders
+//! for its sound, and things to plug in its link and infrared ports.
+
+pub mod audio;
+pub mod cartridge;
+pub mod graphics;
+pub mod infrared;
+pub mod joypad;
+pub mod link;
+pub mod mooneye;
+pub mod printer;
+pub mod recording;
+pub mod report;
+pub mod screenshot;
+pub mod serial;
+pub mod trace;
+pub mod utils;
+mod cpu;
+mod gameboy;
+mod memory;
+mod state;
+mod timer;
+
+pub use crate::gameboy::{CpuRegisters, GameBoy, ONE_FRAME_IN_CYCLES};
+pub use crate::link::{InputEvent, LinkedPair, Side};
@@ -0,0 +1,420 @@
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use crate::infrared::InfraredEnd;
+use crate::joypad::Button;
+use crate::serial::SerialEndpoint;
+use crate::GameBoy;
+
+/// Where `TcpLink::host` listens unless told otherwise: only emulators of
+/// the same machine can plug in.
+pub const DEFAULT_LINK_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+// Both emulators stop and wait for each other every this many clock
+// cycles, so neither can get more than that ahead.
+const SYNC_INTERVAL: u32 = 4096;
+
+const MESSAGE_SYNC: u8 = 0;
+const MESSAGE_TRANSFER: u8 = 1;
+const MESSAGE_REPLY: u8 = 2;
+
+/// A link cable to another emulator over TCP.
+///
+/// Time is split in slices of `SYNC_INTERVAL` cycles, and at the end of
+/// each slice both sides send a sync message and wait for the other's.
+/// When the side driving the clock starts a transfer, it sends its byte
+/// and waits for the reply. The other side only handles it at the end of
+/// the slice, so what is exchanged only depends on emulated time, not on
+/// how fast each host runs.
+pub struct TcpLink {
+    stream: Option<TcpStream>,
+    cycles: u32,
+    // Sync messages received while waiting for a reply.
+    peer_syncs: u32,
+    // The byte to send back when the other side drives the clock, known
+    // while the Game Boy waits for a transfer.
+    outgoing: Option<u8>,
+    // A byte received from the other side, waiting to be picked up.
+    incoming: Option<u8>,
+}
+
+impl TcpLink {
+    /// Waits for the other emulator to connect on `port` of `address`.
+    pub fn host(address: IpAddr, port: u16) -> Result<TcpLink, String> {
+        let listener = TcpListener::bind((address, port))
+            .map_err(|error| format!("Could not listen on {}:{}: {}", address, port, error))?;
+        eprintln!("Waiting for the other Game Boy on {}:{}...", address, port);
+        let (stream, address) = listener.accept().map_err(|error| format!("Could not accept the link cable: {}", error))?;
+        eprintln!("Linked with {}", address);
+        TcpLink::new(stream)
+    }
+
+    /// Connects to an emulator started with `host`.
+    pub fn connect(address: impl ToSocketAddrs + std::fmt::Display) -> Result<TcpLink, String> {
+        let stream = TcpStream::connect(&address).map_err(|error| format!("Could not connect to {}: {}", address, error))?;
+        TcpLink::new(stream)
+    }
+
+    fn new(stream: TcpStream) -> Result<TcpLink, String> {
+        // Messages are tiny and always waited for, batching them would only
+        // add latency.
+        stream.set_nodelay(true).map_err(|error| error.to_string())?;
+        Ok(TcpLink {
+            stream: Some(stream),
+            cycles: 0,
+            peer_syncs: 0,
+            outgoing: None,
+            incoming: None,
+        })
+    }
+
+    fn send(&mut self, message: u8, value: u8) {
+        let sent = match self.stream.as_mut() {
+            Some(stream) => stream.write_all(&[message, value]),
+            None => return,
+        };
+        if sent.is_err() {
+            self.disconnect();
+        }
+    }
+
+    fn receive(&mut self) -> Option<(u8, u8)> {
+        let mut message = [0; 2];
+        let received = self.stream.as_mut()?.read_exact(&mut message);
+        if received.is_err() {
+            self.disconnect();
+            return None;
+        }
+        Some((message[0], message[1]))
+    }
+
+    fn disconnect(&mut self) {
+        if self.stream.take().is_some() {
+            eprintln!("The link cable was unplugged");
+        }
+    }
+
+    /// Handles every message until one of `wanted` shows up, which is
+    /// returned along with its value.
+    fn receive_until(&mut self, wanted: u8) -> Option<u8> {
+        loop {
+            let (message, value) = self.receive()?;
+            match message {
+                MESSAGE_SYNC => {
+                    if wanted == MESSAGE_SYNC {
+                        return Some(value);
+                    }
+                    self.peer_syncs += 1;
+                }
+                MESSAGE_TRANSFER => {
+                    let reply = match self.outgoing.take() {
+                        Some(byte) => {
+                            self.incoming = Some(value);
+                            byte
+                        }
+                        // Without a transfer pending on this side, the bits
+                        // shifted in are lost and the line reads high.
+                        None => 0xFF,
+                    };
+                    self.send(MESSAGE_REPLY, reply);
+                }
+                MESSAGE_REPLY if wanted == MESSAGE_REPLY => return Some(value),
+                _ => {}
+            }
+        }
+    }
+
+    fn sync(&mut self) {
+        self.send(MESSAGE_SYNC, 0);
+        if self.peer_syncs > 0 {
+            self.peer_syncs -= 1;
+        } else {
+            self.receive_until(MESSAGE_SYNC);
+        }
+    }
+}
+
+impl SerialEndpoint for TcpLink {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.send(MESSAGE_TRANSFER, byte);
+        self.receive_until(MESSAGE_REPLY).unwrap_or(0xFF)
+    }
+
+    fn respond(&mut self, byte: u8) -> Option<u8> {
+        self.outgoing = Some(byte);
+        self.incoming.take()
+    }
+
+    fn tick(&mut self, cycles: u16) {
+        self.cycles += cycles as u32;
+        while self.cycles >= SYNC_INTERVAL {
+            self.cycles -= SYNC_INTERVAL;
+            self.sync();
+        }
+        // `respond` is called again right after if the Game Boy still
+        // waits for a transfer.
+        self.outgoing = None;
+    }
+}
+
+/// One of the two Game Boys at the ends of a cable.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    pub(crate) fn index(&self) -> usize {
+        match self {
+            Side::Left => 0,
+            Side::Right => 1,
+        }
+    }
+
+    pub(crate) fn other(&self) -> Side {
+        match self {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Plug {
+    // The byte to send back when the other side drives the clock, known
+    // while this side waits for a transfer.
+    outgoing: Option<u8>,
+    // A byte received from the other side, waiting to be picked up.
+    incoming: Option<u8>,
+}
+
+/// One end of a link cable between two Game Boys of the same process.
+pub struct CableEnd {
+    plugs: Rc<RefCell<[Plug; 2]>>,
+    side: Side,
+}
+
+impl CableEnd {
+    pub fn pair() -> (CableEnd, CableEnd) {
+        let plugs = Rc::new(RefCell::new([Plug::default(), Plug::default()]));
+        (
+            CableEnd { plugs: plugs.clone(), side: Side::Left },
+            CableEnd { plugs, side: Side::Right },
+        )
+    }
+}
+
+impl SerialEndpoint for CableEnd {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut plugs = self.plugs.borrow_mut();
+        let other = &mut plugs[self.side.other().index()];
+        match other.outgoing.take() {
+            Some(reply) => {
+                other.incoming = Some(byte);
+                reply
+            }
+            // The other side is not waiting for a transfer, the line reads
+            // high.
+            None => 0xFF,
+        }
+    }
+
+    fn respond(&mut self, byte: u8) -> Option<u8> {
+        let plug = &mut self.plugs.borrow_mut()[self.side.index()];
+        plug.outgoing = Some(byte);
+        plug.incoming.take()
+    }
+
+    fn tick(&mut self, _cycles: u16) {
+        // `respond` is called again right after if the Game Boy still
+        // waits for a transfer.
+        self.plugs.borrow_mut()[self.side.index()].outgoing = None;
+    }
+}
+
+/// A button press or release, `cycle` clock cycles after the pair started.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct InputEvent {
+    pub cycle: u64,
+    pub side: Side,
+    pub button: Button,
+    pub pressed: bool,
+}
+
+/// Two Game Boys linked together in the same process, by the link cable
+/// and with their infrared ports facing each other.
+///
+/// They are stepped one instruction at a time, always moving the one that
+/// is behind, so they never drift apart by more than an instruction and
+/// every run with the same inputs gives the same result.
+pub struct LinkedPair {
+    game_boys: [GameBoy; 2],
+    // Inputs recorded before this cycle have been applied already.
+    next_input_cycle: u64,
+}
+
+impl LinkedPair {
+    /// Links two Game Boys, in place of whatever was plugged in their link
+    /// and infrared ports.
+    pub fn new(mut left: GameBoy, mut right: GameBoy) -> LinkedPair {
+        let (left_end, right_end) = CableEnd::pair();
+        left.connect_serial(Box::new(left_end));
+        right.connect_serial(Box::new(right_end));
+        let (left_end, right_end) = InfraredEnd::pair();
+        left.connect_infrared(Box::new(left_end));
+        right.connect_infrared(Box::new(right_end));
+        LinkedPair { game_boys: [left, right], next_input_cycle: 0 }
+    }
+
+    pub fn game_boy(&self, side: Side) -> &GameBoy {
+        &self.game_boys[side.index()]
+    }
+
+    pub fn game_boy_mut(&mut self, side: Side) -> &mut GameBoy {
+        &mut self.game_boys[side.index()]
+    }
+
+    /// How many clock cycles the one that is behind has run.
+    pub fn cycles(&self) -> u64 {
+        self.game_boys[0].cycles().min(self.game_boys[1].cycles())
+    }
+
+    /// Runs one instruction on the Game Boy that is behind.
+    pub fn step(&mut self) {
+        let [left, right] = &self.game_boys;
+        let side = if left.cycles() <= right.cycles() { Side::Left } else { Side::Right };
+        self.game_boy_mut(side).step_instruction();
+    }
+
+    /// Runs both Game Boys for `cycles` clock cycles, pressing and releasing
+    /// buttons as recorded in `inputs`, which must be sorted by cycle.
+    /// Events applied by an earlier run are skipped, so a recording can be
+    /// played in slices by passing it whole to every run.
+    pub fn run(&mut self, cycles: u64, inputs: &[InputEvent]) {
+        let end = self.cycles() + cycles;
+        let applied = self.next_input_cycle;
+        let mut inputs = inputs.iter().skip_while(|input| input.cycle < applied).peekable();
+        while self.cycles() < end {
+            let now = self.cycles();
+            while let Some(input) = inputs.next_if(|input| input.cycle <= now) {
+                self.game_boys[input.side.index()].set_button(input.button, input.pressed);
+            }
+            self.next_input_cycle = now + 1;
+            self.step();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn bytes_are_swapped_over_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // The other side waits for a transfer driven by this side.
+        let other = thread::spawn(move || {
+            let mut link = TcpLink::new(listener.accept().unwrap().0).unwrap();
+            loop {
+                link.tick(4);
+                if let Some(byte) = link.respond(0x99) {
+                    return byte;
+                }
+            }
+        });
+
+        let mut link = TcpLink::connect(address).unwrap();
+        link.tick(100);
+        assert_eq!(link.exchange(0x42), 0x99);
+        link.tick(SYNC_INTERVAL as u16);
+        assert_eq!(other.join().unwrap(), 0x42);
+    }
+
+    #[test]
+    fn nobody_listening_reads_high() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let other = thread::spawn(move || {
+            let mut link = TcpLink::new(listener.accept().unwrap().0).unwrap();
+            link.tick(SYNC_INTERVAL as u16);
+        });
+
+        let mut link = TcpLink::connect(address).unwrap();
+        assert_eq!(link.exchange(0x42), 0xFF);
+        link.tick(SYNC_INTERVAL as u16);
+        other.join().unwrap();
+    }
+
+    #[test]
+    fn cable_ends_swap_bytes_between_serial_ports() {
+        use crate::serial::{Serial, SB_ADDRESS, SC_ADDRESS, SERIAL_INTERRUPT};
+
+        let (left_end, right_end) = CableEnd::pair();
+        let mut left = Serial::new();
+        let mut right = Serial::new();
+        left.connect(Box::new(left_end));
+        right.connect(Box::new(right_end));
+
+        right.write_register(SB_ADDRESS, 0x99);
+        right.write_register(SC_ADDRESS, 0x80);
+        assert_eq!(right.step(4), 0);
+
+        left.write_register(SB_ADDRESS, 0x42);
+        left.write_register(SC_ADDRESS, 0x81);
+        assert_eq!(left.step(8 * 512), SERIAL_INTERRUPT);
+        assert_eq!(left.read_register(SB_ADDRESS), 0x99);
+
+        assert_eq!(right.step(4), SERIAL_INTERRUPT);
+        assert_eq!(right.read_register(SB_ADDRESS), 0x42);
+    }
+
+    #[test]
+    fn cable_end_reads_high_when_the_other_side_is_not_waiting() {
+        let (mut left, mut right) = CableEnd::pair();
+        right.respond(0x99);
+        right.tick(4);
+        assert_eq!(left.exchange(0x42), 0xFF);
+        assert_eq!(right.respond(0x99), None);
+    }
+
+    // LD BC,d16 with the value in B, LD A,B and LD (a16),A.
+    fn write(address: u16, value: u8) -> Vec<u8> {
+        vec![0x01, 0x00, value, 0x78, 0xEA, address as u8, (address >> 8) as u8]
+    }
+
+    // Starts a transfer of `byte`, then enables the serial interrupt and
+    // halts. The handler hits LD B,B and halts for good.
+    fn transfer_rom(delay: usize, byte: u8, control: u8) -> GameBoy {
+        let mut code = vec![0xAF; delay];
+        code.extend(write(0xFF01, byte));
+        code.extend(write(0xFF02, control));
+        code.extend(write(0xFFFF, crate::serial::SERIAL_INTERRUPT));
+        code.extend([0xFB, 0x76]);
+        let mut rom = vec![0xAF; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+        rom[0x58..0x5A].copy_from_slice(&[0x40, 0x76]);
+        GameBoy::new(None, rom)
+    }
+
+    #[test]
+    fn linked_pairs_exchange_bytes_and_raise_interrupts() {
+        // The right side waits for the left one to drive the clock, which
+        // only happens once the right side is ready.
+        let left = transfer_rom(16, 0x42, 0x81);
+        let right = transfer_rom(0, 0x99, 0x80);
+        let mut pair = LinkedPair::new(left, right);
+        pair.run(8 * 512 + 1000, &[]);
+
+        for (side, received) in [(Side::Left, 0x99), (Side::Right, 0x42)] {
+            let game_boy = pair.game_boy_mut(side);
+            assert_eq!(game_boy.read_memory(0xFF01), received, "{:?} side", side);
+            assert!(game_boy.take_breakpoint(), "{:?} side", side);
+            assert_eq!(game_boy.read_memory(0xFF0F) & crate::serial::SERIAL_INTERRUPT, 0, "{:?} side", side);
+        }
+        assert!(pair.cycles() >= 8 * 512 + 1000);
+    }
+}
@@ -1,72 +1,588 @@
 extern crate clap;
 
-mod graphics;
-mod memory;
-mod utils;
-mod cpu;
+mod config;
+mod display;
+mod filters;
+mod input;
+mod visualiser;
 
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::thread::sleep;
-use std::time::{Duration, Instant};
-use clap::{App, Arg};
-use minifb::{Key, Window, WindowOptions};
-use crate::cpu::{CPU, FlagRegister, Registers};
-use crate::graphics::{empty_tile, GPU, VRAM_SIZE};
-use crate::memory::MemoryBus;
-use crate::utils::buffer_from_file;
-
-const ENLARGEMENT_FACTOR: usize = 1;
-const WINDOW_DIMENSIONS: [usize; 2] = [(160 * ENLARGEMENT_FACTOR), (144 * ENLARGEMENT_FACTOR)];
-const NUMBER_OF_PIXELS: usize = 23040;
-const ONE_FRAME_IN_CYCLES: usize = 70224;
+use std::time::Duration;
+use clap::{App, Arg, ArgGroup};
+use minifb::{KeyRepeat, Window, WindowOptions};
+use emulator::{GameBoy, ONE_FRAME_IN_CYCLES};
+use emulator::audio::{AudioOutput, NullSink, CHANNEL_COUNT, CHANNEL_NAMES, DEFAULT_SAMPLE_RATE};
+use emulator::cartridge::CartridgeHeader;
+use emulator::graphics::{Argb8888, Palette, PaletteCycle, PaletteStage, NUMBER_OF_PIXELS, SCREEN_HEIGHT, SCREEN_WIDTH};
+use emulator::infrared::Loopback;
+use emulator::link::{TcpLink, DEFAULT_LINK_ADDRESS};
+use emulator::mooneye;
+use emulator::printer::Printer;
+use emulator::recording::AudioRecorder;
+use emulator::report;
+use emulator::screenshot;
+use emulator::serial::{SerialCapture, SerialOutput, SharedEndpoint};
+use emulator::trace::{TraceWindow, Tracer};
+use emulator::utils::buffer_from_file;
+use crate::config::Config;
+use crate::display::{Display, ScalingMode};
+use crate::filters::{FilterChain, Frame, VideoFilter, FILTER_NAMES};
+use crate::input::{Hotkey, KeyBindings};
+use crate::visualiser::Visualiser;
+
+const DEFAULT_SCALE: &str = "3";
+const MAX_SCALE: usize = 8;
+// Only one frame out of this many is shown while fast forwarding.
+const FAST_FORWARD_FRAME_SKIP: u64 = 4;
+const PAUSED_REFRESH: Duration = Duration::from_millis(16);
+// How much sound is queued ahead of what is being played.
+const AUDIO_LATENCY: Duration = Duration::from_millis(50);
 
 fn main() {
     let args = App::new("Emulator")
         .arg(Arg::with_name("boot").short("b").value_name("FILE"))
         .arg(Arg::with_name("rom").short("r").value_name("FILE"))
+        .arg(Arg::with_name("config").short("c").long("config").value_name("FILE"))
+        .arg(Arg::with_name("palette").short("p").long("palette").value_name("PALETTE")
+            .help("A preset (classic-green, pocket, light, high-contrast) or four hex colours"))
+        .arg(Arg::with_name("scale").short("s").long("scale").value_name("FACTOR").default_value(DEFAULT_SCALE)
+            .help("Initial window size, from 1 to 8 times the screen size"))
+        .arg(Arg::with_name("scaling").long("scaling").value_name("MODE").possible_values(&["integer", "smooth"])
+            .default_value("integer"))
+        .arg(Arg::with_name("filter").short("f").long("filter").value_name("FILTER").multiple(true).number_of_values(1)
+            .possible_values(&FILTER_NAMES)
+            .help("Post-processing applied to every frame, can be repeated to chain filters"))
+        .arg(Arg::with_name("bind").long("bind").value_name("ACTION=KEY").multiple(true).number_of_values(1)
+            .help("Binds a button or a hotkey to a key, on top of the config file"))
+        .arg(Arg::with_name("serial-stdout").long("serial-stdout")
+            .help("Prints the bytes sent over the link cable to stdout"))
+        .arg(Arg::with_name("serial-file").long("serial-file").value_name("FILE")
+            .help("Writes the bytes sent over the link cable to a file"))
+        .arg(Arg::with_name("link-host").long("link-host").value_name("PORT").conflicts_with("link-connect")
+            .help("Waits for another emulator to plug a link cable on this port"))
+        .arg(Arg::with_name("link-address").long("link-address").value_name("ADDRESS").requires("link-host")
+            .help("Listens for the link cable on ADDRESS, like 0.0.0.0 for the whole network, rather than 127.0.0.1"))
+        .arg(Arg::with_name("link-connect").long("link-connect").value_name("HOST:PORT")
+            .help("Plugs a link cable in an emulator started with --link-host"))
+        .arg(Arg::with_name("printer").long("printer").value_name("DIRECTORY").conflicts_with_all(&["link-host", "link-connect"])
+            .help("Plugs a Game Boy Printer saving its prints as PNG images in the directory"))
+        .arg(Arg::with_name("ir-loopback").long("ir-loopback")
+            .help("Puts a mirror in front of the infrared port"))
+        .arg(Arg::with_name("sample-rate").long("sample-rate").value_name("HZ")
+            .help("Rate at which audio samples are produced"))
+        .arg(Arg::with_name("record-audio").long("record-audio").value_name("FILE")
+            .help("Records the sound to a WAV file"))
+        .arg(Arg::with_name("record-stems").long("record-stems").requires("record-audio")
+            .help("Also records each sound channel to its own WAV file next to the recording"))
+        .arg(Arg::with_name("record-on-hotkey").long("record-on-hotkey").requires("record-audio")
+            .help("Waits for the record-audio hotkey before recording"))
+        .arg(Arg::with_name("visualiser").long("visualiser").conflicts_with("headless")
+            .help("Starts with the sound channels oscilloscope instead of the game screen"))
+        .arg(Arg::with_name("trace").long("trace").value_name("FILE")
+            .help("Logs the registers before every instruction, in the format of gameboy-doctor"))
+        .arg(Arg::with_name("trace-start-pc").long("trace-start-pc").value_name("ADDRESS").requires("trace")
+            .help("Starts the trace when the CPU reaches ADDRESS"))
+        .arg(Arg::with_name("trace-start-cycle").long("trace-start-cycle").value_name("N").requires("trace")
+            .help("Starts the trace after N clock cycles"))
+        .arg(Arg::with_name("trace-stop-pc").long("trace-stop-pc").value_name("ADDRESS").requires("trace")
+            .help("Stops the trace when the CPU reaches ADDRESS"))
+        .arg(Arg::with_name("trace-stop-cycle").long("trace-stop-cycle").value_name("N").requires("trace")
+            .help("Stops the trace after N clock cycles"))
+        .arg(Arg::with_name("trace-doctor").long("trace-doctor").requires("trace")
+            .help("Makes LY always read 0x90, as in the reference logs of gameboy-doctor"))
+        .arg(Arg::with_name("headless").long("headless")
+            .help("Runs without opening a window"))
+        // Mooneye ROMs never open a window, the limits are their timeout.
+        .group(ArgGroup::with_name("windowless").args(&["headless", "mooneye"]).multiple(true))
+        .arg(Arg::with_name("frames").long("frames").value_name("N").requires("windowless")
+            .help("Stops a headless run after N frames"))
+        .arg(Arg::with_name("cycles").long("cycles").value_name("N").requires("windowless")
+            .help("Stops a headless run after N clock cycles"))
+        .arg(Arg::with_name("until-serial").long("until-serial").value_name("TEXT").requires("headless")
+            .conflicts_with_all(&["printer", "link-host", "link-connect"])
+            .help("Stops a headless run once the game sends TEXT over the link cable"))
+        .arg(Arg::with_name("until-pc").long("until-pc").value_name("ADDRESS").requires("headless")
+            .help("Stops a headless run when the CPU reaches ADDRESS, written like 0x0150"))
+        .arg(Arg::with_name("dump-frame").long("dump-frame").value_name("FILE").requires("headless")
+            .help("Saves the last frame of a headless run as a PNG image"))
+        .arg(Arg::with_name("screenshot-at-frame").long("screenshot-at-frame").value_names(&["N", "FILE"])
+            .multiple(true).number_of_values(2).requires("headless")
+            .help("Saves frame N of a headless run as a PNG image, can be repeated"))
+        .arg(Arg::with_name("screenshot-size").long("screenshot-size").value_name("SIZE").possible_values(&["native", "scaled"])
+            .default_value("native")
+            .help("Saves screenshots at the size of the screen, or scaled like the window"))
+        .arg(Arg::with_name("screenshot-palette").long("screenshot-palette").value_name("PALETTE")
+            .help("Palette of the screenshots, the one on screen by default"))
+        .arg(Arg::with_name("mooneye").long("mooneye").value_name("PATH")
+            .help("Runs a Mooneye test ROM, or all those below a directory, and prints which passed"))
+        .arg(Arg::with_name("dump-memory").long("dump-memory").value_name("FILE").requires("headless")
+            .help("Saves the 64 KiB address space at the end of a headless run"))
         .get_matches();
-    let boot = args.value_of("boot").map(|path| buffer_from_file(path));
-    let rom = args.value_of("rom").map(|path| buffer_from_file(path)).unwrap();
-    let cpu = CPU {
-        registers: Registers {
-            a: 0,
-            b: 0,
-            c: 0,
-            d: 0,
-            e: 0,
-            f: FlagRegister {
-                zero: false,
-                subtract: false,
-                half_carry: false,
-                carry: false,
-            },
-            h: 0,
-            l: 0,
-        },
-        pc: 0x0,
-        sp: 0x00,
-        bus: MemoryBus::new(boot, rom),
-        is_halted: false,
+    if let Some(path) = args.value_of("mooneye") {
+        let limit = |name| args.value_of(name)
+            .map(|value| value.parse::<u64>().unwrap_or_else(|_| exit_with_error(&format!("--{} should be a whole number", name))));
+        let timeout = limit("cycles")
+            .or(limit("frames").map(|frames| frames * ONE_FRAME_IN_CYCLES as u64))
+            .unwrap_or(MOONEYE_TIMEOUT);
+        let reports = mooneye::run(Path::new(path), timeout).unwrap_or_else(|error| exit_with_error(&error));
+        println!("{}", report::table(&reports));
+        std::process::exit(if reports.iter().any(|report| report.failed()) { EXIT_FAILED } else { 0 });
+    }
+    let boot = args.value_of("boot").map(buffer_from_file);
+    let rom_path = args.value_of("rom").unwrap();
+    let rom = buffer_from_file(rom_path);
+    let config = args.value_of("config").map(Config::from_file).transpose().unwrap_or_else(|error| exit_with_error(&error));
+    let header = CartridgeHeader::from_rom(&rom);
+    let palette = match args.value_of("palette") {
+        Some(spec) => Palette::parse(spec),
+        None => config.as_ref()
+            .map(|config| config.palette.palette_for(&header))
+            .transpose()
+            .map(|palette| palette.flatten().unwrap_or(Palette::GREYSCALE)),
+    }.unwrap_or_else(|error| exit_with_error(&error));
+    let scale = args.value_of("scale")
+        .and_then(|scale| scale.parse::<usize>().ok())
+        .filter(|scale| (1..=MAX_SCALE).contains(scale))
+        .unwrap_or_else(|| exit_with_error(&format!("--scale should be a whole number from 1 to {}", MAX_SCALE)));
+    let screenshot_palette = args.value_of("screenshot-palette")
+        .map(Palette::parse)
+        .transpose()
+        .unwrap_or_else(|error| exit_with_error(&error));
+    let screenshot_scaled = args.value_of("screenshot-size") == Some("scaled");
+    let scaling = args.value_of("scaling").and_then(ScalingMode::from_name).unwrap();
+    let filters = args.values_of("filter")
+        .map(|names| names.filter_map(<dyn VideoFilter>::from_name).collect())
+        .unwrap_or_default();
+    let bindings = KeyBindings::new(
+        &config.as_ref().map(|config| config.keys.clone()).unwrap_or_default(),
+        &args.values_of("bind").map(|bindings| bindings.collect::<Vec<_>>()).unwrap_or_default(),
+    ).unwrap_or_else(|error| exit_with_error(&error));
+    let serial_capture = args.value_of("until-serial").map(SerialCapture::new);
+    let mut serial_writers: Vec<Box<dyn Write>> = Vec::new();
+    if let Some(capture) = &serial_capture {
+        serial_writers.push(Box::new(capture.clone()));
+    }
+    if args.is_present("serial-stdout") {
+        serial_writers.push(Box::new(std::io::stdout()));
+    }
+    if let Some(path) = args.value_of("serial-file") {
+        let file = File::create(path).unwrap_or_else(|error| exit_with_error(&format!("Could not create {}: {}", path, error)));
+        serial_writers.push(Box::new(file));
+    }
+    let link = if let Some(port) = args.value_of("link-host") {
+        let port = port.parse().unwrap_or_else(|_| exit_with_error(&format!("Invalid port {}", port)));
+        let address = args.value_of("link-address")
+            .map(|address| address.parse().unwrap_or_else(|_| exit_with_error(&format!("Invalid address {}", address))))
+            .unwrap_or(DEFAULT_LINK_ADDRESS);
+        Some(TcpLink::host(address, port))
+    } else {
+        args.value_of("link-connect").map(TcpLink::connect)
+    }.transpose().unwrap_or_else(|error| exit_with_error(&error));
+    // Endpoints can't be cloned, so resetting keeps the same ones.
+    let serial_output = Rc::new(RefCell::new(SerialOutput::new(serial_writers)));
+    let link = link.map(|link| Rc::new(RefCell::new(link)));
+    let printer = args.value_of("printer").map(|directory| Rc::new(RefCell::new(Printer::new(directory.into()))));
+    let ir_loopback = args.is_present("ir-loopback");
+    let doctor_mode = args.is_present("trace-doctor");
+    let sample_rate = args.value_of("sample-rate")
+        .map(|rate| rate.parse::<u32>().ok().filter(|&rate| rate > 0))
+        .unwrap_or(Some(DEFAULT_SAMPLE_RATE))
+        .unwrap_or_else(|| exit_with_error("--sample-rate should be a positive whole number"));
+    let recorder = args.value_of("record-audio")
+        .map(|path| AudioRecorder::new(Path::new(path), sample_rate, args.is_present("record-stems"), !args.is_present("record-on-hotkey")))
+        .transpose()
+        .unwrap_or_else(|error| exit_with_error(&error));
+    let tap_channels = recorder.as_ref().is_some_and(AudioRecorder::records_stems);
+    let tracer = args.value_of("trace").map(|path| {
+        let address = |name| args.value_of(name).map(|address| parse_address(address)
+            .unwrap_or_else(|| exit_with_error(&format!("Invalid address {}", address))));
+        let cycle = |name| args.value_of(name)
+            .map(|value| value.parse::<u64>().unwrap_or_else(|_| exit_with_error(&format!("--{} should be a whole number", name))));
+        let window = TraceWindow {
+            start_pc: address("trace-start-pc"),
+            start_cycle: cycle("trace-start-cycle"),
+            stop_pc: address("trace-stop-pc"),
+            stop_cycle: cycle("trace-stop-cycle"),
+        };
+        let file = File::create(path).unwrap_or_else(|error| exit_with_error(&format!("Could not create {}: {}", path, error)));
+        Tracer::new(Box::new(file), window)
+    });
+    let power_on = move || {
+        let mut game_boy = GameBoy::new(boot.clone(), rom.clone());
+        game_boy.audio_mut().set_sample_rate(sample_rate);
+        game_boy.audio_mut().tap_channels(tap_channels);
+        game_boy.set_doctor_mode(doctor_mode);
+        if ir_loopback {
+            game_boy.connect_infrared(Box::new(Loopback::new()));
+        }
+        match (&link, &printer) {
+            (Some(link), _) => game_boy.connect_serial(Box::new(SharedEndpoint(link.clone()))),
+            (_, Some(printer)) => game_boy.connect_serial(Box::new(SharedEndpoint(printer.clone()))),
+            _ => game_boy.connect_serial(Box::new(SharedEndpoint(serial_output.clone()))),
+        }
+        game_boy
+    };
+    if args.is_present("headless") {
+        let limit = |name| args.value_of(name)
+            .map(|value| value.parse::<u64>().unwrap_or_else(|_| exit_with_error(&format!("--{} should be a whole number", name))));
+        let options = HeadlessOptions {
+            frames: limit("frames"),
+            cycles: limit("cycles"),
+            until_serial: serial_capture,
+            until_pc: args.value_of("until-pc").map(|address| parse_address(address)
+                .unwrap_or_else(|| exit_with_error(&format!("Invalid address {}", address)))),
+            dump_frame: args.value_of("dump-frame").map(PathBuf::from),
+            dump_memory: args.value_of("dump-memory").map(PathBuf::from),
+            screenshots: args.values_of("screenshot-at-frame")
+                .map(|values| values.collect::<Vec<_>>().chunks(2).map(|screenshot| {
+                    let frame = screenshot[0].parse::<u64>().ok().filter(|&frame| frame > 0)
+                        .unwrap_or_else(|| exit_with_error("--screenshot-at-frame should be given a frame number from 1"));
+                    (frame, PathBuf::from(screenshot[1]))
+                }).collect())
+                .unwrap_or_default(),
+            screenshot_scale: if screenshot_scaled { scale } else { 1 },
+            screenshot_palette: screenshot_palette.unwrap_or(palette),
+            palette,
+        };
+        let mut game_boy = power_on();
+        if let Some(tracer) = tracer {
+            game_boy.start_trace(tracer);
+        }
+        std::process::exit(run_headless(game_boy, recorder, &options));
+    }
+    let window_options = WindowOptions {
+        resize: true,
+        ..WindowOptions::default()
+    };
+    let window = Window::new("Emulator", SCREEN_WIDTH * scale, SCREEN_HEIGHT * scale, window_options).unwrap();
+    let frontend = Frontend {
+        window,
+        palette_stage: Argb8888(palette),
+        palettes: PaletteCycle::new(palette),
+        filters: FilterChain::new(filters),
+        display: Display::new(scaling),
+        bindings,
+        visualiser: args.is_present("visualiser").then(Visualiser::new),
+        screenshot_palette,
+        screenshot_scaled,
+        rom_path: PathBuf::from(rom_path),
     };
-    let window = Window::new("Emulator", WINDOW_DIMENSIONS[0], WINDOW_DIMENSIONS[1], WindowOptions::default()).unwrap();
-    run(cpu, window);
+    // There is no sound card backend yet, the null sink keeps the pace.
+    let output = AudioOutput::new(Box::new(NullSink::new(sample_rate)), AUDIO_LATENCY);
+    let state_path = PathBuf::from(format!("{}.state", rom_path));
+    run(power_on, frontend, recorder, output, tracer, &state_path);
 }
 
-fn run(mut cpu: CPU, mut window: Window) {
+fn exit_with_error(error: &str) -> ! {
+    eprintln!("error: {}", error);
+    std::process::exit(1)
+}
+
+/// Everything standing between the emulated hardware and the user.
+struct Frontend {
+    window: Window,
+    palette_stage: Argb8888,
+    palettes: PaletteCycle,
+    filters: FilterChain,
+    display: Display,
+    bindings: KeyBindings,
+    // Shown instead of the game screen when set.
+    visualiser: Option<Visualiser>,
+    // The palette on screen is used when not set.
+    screenshot_palette: Option<Palette>,
+    screenshot_scaled: bool,
+    // Screenshots are numbered next to it.
+    rom_path: PathBuf,
+}
+
+impl Frontend {
+    fn is_pressed(&self, hotkey: Hotkey) -> bool {
+        self.window.is_key_pressed(self.bindings.key(hotkey), KeyRepeat::No)
+    }
+
+    fn is_down(&self, hotkey: Hotkey) -> bool {
+        self.window.is_key_down(self.bindings.key(hotkey))
+    }
+
+    fn update_buttons(&self, game_boy: &mut GameBoy) {
+        for (button, key) in self.bindings.buttons() {
+            game_boy.set_button(button, self.window.is_key_down(key));
+        }
+    }
+
+    /// Saves the picture on screen, without filters, and returns where.
+    fn take_screenshot(&self, game_boy: &GameBoy) -> Result<PathBuf, String> {
+        let scale = if self.screenshot_scaled {
+            // Screenshots use the same whole factor integer scaling would.
+            let (width, height) = self.window.get_size();
+            (width / SCREEN_WIDTH).min(height / SCREEN_HEIGHT).max(1)
+        } else {
+            1
+        };
+        let palette = self.screenshot_palette.unwrap_or(self.palettes.current());
+        let path = screenshot::next_path(&self.rom_path);
+        screenshot::save(&path, game_boy.frame_buffer(), palette, scale)?;
+        Ok(path)
+    }
+
+    fn present(&mut self, game_boy: &GameBoy, buffer: &mut [u32]) {
+        let frame = match &self.visualiser {
+            Some(visualiser) => visualiser.render(&game_boy.audio().channel_statuses(), game_boy.audio().muted()),
+            None => {
+                self.palette_stage.render(game_boy.frame_buffer(), buffer);
+                self.filters.apply(Frame { pixels: buffer.to_vec(), width: SCREEN_WIDTH, height: SCREEN_HEIGHT })
+            }
+        };
+        let (width, height) = self.window.get_size();
+        let picture = self.display.present(&frame.pixels, frame.width, frame.height, width, height);
+        self.window.update_with_buffer(picture, width, height).unwrap();
+    }
+}
+
+/// Takes the mixed and per-channel samples produced since the last call
+/// and hands them to the recorder, if any. A recording that fails to be
+/// written is given up.
+fn drain_audio(game_boy: &mut GameBoy, recorder: &mut Option<AudioRecorder>) -> (Vec<[f32; 2]>, Vec<[[f32; 2]; CHANNEL_COUNT]>) {
+    let samples = game_boy.take_audio_samples();
+    let channel_samples = game_boy.take_channel_samples();
+    if let Some(Err(error)) = recorder.as_mut().map(|recorder| recorder.record(&samples, &channel_samples)) {
+        eprintln!("error: {}", error);
+        *recorder = None;
+    }
+    (samples, channel_samples)
+}
+
+fn print_muted_channels(game_boy: &GameBoy) {
+    let muted: Vec<&str> = CHANNEL_NAMES.iter().zip(game_boy.audio().muted()).filter(|(_, muted)| *muted).map(|(name, _)| *name).collect();
+    if muted.is_empty() {
+        eprintln!("All sound channels playing");
+    } else {
+        eprintln!("Muted sound channels: {}", muted.join(", "));
+    }
+}
+
+/// When a headless run stops and what it leaves behind.
+struct HeadlessOptions {
+    frames: Option<u64>,
+    cycles: Option<u64>,
+    until_serial: Option<SerialCapture>,
+    until_pc: Option<u16>,
+    dump_frame: Option<PathBuf>,
+    dump_memory: Option<PathBuf>,
+    // Frame numbers and where to save them.
+    screenshots: Vec<(u64, PathBuf)>,
+    screenshot_scale: usize,
+    screenshot_palette: Palette,
+    palette: Palette,
+}
+
+// A headless run stopped on a frame or cycle limit before seeing the serial
+// output or the address it was waiting for, or on the default limit.
+const EXIT_TIMED_OUT: i32 = 2;
+// Headless runs given no limit stop after ten emulated minutes, rather than
+// hang a CI job on a ROM that never gets where it was expected to.
+const DEFAULT_HEADLESS_FRAMES: u64 = 10 * 60 * 60;
+// A test ROM of a suite failed.
+const EXIT_FAILED: i32 = 1;
+// Mooneye test ROMs are done within a few emulated seconds.
+const MOONEYE_TIMEOUT: u64 = 20 * 60 * ONE_FRAME_IN_CYCLES as u64;
+
+fn parse_address(address: &str) -> Option<u16> {
+    match address.strip_prefix("0x").or_else(|| address.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => address.parse().ok(),
+    }
+}
+
+/// Runs until a limit or a condition of `options` is met, or for
+/// `DEFAULT_HEADLESS_FRAMES` if there is no limit, and returns the exit
+/// status.
+fn run_headless(mut game_boy: GameBoy, mut recorder: Option<AudioRecorder>, options: &HeadlessOptions) -> i32 {
+    let waiting = options.until_serial.is_some() || options.until_pc.is_some();
+    // Without anything else to stop it, a run ends after its last screenshot.
+    let last_screenshot = options.screenshots.iter().map(|(frame, _)| *frame).max()
+        .filter(|_| !waiting && options.frames.is_none() && options.cycles.is_none());
+    let default_limit = options.frames.is_none() && options.cycles.is_none();
+    let frame_limit = if default_limit { Some(DEFAULT_HEADLESS_FRAMES) } else { options.frames };
+    let status = loop {
+        let frames = game_boy.frames();
+        game_boy.step_instruction();
+        if game_boy.frames() != frames {
+            drain_audio(&mut game_boy, &mut recorder);
+            for (_, path) in options.screenshots.iter().filter(|(frame, _)| *frame == game_boy.frames()) {
+                screenshot::save(path, game_boy.frame_buffer(), options.screenshot_palette, options.screenshot_scale)
+                    .unwrap_or_else(|error| exit_with_error(&error));
+            }
+            if last_screenshot == Some(game_boy.frames()) {
+                break 0;
+            }
+        }
+        if options.until_pc == Some(game_boy.pc()) {
+            eprintln!("Reached {:#06x}", game_boy.pc());
+            break 0;
+        }
+        if let Some(capture) = options.until_serial.as_ref().filter(|capture| capture.found()) {
+            eprintln!("Found {:?} in the serial output", capture.text());
+            break 0;
+        }
+        if frame_limit.is_some_and(|limit| game_boy.frames() >= limit) || options.cycles.is_some_and(|limit| game_boy.cycles() >= limit) {
+            eprintln!("Stopped after {} frames ({} cycles)", game_boy.frames(), game_boy.cycles());
+            if default_limit {
+                eprintln!("No --frames or --cycles limit was given, gave up after the default of {} frames", DEFAULT_HEADLESS_FRAMES);
+            }
+            break if waiting || default_limit { EXIT_TIMED_OUT } else { 0 };
+        }
+    };
+    drain_audio(&mut game_boy, &mut recorder);
+    finish_trace(&mut game_boy);
+    for (frame, path) in options.screenshots.iter().filter(|(frame, _)| *frame > game_boy.frames()) {
+        eprintln!("Frame {} was never reached, {} was not saved", frame, path.display());
+    }
+    if let Some(path) = &options.dump_frame {
+        screenshot::save(path, game_boy.frame_buffer(), options.palette, 1).unwrap_or_else(|error| exit_with_error(&error));
+    }
+    if let Some(path) = &options.dump_memory {
+        std::fs::write(path, game_boy.dump_memory())
+            .unwrap_or_else(|error| exit_with_error(&format!("Could not write {}: {}", path.display(), error)));
+    }
+    status
+}
+
+fn run(
+    power_on: impl Fn() -> GameBoy,
+    mut frontend: Frontend,
+    mut recorder: Option<AudioRecorder>,
+    mut output: AudioOutput,
+    tracer: Option<Tracer>,
+    state_path: &Path,
+) {
+    let mut game_boy = power_on();
+    if let Some(tracer) = tracer {
+        game_boy.start_trace(tracer);
+    }
     let mut buffer: [u32; NUMBER_OF_PIXELS] = [0; NUMBER_OF_PIXELS];
-    let mut cycles: usize = 0;
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        cycles += cpu.step() as usize;
-        if cycles >= ONE_FRAME_IN_CYCLES {
-            for (i, pixel) in cpu.bus.graphics.canvas_buffer.chunks(4).enumerate() {
-                buffer[i] = (pixel[3] as u32) << 24
-                    | (pixel[2] as u32) << 16
-                    | (pixel[1] as u32) << 8
-                    | (pixel[0] as u32)
+    let mut paused = false;
+    while frontend.window.is_open() && !frontend.is_down(Hotkey::Quit) {
+        if paused {
+            frontend.window.update();
+            if frontend.is_pressed(Hotkey::Pause) {
+                paused = false;
+            }
+            sleep(PAUSED_REFRESH);
+            continue;
+        }
+        game_boy.step_frame();
+        let fast_forward = frontend.is_down(Hotkey::FastForward);
+        let (samples, channel_samples) = drain_audio(&mut game_boy, &mut recorder);
+        if let Some(visualiser) = &mut frontend.visualiser {
+            visualiser.push(&channel_samples);
+        }
+        // Sound is dropped while fast forwarding, and emulation runs as fast
+        // as it can.
+        if !fast_forward {
+            if let Err(error) = output.push(&samples) {
+                eprintln!("error: {}", error);
+            }
+        }
+        if fast_forward && !game_boy.frames().is_multiple_of(FAST_FORWARD_FRAME_SKIP) {
+            continue;
+        }
+        frontend.update_buttons(&mut game_boy);
+        if frontend.is_pressed(Hotkey::Palette) {
+            frontend.palette_stage = Argb8888(frontend.palettes.next());
+        }
+        if frontend.is_pressed(Hotkey::Pause) {
+            paused = true;
+        }
+        if frontend.is_pressed(Hotkey::Reset) {
+            let muted = game_boy.audio().muted();
+            // A trace only makes sense from power on, it ends with the reset.
+            finish_trace(&mut game_boy);
+            game_boy = power_on();
+            game_boy.audio_mut().set_muted(muted);
+        }
+        for channel in 0..CHANNEL_COUNT {
+            if frontend.is_pressed(Hotkey::Mute(channel)) {
+                game_boy.audio_mut().toggle_mute(channel);
+                print_muted_channels(&game_boy);
+            }
+            if frontend.is_pressed(Hotkey::Solo(channel)) {
+                game_boy.audio_mut().toggle_solo(channel);
+                print_muted_channels(&game_boy);
+            }
+        }
+        if frontend.is_pressed(Hotkey::Visualiser) {
+            frontend.visualiser = match frontend.visualiser {
+                Some(_) => None,
+                None => Some(Visualiser::new()),
+            };
+        }
+        // The visualiser feeds on the channel taps.
+        if frontend.visualiser.is_some() && !game_boy.audio().channels_tapped() {
+            game_boy.audio_mut().tap_channels(true);
+        }
+        if frontend.is_pressed(Hotkey::RecordAudio) {
+            match recorder.as_mut().map(AudioRecorder::toggle) {
+                Some(true) => eprintln!("Audio recording resumed"),
+                Some(false) => eprintln!("Audio recording paused"),
+                None => eprintln!("Audio recording needs --record-audio FILE"),
             }
-            window.update_with_buffer(&buffer, WINDOW_DIMENSIONS[0], WINDOW_DIMENSIONS[1]).unwrap();
-            cycles = 0;
-        } else {
-            sleep(Duration::from_nanos(2));
         }
+        if frontend.is_pressed(Hotkey::Screenshot) {
+            match frontend.take_screenshot(&game_boy) {
+                Ok(path) => eprintln!("Screenshot saved to {}", path.display()),
+                Err(error) => eprintln!("error: {}", error),
+            }
+        }
+        if frontend.is_pressed(Hotkey::SaveState) {
+            match std::fs::write(state_path, game_boy.save_state()) {
+                Ok(()) => eprintln!("State saved to {}", state_path.display()),
+                Err(error) => eprintln!("error: Could not write {}: {}", state_path.display(), error),
+            }
+        }
+        if frontend.is_pressed(Hotkey::LoadState) {
+            let loaded = std::fs::read(state_path)
+                .map_err(|error| format!("Could not read {}: {}", state_path.display(), error))
+                .and_then(|state| game_boy.load_state(&state));
+            match loaded {
+                Ok(()) => eprintln!("State loaded from {}", state_path.display()),
+                Err(error) => eprintln!("error: {}", error),
+            }
+        }
+        frontend.present(&game_boy, &mut buffer);
+    }
+    finish_trace(&mut game_boy);
+}
+
+fn finish_trace(game_boy: &mut GameBoy) {
+    if let Some(Err(error)) = game_boy.stop_trace() {
+        eprintln!("error: {}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_hex_or_decimal() {
+        assert_eq!(parse_address("0x0100"), Some(0x0100));
+        assert_eq!(parse_address("0XC3a0"), Some(0xC3A0));
+        assert_eq!(parse_address("256"), Some(256));
+        assert_eq!(parse_address("0xFFFF"), Some(0xFFFF));
+    }
+
+    #[test]
+    fn bad_addresses_are_refused() {
+        assert_eq!(parse_address("0x10000"), None);
+        assert_eq!(parse_address("65536"), None);
+        assert_eq!(parse_address("0x"), None);
+        assert_eq!(parse_address("C3A0"), None);
+        assert_eq!(parse_address("-1"), None);
     }
 }
@@ -1,5 +1,10 @@
-use crate::graphics::{empty_tile, GPU, VRAM_BEGIN, VRAM_END, VRAM_SIZE};
-use crate::WINDOW_DIMENSIONS;
+use crate::audio::{APU, AUDIO_BEGIN, AUDIO_END};
+use crate::graphics::{GPU, LCDC_ADDRESS, VRAM_BEGIN, VRAM_END, WX_ADDRESS};
+use crate::infrared::{Infrared, RP_ADDRESS};
+use crate::joypad::{Button, Joypad, JOYPAD_ADDRESS};
+use crate::serial::{Serial, SB_ADDRESS, SC_ADDRESS};
+use crate::timer::{Timer, DIV_ADDRESS, TAC_ADDRESS};
+use crate::state::{StateReader, StateWriter};
 
 pub const BOOT_ROM_BEGIN: usize = 0x00;
 pub const BOOT_ROM_END: usize = 0xFF;
@@ -28,6 +33,12 @@ pub const ZERO_PAGE_SIZE: usize = ZERO_PAGE_END - ZERO_PAGE_BEGIN + 1;
 pub const ECHO_RAM_BEGIN: usize = 0xE000;
 pub const ECHO_RAM_END: usize = 0xFDFF;
 
+// OAM DMA sits among the LCD registers but is not emulated yet.
+const DMA_ADDRESS: usize = 0xFF46;
+
+pub const INTERRUPT_FLAG_ADDRESS: usize = 0xFF0F;
+pub const INTERRUPT_ENABLE_ADDRESS: usize = 0xFFFF;
+
 pub(crate) struct MemoryBus {
     boot_rom: Option<[u8; BOOT_ROM_SIZE]>,
     rom_bank_0: [u8; ROM_BANK_0_SIZE],
@@ -36,12 +47,26 @@ pub(crate) struct MemoryBus {
     working_ram: [u8; WORKING_RAM_SIZE],
     zero_page: [u8; ZERO_PAGE_SIZE],
     pub(crate) graphics: GPU,
+    pub(crate) audio: APU,
+    pub(crate) timer: Timer,
+    pub(crate) joypad: Joypad,
+    pub(crate) serial: Serial,
+    pub(crate) infrared: Infrared,
+    pub(crate) interrupt_flag: u8,
+    pub(crate) interrupt_enable: u8,
 }
 
 impl MemoryBus {
     pub(crate) fn read_byte(&self, address:u16) -> u8 {
+        self.try_read_byte(address).unwrap_or_else(|| panic!("TODO implement more memory spaces {}", address))
+    }
+
+    /// Reads a byte, or returns `None` for what is not mapped yet.
+    // The boot ROM is mapped on top of the first bank until it is disabled.
+    #[allow(clippy::match_overlapping_arm)]
+    pub(crate) fn try_read_byte(&self, address:u16) -> Option<u8> {
         let address = address as usize;
-        match address {
+        Some(match address {
             BOOT_ROM_BEGIN ..= BOOT_ROM_END => {
                 if let Some(boot_rom) = self.boot_rom {
                     boot_rom[address]
@@ -68,12 +93,45 @@ impl MemoryBus {
                 self.zero_page[address - ZERO_PAGE_BEGIN]
             },
             VRAM_BEGIN ..= VRAM_END => {
-                self.graphics.vram[address - VRAM_BEGIN]
+                self.graphics.read_vram(address - VRAM_BEGIN)
+            }
+            JOYPAD_ADDRESS => {
+                self.joypad.read()
+            }
+            SB_ADDRESS ..= SC_ADDRESS => {
+                self.serial.read_register(address)
+            }
+            DIV_ADDRESS ..= TAC_ADDRESS => {
+                self.timer.read_register(address)
+            }
+            AUDIO_BEGIN ..= AUDIO_END => {
+                self.audio.read(address)
+            }
+            DMA_ADDRESS => {
+                return None
+            }
+            LCDC_ADDRESS ..= WX_ADDRESS => {
+                self.graphics.read_register(address)
+            }
+            RP_ADDRESS => {
+                self.infrared.read()
+            }
+            INTERRUPT_FLAG_ADDRESS => {
+                // Only the five lowest bits exist.
+                0xE0 | self.interrupt_flag
+            }
+            INTERRUPT_ENABLE_ADDRESS => {
+                self.interrupt_enable
             }
             _ => {
-                panic!("TODO implement more memory spaces {}", address)
+                return None
             }
-        }
+        })
+    }
+
+    /// The whole address space, with 0xFF where nothing is mapped yet.
+    pub(crate) fn dump(&self) -> Vec<u8> {
+        (0..=0xFFFF).map(|address| self.try_read_byte(address).unwrap_or(0xFF)).collect()
     }
 
     pub(crate) fn write_byte(&mut self, address: u16, byte: u8) {
@@ -98,7 +156,35 @@ impl MemoryBus {
                 self.zero_page[address - ZERO_PAGE_BEGIN] = byte
             },
             VRAM_BEGIN ..= VRAM_END => {
-                self.graphics.vram[address - VRAM_BEGIN] = byte
+                self.graphics.write_vram(address - VRAM_BEGIN, byte)
+            }
+            JOYPAD_ADDRESS => {
+                self.interrupt_flag |= self.joypad.write(byte)
+            }
+            SB_ADDRESS ..= SC_ADDRESS => {
+                self.serial.write_register(address, byte)
+            }
+            DIV_ADDRESS ..= TAC_ADDRESS => {
+                self.timer.write_register(address, byte)
+            }
+            AUDIO_BEGIN ..= AUDIO_END => {
+                self.audio.write(address, byte)
+            }
+            DMA_ADDRESS => {
+                // There is no OAM to copy to until sprites are drawn, the
+                // transfer is dropped rather than stopping the game.
+            }
+            LCDC_ADDRESS ..= WX_ADDRESS => {
+                self.graphics.write_register(address, byte)
+            }
+            RP_ADDRESS => {
+                self.infrared.write(byte)
+            }
+            INTERRUPT_FLAG_ADDRESS => {
+                self.interrupt_flag = byte & 0x1F
+            }
+            INTERRUPT_ENABLE_ADDRESS => {
+                self.interrupt_enable = byte
             }
             _ => {
                 panic!("TODO implement more memory spaces {}", address)
@@ -106,6 +192,52 @@ impl MemoryBus {
         }
     }
 
+    /// Lets the rest of the hardware catch up with the CPU.
+    pub(crate) fn step(&mut self, cycles: u16) {
+        self.interrupt_flag |= self.graphics.step(cycles);
+        self.interrupt_flag |= self.timer.step(cycles);
+        self.audio.step(cycles, self.timer.take_frame_sequencer_ticks());
+        self.interrupt_flag |= self.serial.step(cycles);
+    }
+
+    pub(crate) fn set_button(&mut self, button: Button, pressed: bool) {
+        self.interrupt_flag |= self.joypad.set_button(button, pressed);
+    }
+
+    /// Saves the memory and every piece of hardware on the bus. The boot ROM
+    /// is not saved, it is whatever the bus was created with.
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.rom_bank_0);
+        state.bytes(&self.rom_bank_n);
+        state.bytes(&self.external_ram);
+        state.bytes(&self.working_ram);
+        state.bytes(&self.zero_page);
+        state.u8(self.interrupt_flag);
+        state.u8(self.interrupt_enable);
+        self.graphics.save_state(state);
+        self.audio.save_state(state);
+        self.timer.save_state(state);
+        self.joypad.save_state(state);
+        self.serial.save_state(state);
+        self.infrared.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes(&mut self.rom_bank_0)?;
+        state.bytes(&mut self.rom_bank_n)?;
+        state.bytes(&mut self.external_ram)?;
+        state.bytes(&mut self.working_ram)?;
+        state.bytes(&mut self.zero_page)?;
+        self.interrupt_flag = state.u8()?;
+        self.interrupt_enable = state.u8()?;
+        self.graphics.load_state(state)?;
+        self.audio.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.joypad.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.infrared.load_state(state)
+    }
+
     pub fn new(boot_rom_buffer: Option<Vec<u8>>, game_rom: Vec<u8>) -> MemoryBus {
         let boot_rom = boot_rom_buffer.map(|boot_rom_buffer| {
             if boot_rom_buffer.len() != BOOT_ROM_SIZE {
@@ -122,12 +254,8 @@ impl MemoryBus {
 
         let mut rom_bank_0 = [0; ROM_BANK_0_SIZE];
         let mut rom_bank_n = [0; ROM_BANK_N_SIZE];
-        for i in 0..ROM_BANK_0_SIZE {
-            rom_bank_0[i] = game_rom[i];
-        }
-        for i in 0..ROM_BANK_N_SIZE {
-            rom_bank_n[i] = game_rom[ROM_BANK_0_SIZE + i];
-        }
+        rom_bank_0.copy_from_slice(&game_rom[..ROM_BANK_0_SIZE]);
+        rom_bank_n.copy_from_slice(&game_rom[ROM_BANK_0_SIZE..ROM_BANK_0_SIZE + ROM_BANK_N_SIZE]);
         MemoryBus {
             // Note: instead of modeling memory as one array of length 0xFFFF, we'll
             // break memory up into it's logical parts.
@@ -137,11 +265,29 @@ impl MemoryBus {
             external_ram: [0; EXTERNAL_RAM_SIZE],
             working_ram: [0; WORKING_RAM_SIZE],
             zero_page: [0; ZERO_PAGE_SIZE],
-            graphics: GPU {
-                vram: [0; VRAM_SIZE],
-                tile_set: [empty_tile(); 384],
-                canvas_buffer: [u32::MAX; WINDOW_DIMENSIONS[0] * WINDOW_DIMENSIONS[1] * 4],
-            },
+            graphics: GPU::new(),
+            audio: APU::default(),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            infrared: Infrared::new(),
+            interrupt_flag: 0,
+            interrupt_enable: 0,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oam_dma_writes_leave_the_lcd_registers_alone() {
+        let mut bus = MemoryBus::new(None, vec![0; 0x8000]);
+        bus.write_byte(LCDC_ADDRESS as u16, 0x91);
+        let registers: Vec<Option<u8>> = (LCDC_ADDRESS..=WX_ADDRESS).map(|address| bus.try_read_byte(address as u16)).collect();
+        bus.write_byte(DMA_ADDRESS as u16, 0xC1);
+        let after: Vec<Option<u8>> = (LCDC_ADDRESS..=WX_ADDRESS).map(|address| bus.try_read_byte(address as u16)).collect();
+        assert_eq!(after, registers);
+    }
+}
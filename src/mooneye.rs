@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+use crate::report::{guarded, Outcome, Report};
+use crate::{CpuRegisters, GameBoy};
+
+// What the Mooneye acceptance ROMs leave in B, C, D, E, H and L before
+// hitting LD B,B.
+const PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const FAILED: [u8; 6] = [0x42; 6];
+
+/// Runs a Mooneye test ROM until its LD B,B breakpoint, or `timeout` clock
+/// cycles, and tells from the registers whether it passed.
+pub fn run_rom(rom: Vec<u8>, timeout: u64) -> Outcome {
+    let registers = guarded(|| {
+        let mut game_boy = GameBoy::new(None, rom);
+        while game_boy.cycles() < timeout {
+            game_boy.step_instruction();
+            if game_boy.take_breakpoint() {
+                return Some(game_boy.registers());
+            }
+        }
+        None
+    });
+    match registers {
+        Ok(Some(registers)) => verdict(registers),
+        Ok(None) => Outcome::Failed(format!("No LD B,B after {} cycles", timeout)),
+        Err(error) => Outcome::Failed(error),
+    }
+}
+
+fn verdict(registers: CpuRegisters) -> Outcome {
+    let signature = [registers.b, registers.c, registers.d, registers.e, registers.h, registers.l];
+    match signature {
+        PASSED => Outcome::Passed,
+        FAILED => Outcome::Failed("The test reported a failure".to_string()),
+        [b, c, d, e, h, l] => Outcome::Failed(format!(
+            "Unexpected registers B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} at PC {:#06x}",
+            b, c, d, e, h, l, registers.pc
+        )),
+    }
+}
+
+/// Runs `path`, or every `.gb` file below it if it is a directory, in
+/// file name order. Reports are named after the path of each ROM.
+pub fn run(path: &Path, timeout: u64) -> Result<Vec<Report>, String> {
+    let mut roms = Vec::new();
+    find_roms(path, &mut roms)?;
+    roms.sort();
+    let reports = roms
+        .iter()
+        .map(|rom| {
+            let name = rom.strip_prefix(path).ok().filter(|name| !name.as_os_str().is_empty()).unwrap_or(rom);
+            let outcome = match std::fs::read(rom) {
+                Ok(bytes) => run_rom(bytes, timeout),
+                Err(error) => Outcome::Failed(format!("Could not read {}: {}", rom.display(), error)),
+            };
+            Report { name: name.display().to_string(), outcome }
+        })
+        .collect();
+    Ok(reports)
+}
+
+fn find_roms(path: &Path, roms: &mut Vec<PathBuf>) -> Result<(), String> {
+    if !path.is_dir() {
+        roms.push(path.to_path_buf());
+        return Ok(());
+    }
+    let entries = std::fs::read_dir(path).map_err(|error| format!("Could not read {}: {}", path.display(), error))?;
+    for entry in entries {
+        let entry = entry.map_err(|error| format!("Could not read {}: {}", path.display(), error))?;
+        let path = entry.path();
+        if path.is_dir() || path.extension().is_some_and(|extension| extension == "gb") {
+            find_roms(&path, roms)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LD BC,d16, LD DE,d16 and LD HL,d16 then LD B,B, where the cartridge
+    // starts.
+    fn rom(signature: [u8; 6]) -> Vec<u8> {
+        let [b, c, d, e, h, l] = signature;
+        let mut rom = vec![0xAF; 0x8000];
+        rom[0x100..0x10A].copy_from_slice(&[0x01, c, b, 0x11, e, d, 0x21, l, h, 0x40]);
+        rom
+    }
+
+    #[test]
+    fn the_fibonacci_signature_passes() {
+        assert!(matches!(run_rom(rom(PASSED), 1000), Outcome::Passed));
+    }
+
+    #[test]
+    fn other_signatures_fail() {
+        assert!(matches!(run_rom(rom(FAILED), 1000), Outcome::Failed(reason) if reason.contains("reported a failure")));
+        assert!(matches!(
+            run_rom(rom([3, 5, 8, 13, 21, 0]), 1000),
+            Outcome::Failed(reason) if reason.starts_with("Unexpected registers B:03 C:05 D:08 E:0D H:15 L:00")
+        ));
+    }
+
+    #[test]
+    fn roms_without_breakpoint_time_out() {
+        assert!(matches!(run_rom(vec![0xAF; 0x8000], 1000), Outcome::Failed(reason) if reason.starts_with("No LD B,B")));
+    }
+
+    #[test]
+    fn directories_are_searched_in_order() {
+        let directory = std::env::temp_dir().join(format!("mooneye-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("timer")).unwrap();
+        std::fs::write(directory.join("timer/div_write.gb"), rom(PASSED)).unwrap();
+        std::fs::write(directory.join("add_sp_e_timing.gb"), rom(FAILED)).unwrap();
+        std::fs::write(directory.join("README.md"), "").unwrap();
+        let reports = run(&directory, 1000).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let names: Vec<&str> = reports.iter().map(|report| report.name.as_str()).collect();
+        assert_eq!(names, ["add_sp_e_timing.gb", "timer/div_write.gb"]);
+        assert!(reports[0].failed());
+        assert!(!reports[1].failed());
+    }
+}
@@ -0,0 +1,456 @@
+use std::path::{Path, PathBuf};
+use crate::graphics::{ColorMode, Palette, PaletteStage, Rgba8888};
+use crate::serial::SerialEndpoint;
+use crate::utils::write_png;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 1;
+const STATUS_PRINTING: u8 = 1 << 1;
+const STATUS_IMAGE_DATA_FULL: u8 = 1 << 2;
+const STATUS_UNPROCESSED_DATA: u8 = 1 << 3;
+const STATUS_OTHER_ERROR: u8 = 1 << 6;
+
+// Sent back after the checksum to tell a printer is plugged in.
+const ALIVE: u8 = 0x81;
+
+// The printer memory holds up to nine bands of 20x2 tiles.
+const BAND_SIZE: usize = 20 * 2 * 16;
+const MEMORY_SIZE: usize = BAND_SIZE * 9;
+
+const PAPER_WIDTH: usize = 160;
+const TILE_SIZE: usize = 16;
+const TILES_PER_ROW: usize = PAPER_WIDTH / 8;
+// Margins are given in paper feeds, which we take as the height of a
+// band.
+const FEED_HEIGHT: usize = 16;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    MagicOne,
+    MagicTwo,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// The Game Boy Printer, plugged in the link port.
+///
+/// Games send it packets made of two magic bytes, a command, a compression
+/// flag, a little endian length, the data and a checksum. The printer
+/// answers the last two bytes with 0x81 and its status. Printed pictures are
+/// saved as PNG images as soon as they are printed: consecutive prints with
+/// no margin between them end up on the same sheet, which is saved again as
+/// it grows and is cut whenever the game feeds paper after a print. Sheets
+/// are numbered after the ones already in the directory.
+pub struct Printer {
+    directory: PathBuf,
+    // Where the sheet being printed is saved, once something is on it.
+    sheet: Option<PathBuf>,
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    packet: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    // Tile data received since the last print.
+    memory: Vec<u8>,
+    // Shades of the sheet being printed, `PAPER_WIDTH` per row.
+    paper: Vec<u8>,
+}
+
+impl Printer {
+    pub fn new(directory: PathBuf) -> Printer {
+        Printer {
+            directory,
+            sheet: None,
+            state: State::MagicOne,
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            memory: Vec::new(),
+            paper: Vec::new(),
+        }
+    }
+
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut reply = 0;
+        self.state = match self.state {
+            State::MagicOne if byte == MAGIC[0] => State::MagicTwo,
+            State::MagicOne => State::MagicOne,
+            State::MagicTwo if byte == MAGIC[1] => State::Command,
+            // Out of sync, wait for the next packet.
+            State::MagicTwo => State::MagicOne,
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 1 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.packet.clear();
+                if self.length == 0 { State::ChecksumLow } else { State::Data }
+            }
+            State::Data => {
+                self.packet.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.packet.len() == self.length as usize { State::ChecksumLow } else { State::Data }
+            }
+            State::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                State::Alive
+            }
+            State::Alive => {
+                reply = ALIVE;
+                self.execute();
+                State::Status
+            }
+            State::Status => {
+                reply = self.status;
+                // Printing is instant, it is only reported once so the game
+                // sees it start.
+                self.status &= !STATUS_PRINTING;
+                State::MagicOne
+            }
+        };
+        reply
+    }
+
+    fn execute(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+        match self.command {
+            COMMAND_INIT => {
+                self.memory.clear();
+                self.status = 0;
+            }
+            COMMAND_DATA => {
+                let data = if self.compressed { decompress(&self.packet) } else { self.packet.clone() };
+                self.memory.extend(data);
+                self.memory.truncate(MEMORY_SIZE);
+                if !self.memory.is_empty() {
+                    self.status |= STATUS_UNPROCESSED_DATA;
+                }
+                if self.memory.len() == MEMORY_SIZE {
+                    self.status |= STATUS_IMAGE_DATA_FULL;
+                }
+            }
+            COMMAND_PRINT if self.packet.len() >= 4 => {
+                let margins = self.packet[1];
+                let palette = self.packet[2];
+                self.status = (self.status & !(STATUS_UNPROCESSED_DATA | STATUS_IMAGE_DATA_FULL | STATUS_OTHER_ERROR)) | STATUS_PRINTING;
+                match self.print(margins >> 4, margins & 0x0F, palette) {
+                    Ok(Some(path)) => eprintln!("Printed {}", path.display()),
+                    Ok(None) => {}
+                    // Let the game tell the player, as it would for a paper
+                    // jam.
+                    Err(error) => {
+                        eprintln!("{}", error);
+                        self.status |= STATUS_OTHER_ERROR;
+                    }
+                }
+            }
+            // Every reply carries the status, there is nothing else to do.
+            COMMAND_STATUS => {}
+            _ => {}
+        }
+    }
+
+    /// Prints what was received since the last print and saves the sheet,
+    /// returning where, or `None` if nothing came out.
+    fn print(&mut self, margin_before: u8, margin_after: u8, palette: u8) -> Result<Option<PathBuf>, String> {
+        // 0 is often sent by games meaning the usual palette.
+        let palette = if palette == 0 { 0b1110_0100 } else { palette };
+        self.feed(margin_before);
+        let rows = self.memory.len() / (TILE_SIZE * TILES_PER_ROW) * 8;
+        for y in 0..rows {
+            for x in 0..PAPER_WIDTH {
+                let tile = &self.memory[((y / 8) * TILES_PER_ROW + x / 8) * TILE_SIZE..];
+                let low = tile[(y % 8) * 2] >> (7 - x % 8) & 1;
+                let high = tile[(y % 8) * 2 + 1] >> (7 - x % 8) & 1;
+                let color = high << 1 | low;
+                self.paper.push((palette >> (color * 2)) & 0b11);
+            }
+        }
+        self.memory.clear();
+        self.feed(margin_after);
+        if self.paper.is_empty() {
+            return Ok(None);
+        }
+        let path = self.sheet.take().unwrap_or_else(|| next_path(&self.directory));
+        let saved = self.save(&path);
+        // Feeding paper after the picture means the game is done with
+        // this sheet.
+        if margin_after > 0 {
+            self.paper.clear();
+        } else {
+            self.sheet = Some(path.clone());
+        }
+        saved.map(|_| Some(path))
+    }
+
+    fn feed(&mut self, lines: u8) {
+        self.paper.extend(std::iter::repeat_n(0, lines as usize * FEED_HEIGHT * PAPER_WIDTH));
+    }
+
+    fn save(&self, path: &Path) -> Result<(), String> {
+        std::fs::create_dir_all(&self.directory).map_err(|error| format!("Could not create {}: {}", self.directory.display(), error))?;
+        let stage = Rgba8888(Palette::GREYSCALE);
+        let pixels: Vec<[u8; 4]> = self.paper.iter().map(|&shade| stage.map(ColorMode::Shade, shade as u16)).collect();
+        write_png(path, PAPER_WIDTH, self.paper.len() / PAPER_WIDTH, &pixels)
+    }
+}
+
+/// The first of `print-001.png`, `print-002.png`... in `directory` that
+/// does not exist yet.
+fn next_path(directory: &Path) -> PathBuf {
+    (1..).map(|index| directory.join(format!("print-{:03}.png", index))).find(|path| !path.exists()).unwrap()
+}
+
+impl SerialEndpoint for Printer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.receive(byte)
+    }
+
+    // The printer never drives the clock.
+    fn respond(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+}
+
+/// Data packets can be run-length encoded: a byte with its top bit set is
+/// followed by one byte to repeat `(n & 0x7F) + 2` times, otherwise it is
+/// followed by `n + 1` bytes to copy as is.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut bytes = data.iter();
+    while let Some(&header) = bytes.next() {
+        if header & 0x80 != 0 {
+            if let Some(&byte) = bytes.next() {
+                output.extend(std::iter::repeat_n(byte, (header & 0x7F) as usize + 2));
+            }
+        } else {
+            output.extend(bytes.by_ref().take(header as usize + 1));
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::read_png;
+
+    const BLACK: [u8; 4] = [0, 0, 0, 0xFF];
+    const WHITE: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+        let mut packet = MAGIC.to_vec();
+        let header = [command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+        let checksum = header.iter().chain(data).fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        packet.extend(header);
+        packet.extend(data);
+        packet.extend([checksum as u8, (checksum >> 8) as u8, 0, 0]);
+        packet
+    }
+
+    // Sends a packet and returns the last two replies, which should be the
+    // alive byte and the status.
+    fn send(printer: &mut Printer, packet: &[u8]) -> [u8; 2] {
+        let replies: Vec<u8> = packet.iter().map(|&byte| printer.exchange(byte)).collect();
+        [replies[replies.len() - 2], replies[replies.len() - 1]]
+    }
+
+    // One band of 20x2 tiles, all in shade 3.
+    fn band() -> Vec<u8> {
+        vec![0xFF; BAND_SIZE]
+    }
+
+    fn print(margins: u8) -> Vec<u8> {
+        packet(COMMAND_PRINT, false, &[1, margins, 0xE4, 0x40])
+    }
+
+    // For tests that print nothing.
+    fn printer() -> Printer {
+        Printer::new(PathBuf::from("prints"))
+    }
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("printer-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn packets_are_answered_with_alive_and_status() {
+        let mut printer = printer();
+        assert_eq!(send(&mut printer, &packet(COMMAND_INIT, false, &[])), [ALIVE, 0]);
+        assert_eq!(send(&mut printer, &packet(COMMAND_DATA, false, &band())), [ALIVE, STATUS_UNPROCESSED_DATA]);
+        assert_eq!(send(&mut printer, &packet(COMMAND_STATUS, false, &[])), [ALIVE, STATUS_UNPROCESSED_DATA]);
+        assert_eq!(send(&mut printer, &packet(COMMAND_INIT, false, &[])), [ALIVE, 0]);
+    }
+
+    #[test]
+    fn bad_checksums_are_reported_and_ignored() {
+        let mut printer = printer();
+        let mut data = packet(COMMAND_DATA, false, &band());
+        let checksum = data.len() - 4;
+        data[checksum] ^= 1;
+        assert_eq!(send(&mut printer, &data), [ALIVE, STATUS_CHECKSUM_ERROR]);
+        assert!(printer.memory.is_empty());
+        assert_eq!(send(&mut printer, &packet(COMMAND_STATUS, false, &[])), [ALIVE, 0]);
+    }
+
+    #[test]
+    fn bytes_out_of_sync_are_skipped_until_the_magic_bytes() {
+        let mut printer = printer();
+        let mut bytes = vec![0x00, 0x88, 0x00, 0x33];
+        bytes.extend(packet(COMMAND_STATUS, false, &[]));
+        assert_eq!(send(&mut printer, &bytes), [ALIVE, 0]);
+    }
+
+    #[test]
+    fn memory_fills_up_after_nine_bands() {
+        let mut printer = printer();
+        for _ in 0..8 {
+            send(&mut printer, &packet(COMMAND_DATA, false, &band()));
+        }
+        assert_eq!(send(&mut printer, &packet(COMMAND_DATA, false, &band())), [ALIVE, STATUS_UNPROCESSED_DATA | STATUS_IMAGE_DATA_FULL]);
+        send(&mut printer, &packet(COMMAND_DATA, false, &band()));
+        assert_eq!(printer.memory.len(), MEMORY_SIZE);
+    }
+
+    #[test]
+    fn run_length_encoding_repeats_and_copies() {
+        assert_eq!(decompress(&[0x81, 0xAB, 0x01, 0x01, 0x02, 0x80, 0xCD]), [0xAB, 0xAB, 0xAB, 0x01, 0x02, 0xCD, 0xCD]);
+        // A repeat missing its byte is dropped.
+        assert_eq!(decompress(&[0x00, 0x07, 0x85]), [0x07]);
+    }
+
+    #[test]
+    fn compressed_data_is_expanded() {
+        let mut printer = printer();
+        // 5 runs of 128 bytes make one band.
+        let data: Vec<u8> = [0xFE, 0xFF].repeat(5);
+        send(&mut printer, &packet(COMMAND_DATA, true, &data));
+        assert_eq!(printer.memory, band());
+    }
+
+    #[test]
+    fn prints_are_saved_at_once_and_cut_after_a_feed() {
+        let directory = directory("sheets");
+        let mut printer = Printer::new(directory.clone());
+        send(&mut printer, &packet(COMMAND_DATA, false, &band()));
+        // No margin, the sheet goes on.
+        assert_eq!(send(&mut printer, &print(0x00)), [ALIVE, STATUS_PRINTING]);
+        let (width, height, pixels) = read_png(&directory.join("print-001.png")).unwrap();
+        assert_eq!((width, height), (PAPER_WIDTH, 16));
+        assert!(pixels.iter().all(|&pixel| pixel == BLACK));
+        assert_eq!(send(&mut printer, &packet(COMMAND_STATUS, false, &[])), [ALIVE, 0]);
+
+        // One feed after, the sheet is cut below a white margin.
+        send(&mut printer, &packet(COMMAND_DATA, false, &band()));
+        send(&mut printer, &print(0x01));
+        let (_, height, pixels) = read_png(&directory.join("print-001.png")).unwrap();
+        assert_eq!(height, 16 + 16 + FEED_HEIGHT);
+        assert_eq!(pixels[PAPER_WIDTH * 32 - 1], BLACK);
+        assert_eq!(pixels[PAPER_WIDTH * 32], WHITE);
+
+        send(&mut printer, &packet(COMMAND_DATA, false, &band()));
+        send(&mut printer, &print(0x10));
+        let (_, height, pixels) = read_png(&directory.join("print-002.png")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(height, FEED_HEIGHT + 16);
+        assert_eq!(pixels[0], WHITE);
+        assert_eq!(pixels[PAPER_WIDTH * FEED_HEIGHT], BLACK);
+    }
+
+    #[test]
+    fn the_print_palette_maps_shades() {
+        let directory = directory("palette");
+        let mut printer = Printer::new(directory.clone());
+        send(&mut printer, &packet(COMMAND_DATA, false, &band()));
+        // Shade 3 printed as white.
+        send(&mut printer, &packet(COMMAND_PRINT, false, &[1, 0x00, 0b0011_1111, 0x40]));
+        let (_, _, pixels) = read_png(&directory.join("print-001.png")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(pixels.iter().all(|&pixel| pixel == WHITE));
+    }
+
+    #[test]
+    fn sheets_are_numbered_after_the_ones_already_printed() {
+        let directory = directory("numbering");
+        std::fs::write(directory.join("print-001.png"), []).unwrap();
+        let mut printer = Printer::new(directory.clone());
+        send(&mut printer, &packet(COMMAND_DATA, false, &band()));
+        send(&mut printer, &print(0x00));
+        send(&mut printer, &packet(COMMAND_DATA, false, &band()));
+        send(&mut printer, &print(0x01));
+        let (_, height, _) = read_png(&directory.join("print-002.png")).unwrap();
+        let untouched = std::fs::read(directory.join("print-001.png")).unwrap();
+        let third = directory.join("print-003.png").exists();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(height, 16 + 16 + FEED_HEIGHT);
+        assert!(untouched.is_empty());
+        assert!(!third);
+    }
+
+    #[test]
+    fn missing_directories_are_created() {
+        let parent = std::env::temp_dir().join(format!("printer-missing-{}", std::process::id()));
+        let directory = parent.join("prints");
+        let mut printer = Printer::new(directory.clone());
+        send(&mut printer, &packet(COMMAND_DATA, false, &band()));
+        assert_eq!(send(&mut printer, &print(0x01)), [ALIVE, STATUS_PRINTING]);
+        let printed = directory.join("print-001.png").exists();
+        std::fs::remove_dir_all(&parent).unwrap();
+        assert!(printed);
+    }
+
+    #[test]
+    fn failing_to_save_is_reported_to_the_game() {
+        // A file stands where the directory should be.
+        let directory = std::env::temp_dir().join(format!("printer-file-{}", std::process::id()));
+        std::fs::write(&directory, []).unwrap();
+        let mut printer = Printer::new(directory.clone());
+        send(&mut printer, &packet(COMMAND_DATA, false, &band()));
+        let replies = send(&mut printer, &print(0x01));
+        std::fs::remove_file(&directory).unwrap();
+        assert_eq!(replies, [ALIVE, STATUS_PRINTING | STATUS_OTHER_ERROR]);
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::audio::{CHANNEL_COUNT, CHANNEL_NAMES};
+
+const HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const BYTES_PER_FRAME: u32 = (CHANNELS * BITS_PER_SAMPLE / 8) as u32;
+
+/// A stereo 16-bit PCM WAV file.
+///
+/// The sizes in the header are kept up to date after every write, so the
+/// file stays playable even if the emulator is killed while recording.
+pub struct WavWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32) -> Result<WavWriter, String> {
+        let file = File::create(path).map_err(|error| format!("Could not create {}: {}", path.display(), error))?;
+        let mut writer = WavWriter { path: path.to_path_buf(), file: BufWriter::new(file), data_size: 0 };
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_SIZE - 8).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // PCM
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&CHANNELS.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * BYTES_PER_FRAME).to_le_bytes());
+        header.extend_from_slice(&(BYTES_PER_FRAME as u16).to_le_bytes());
+        header.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        writer.file.write_all(&header).map_err(|error| writer.error(error))?;
+        Ok(writer)
+    }
+
+    /// Appends stereo samples going from -1.0 to 1.0.
+    pub fn write(&mut self, samples: &[[f32; 2]]) -> Result<(), String> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let bytes: Vec<u8> = samples
+            .as_flattened()
+            .iter()
+            .flat_map(|sample| ((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())
+            .collect();
+        self.data_size += bytes.len() as u32;
+        self.file.write_all(&bytes).map_err(|error| self.error(error))?;
+        self.update_sizes().map_err(|error| self.error(error))
+    }
+
+    fn update_sizes(&mut self) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.file.write_all(&self.data_size.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+
+    fn error(&self, error: std::io::Error) -> String {
+        format!("Could not write {}: {}", self.path.display(), error)
+    }
+}
+
+/// Records the mixed output of the APU to a WAV file, and optionally each
+/// channel to its own WAV file named after it, as `out-square1.wav` for
+/// `out.wav`.
+pub struct AudioRecorder {
+    mix: WavWriter,
+    stems: Vec<WavWriter>,
+    recording: bool,
+}
+
+impl AudioRecorder {
+    /// Recording starts right away if `recording` is set, otherwise it waits
+    /// for `toggle`.
+    pub fn new(path: &Path, sample_rate: u32, stems: bool, recording: bool) -> Result<AudioRecorder, String> {
+        let mix = WavWriter::create(path, sample_rate)?;
+        let stems = if stems {
+            CHANNEL_NAMES
+                .iter()
+                .map(|name| WavWriter::create(&stem_path(path, name), sample_rate))
+                .collect::<Result<_, _>>()?
+        } else {
+            Vec::new()
+        };
+        Ok(AudioRecorder { mix, stems, recording })
+    }
+
+    pub fn records_stems(&self) -> bool {
+        !self.stems.is_empty()
+    }
+
+    /// Pauses or resumes the recording and tells whether it is now running.
+    pub fn toggle(&mut self) -> bool {
+        self.recording = !self.recording;
+        self.recording
+    }
+
+    /// Appends the samples if the recording is running. `channel_samples`
+    /// only matters when recording stems.
+    pub fn record(&mut self, samples: &[[f32; 2]], channel_samples: &[[[f32; 2]; CHANNEL_COUNT]]) -> Result<(), String> {
+        if !self.recording {
+            return Ok(());
+        }
+        self.mix.write(samples)?;
+        for (channel, stem) in self.stems.iter_mut().enumerate() {
+            let samples: Vec<[f32; 2]> = channel_samples.iter().map(|channels| channels[channel]).collect();
+            stem.write(&samples)?;
+        }
+        Ok(())
+    }
+}
+
+fn stem_path(path: &Path, channel: &str) -> PathBuf {
+    let name = path.file_stem().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!("{}-{}.wav", name, channel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_sizes_follow_the_data() {
+        let path = std::env::temp_dir().join(format!("wav-writer-{}.wav", std::process::id()));
+        let mut writer = WavWriter::create(&path, 48000).unwrap();
+        writer.write(&[[0.0, 1.0], [-1.0, 2.0]]).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(read_u32(&bytes, 4), 36 + 8);
+        assert_eq!(read_u32(&bytes, 24), 48000);
+        assert_eq!(read_u32(&bytes, 40), 8);
+        let samples: Vec<i16> = bytes[44..].chunks(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]])).collect();
+        assert_eq!(samples, [0, i16::MAX, -i16::MAX, i16::MAX]);
+    }
+
+    #[test]
+    fn stems_are_named_after_the_mix() {
+        assert_eq!(stem_path(Path::new("music/out.wav"), "wave"), Path::new("music/out-wave.wav"));
+    }
+}
@@ -0,0 +1,112 @@
+use std::cell::Cell;
+use std::fmt::Write;
+use std::panic::{catch_unwind, set_hook, take_hook, AssertUnwindSafe};
+use std::sync::Once;
+
+thread_local! {
+    // Set while `guarded` runs on this thread.
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+
+/// How running a test ROM went.
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    /// The ROM is not there, which is fine: ROMs can't be shipped with the
+    /// sources.
+    Skipped(String),
+}
+
+pub struct Report {
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+impl Report {
+    pub fn failed(&self) -> bool {
+        matches!(self.outcome, Outcome::Failed(_))
+    }
+}
+
+/// Runs `emulation`, turning a panic into an error. The CPU panics on what
+/// it can't run yet, which should only fail the ROM at hand. The panic is
+/// not printed, the error ends up in the report.
+pub fn guarded<T>(emulation: impl FnOnce() -> T) -> Result<T, String> {
+    // The hook is shared by every thread, so it is only swapped once and
+    // stays quiet on the threads inside `guarded`.
+    static QUIET_HOOK: Once = Once::new();
+    QUIET_HOOK.call_once(|| {
+        let previous = take_hook();
+        set_hook(Box::new(move |info| {
+            if !QUIET.with(Cell::get) {
+                previous(info);
+            }
+        }));
+    });
+    let was_quiet = QUIET.with(|quiet| quiet.replace(true));
+    let result = catch_unwind(AssertUnwindSafe(emulation));
+    QUIET.with(|quiet| quiet.set(was_quiet));
+    result.map_err(|panic| {
+        let message = panic.downcast_ref::<String>().cloned()
+            .or_else(|| panic.downcast_ref::<&str>().map(|message| message.to_string()))
+            .unwrap_or_default();
+        format!("Emulation stopped: {}", message)
+    })
+}
+
+/// One line per ROM, as in `PASS  tetris-title`.
+pub fn table(reports: &[Report]) -> String {
+    let width = reports.iter().map(|report| report.name.len()).max().unwrap_or(0);
+    let mut table = String::new();
+    for report in reports {
+        let (status, detail) = match &report.outcome {
+            Outcome::Passed => ("PASS", ""),
+            Outcome::Failed(reason) => ("FAIL", reason.as_str()),
+            Outcome::Skipped(reason) => ("SKIP", reason.as_str()),
+        };
+        let line = format!("{}  {:width$}  {}", status, report.name, detail, width = width);
+        writeln!(table, "{}", line.trim_end()).unwrap();
+    }
+    let count = |status: fn(&Outcome) -> bool| reports.iter().filter(|report| status(&report.outcome)).count();
+    write!(
+        table,
+        "{} passed, {} failed, {} skipped",
+        count(|outcome| matches!(outcome, Outcome::Passed)),
+        count(|outcome| matches!(outcome, Outcome::Failed(_))),
+        count(|outcome| matches!(outcome, Outcome::Skipped(_))),
+    ).unwrap();
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_line_names_up_and_count_outcomes() {
+        let reports = [
+            Report { name: "div_write".to_string(), outcome: Outcome::Passed },
+            Report { name: "ei_timing".to_string(), outcome: Outcome::Failed("No LD B,B".to_string()) },
+            Report { name: "tetris".to_string(), outcome: Outcome::Skipped("tetris.gb not found".to_string()) },
+        ];
+        assert_eq!(
+            table(&reports),
+            "PASS  div_write\nFAIL  ei_timing  No LD B,B\nSKIP  tetris     tetris.gb not found\n1 passed, 1 failed, 1 skipped"
+        );
+    }
+
+    #[test]
+    fn panics_become_errors() {
+        assert_eq!(guarded(|| 1), Ok(1));
+        assert_eq!(guarded(|| -> u8 { panic!("Unknown opcode 0xd3") }), Err("Emulation stopped: Unknown opcode 0xd3".to_string()));
+    }
+
+    #[test]
+    fn panics_are_only_silenced_inside_guarded() {
+        assert_eq!(guarded(|| QUIET.with(Cell::get)), Ok(true));
+        assert_eq!(guarded(|| guarded(|| ()).map(|_| QUIET.with(Cell::get))), Ok(Ok(true)));
+        assert!(!QUIET.with(Cell::get));
+        let _ = guarded(|| -> u8 { panic!("Unknown opcode 0xd3") });
+        assert!(!QUIET.with(Cell::get));
+    }
+}
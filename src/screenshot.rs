@@ -0,0 +1,65 @@
+use std::path::{Path, PathBuf};
+use crate::graphics::{FrameBuffer, Palette, PaletteStage, Rgba8888, NUMBER_OF_PIXELS, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::utils::write_png;
+
+/// Renders `frame` with `palette`, every pixel drawn as a `scale` by
+/// `scale` square, and returns its [R, G, B, A] pixels row by row.
+pub fn render(frame: &FrameBuffer, palette: Palette, scale: usize) -> Vec<[u8; 4]> {
+    let mut native = [[0; 4]; NUMBER_OF_PIXELS];
+    Rgba8888(palette).render(frame, &mut native);
+    if scale == 1 {
+        return native.to_vec();
+    }
+    native
+        .chunks(SCREEN_WIDTH)
+        .flat_map(|row| {
+            let row: Vec<[u8; 4]> = row.iter().flat_map(|&pixel| std::iter::repeat_n(pixel, scale)).collect();
+            std::iter::repeat_n(row, scale).flatten()
+        })
+        .collect()
+}
+
+/// Saves `frame` as a PNG image, `scale` times the size of the screen.
+pub fn save(path: &Path, frame: &FrameBuffer, palette: Palette, scale: usize) -> Result<(), String> {
+    write_png(path, SCREEN_WIDTH * scale, SCREEN_HEIGHT * scale, &render(frame, palette, scale))
+}
+
+/// The first of `tetris-1.png`, `tetris-2.png`... that does not exist yet,
+/// next to `rom` for `tetris.gb`.
+pub fn next_path(rom: &Path) -> PathBuf {
+    let name = rom.file_stem().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    (1..)
+        .map(|index| rom.with_file_name(format!("{}-{}.png", name, index)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::ColorMode;
+
+    #[test]
+    fn scaled_pixels_are_squares() {
+        let mut frame = FrameBuffer::new(ColorMode::Shade);
+        frame.set(1, 0, 3);
+        let pixels = render(&frame, Palette::GREYSCALE, 2);
+        assert_eq!(pixels.len(), NUMBER_OF_PIXELS * 4);
+        let black = [0, 0, 0, 0xFF];
+        let width = SCREEN_WIDTH * 2;
+        let dark: Vec<usize> = pixels.iter().enumerate().filter(|(_, &pixel)| pixel == black).map(|(index, _)| index).collect();
+        assert_eq!(dark, [2, 3, width + 2, width + 3]);
+    }
+
+    #[test]
+    fn paths_are_numbered_next_to_the_rom() {
+        let directory = std::env::temp_dir().join(format!("screenshots-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let rom = directory.join("tetris.gb");
+        assert_eq!(next_path(&rom), directory.join("tetris-1.png"));
+        std::fs::write(directory.join("tetris-1.png"), []).unwrap();
+        let next = next_path(&rom);
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(next, directory.join("tetris-2.png"));
+    }
+}
@@ -0,0 +1,417 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::rc::Rc;
+use crate::state::{StateReader, StateWriter};
+
+pub(crate) const SB_ADDRESS: usize = 0xFF01;
+pub(crate) const SC_ADDRESS: usize = 0xFF02;
+
+pub(crate) const SERIAL_INTERRUPT: u8 = 1 << 3;
+
+const SC_TRANSFER: u8 = 1 << 7;
+const SC_INTERNAL_CLOCK: u8 = 1;
+// The internal clock runs at 8192 Hz, one bit every 512 clock cycles.
+const CYCLES_PER_BIT: u16 = 512;
+
+/// Whatever is plugged at the other end of the link cable.
+pub trait SerialEndpoint {
+    /// Called when the Game Boy drives the clock: `byte` is sent and the
+    /// byte sent back by the other side is returned.
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    /// Called while the Game Boy waits for the other side to drive the
+    /// clock. If it started a transfer, it gets `byte` and what it sent is
+    /// returned.
+    fn respond(&mut self, byte: u8) -> Option<u8>;
+
+    /// Called every step with the clock cycles that went by, for endpoints
+    /// that need to keep time.
+    fn tick(&mut self, _cycles: u16) {}
+}
+
+/// Nothing plugged in: the line stays high so 0xFF is received, and
+/// nobody ever drives the clock.
+pub struct Disconnected;
+
+impl SerialEndpoint for Disconnected {
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+
+    fn respond(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+}
+
+/// Copies every byte the game sends to stdout and/or files, the way test
+/// ROMs report their results. Like with nothing plugged in, 0xFF is
+/// received in exchange. A writer that fails, say a closed pipe, is
+/// reported once and dropped, the others carry on.
+pub struct SerialOutput {
+    writers: Vec<Box<dyn Write>>,
+}
+
+impl SerialOutput {
+    pub fn new(writers: Vec<Box<dyn Write>>) -> SerialOutput {
+        SerialOutput { writers }
+    }
+}
+
+impl SerialEndpoint for SerialOutput {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.writers.retain_mut(|writer| {
+            // Flush right away so whoever watches the output sees results
+            // as soon as they are printed.
+            let written = writer.write_all(&[byte]).and_then(|_| writer.flush());
+            if let Err(error) = &written {
+                eprintln!("error: Could not write the serial output: {}", error);
+            }
+            written.is_ok()
+        });
+        0xFF
+    }
+
+    fn respond(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+}
+
+/// Watches what is written to it for a string. Only the last bytes, as
+/// many as the string is long, are kept, so it can watch a whole run.
+#[derive(Clone)]
+pub struct SerialCapture(Rc<RefCell<Capture>>);
+
+struct Capture {
+    text: Vec<u8>,
+    tail: VecDeque<u8>,
+    found: bool,
+}
+
+impl SerialCapture {
+    pub fn new(text: &str) -> SerialCapture {
+        let text = text.as_bytes().to_vec();
+        let tail = VecDeque::with_capacity(text.len());
+        SerialCapture(Rc::new(RefCell::new(Capture { text, tail, found: false })))
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow().text).into_owned()
+    }
+
+    /// Whether the string was written since the capture was made. An empty
+    /// string is never found.
+    pub fn found(&self) -> bool {
+        self.0.borrow().found
+    }
+}
+
+impl Write for SerialCapture {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        let capture = &mut *self.0.borrow_mut();
+        for &byte in buffer {
+            if capture.found || capture.text.is_empty() {
+                break;
+            }
+            if capture.tail.len() == capture.text.len() {
+                capture.tail.pop_front();
+            }
+            capture.tail.push_back(byte);
+            capture.found = capture.tail.iter().eq(capture.text.iter());
+        }
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Lets an endpoint outlive the Game Boy it is plugged in, for instance
+/// to keep it across resets.
+pub struct SharedEndpoint<E: SerialEndpoint>(pub Rc<RefCell<E>>);
+
+impl<E: SerialEndpoint> SerialEndpoint for SharedEndpoint<E> {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.0.borrow_mut().exchange(byte)
+    }
+
+    fn respond(&mut self, byte: u8) -> Option<u8> {
+        self.0.borrow_mut().respond(byte)
+    }
+
+    fn tick(&mut self, cycles: u16) {
+        self.0.borrow_mut().tick(cycles)
+    }
+}
+
+/// SB and SC.
+pub(crate) struct Serial {
+    sb: u8,
+    sc: u8,
+    endpoint: Box<dyn SerialEndpoint>,
+    // The byte being shifted in during an internal clock transfer.
+    incoming: u8,
+    bits_left: u8,
+    cycles: u16,
+}
+
+impl Serial {
+    pub(crate) fn new() -> Serial {
+        Serial {
+            sb: 0,
+            sc: 0,
+            endpoint: Box::new(Disconnected),
+            incoming: 0,
+            bits_left: 0,
+            cycles: 0,
+        }
+    }
+
+    pub(crate) fn connect(&mut self, endpoint: Box<dyn SerialEndpoint>) {
+        self.endpoint = endpoint;
+    }
+
+    pub(crate) fn read_register(&self, address: usize) -> u8 {
+        match address {
+            SB_ADDRESS => self.sb,
+            // Unused bits read as 1.
+            SC_ADDRESS => 0x7E | self.sc,
+            _ => panic!("Not a serial register {:x}", address),
+        }
+    }
+
+    pub(crate) fn write_register(&mut self, address: usize, value: u8) {
+        match address {
+            SB_ADDRESS => self.sb = value,
+            SC_ADDRESS => {
+                self.sc = value & (SC_TRANSFER | SC_INTERNAL_CLOCK);
+                if self.sc == SC_TRANSFER | SC_INTERNAL_CLOCK {
+                    self.incoming = self.endpoint.exchange(self.sb);
+                    self.bits_left = 8;
+                    self.cycles = 0;
+                } else {
+                    self.bits_left = 0;
+                }
+            }
+            _ => panic!("Not a serial register {:x}", address),
+        }
+    }
+
+    /// Advances the transfer by `cycles` clock cycles and returns the
+    /// interrupts it requested, as IF bits.
+    pub(crate) fn step(&mut self, cycles: u16) -> u8 {
+        self.endpoint.tick(cycles);
+        if self.sc & SC_TRANSFER == 0 {
+            return 0;
+        }
+        if self.sc & SC_INTERNAL_CLOCK == 0 {
+            return match self.endpoint.respond(self.sb) {
+                Some(byte) => {
+                    self.sb = byte;
+                    self.finish_transfer()
+                }
+                None => 0,
+            };
+        }
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_BIT && self.bits_left > 0 {
+            self.cycles -= CYCLES_PER_BIT;
+            self.bits_left -= 1;
+            // Bits go out and come in most significant first.
+            self.sb = self.sb << 1 | (self.incoming >> self.bits_left) & 1;
+        }
+        if self.bits_left == 0 {
+            self.finish_transfer()
+        } else {
+            0
+        }
+    }
+
+    fn finish_transfer(&mut self) -> u8 {
+        self.sc &= !SC_TRANSFER;
+        SERIAL_INTERRUPT
+    }
+
+    /// The endpoint is not saved, whatever is plugged stays plugged.
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.sb);
+        state.u8(self.sc);
+        state.u8(self.incoming);
+        state.u8(self.bits_left);
+        state.u16(self.cycles);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.sb = state.u8()?;
+        self.sc = state.u8()?;
+        self.incoming = state.u8()?;
+        self.bits_left = state.u8()?;
+        self.cycles = state.u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sends `reply` back, and drives the clock once when `driving` is set.
+    struct Peer {
+        received: Vec<u8>,
+        reply: u8,
+        driving: bool,
+    }
+
+    impl SerialEndpoint for Peer {
+        fn exchange(&mut self, byte: u8) -> u8 {
+            self.received.push(byte);
+            self.reply
+        }
+
+        fn respond(&mut self, byte: u8) -> Option<u8> {
+            if !std::mem::take(&mut self.driving) {
+                return None;
+            }
+            self.received.push(byte);
+            Some(self.reply)
+        }
+    }
+
+    fn plugged(reply: u8) -> (Serial, Rc<RefCell<Peer>>) {
+        let peer = Rc::new(RefCell::new(Peer { received: Vec::new(), reply, driving: false }));
+        let mut serial = Serial::new();
+        serial.connect(Box::new(SharedEndpoint(peer.clone())));
+        (serial, peer)
+    }
+
+    fn step(serial: &mut Serial, cycles: u32) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..cycles / 4 {
+            interrupts |= serial.step(4);
+        }
+        interrupts
+    }
+
+    #[test]
+    fn internal_clock_transfers_take_eight_bits_of_512_cycles() {
+        let (mut serial, peer) = plugged(0xA5);
+        serial.write_register(SB_ADDRESS, 0x42);
+        serial.write_register(SC_ADDRESS, 0x81);
+        assert_eq!(peer.borrow().received, [0x42]);
+
+        // Bits come in most significant first as the others go out.
+        assert_eq!(step(&mut serial, 4 * 512), 0);
+        assert_eq!(serial.read_register(SB_ADDRESS), 0x2A);
+        assert_eq!(step(&mut serial, 4 * 512 - 4), 0);
+        assert_eq!(serial.read_register(SC_ADDRESS), 0xFF);
+
+        assert_eq!(step(&mut serial, 4), SERIAL_INTERRUPT);
+        assert_eq!(serial.read_register(SB_ADDRESS), 0xA5);
+        assert_eq!(serial.read_register(SC_ADDRESS), 0x7F);
+        assert_eq!(step(&mut serial, 8 * 512), 0);
+    }
+
+    #[test]
+    fn nothing_plugged_receives_ff() {
+        let mut serial = Serial::new();
+        serial.write_register(SB_ADDRESS, 0x42);
+        serial.write_register(SC_ADDRESS, 0x81);
+        assert_eq!(step(&mut serial, 8 * 512), SERIAL_INTERRUPT);
+        assert_eq!(serial.read_register(SB_ADDRESS), 0xFF);
+    }
+
+    #[test]
+    fn external_clock_transfers_wait_for_the_other_side() {
+        let (mut serial, peer) = plugged(0x99);
+        serial.write_register(SB_ADDRESS, 0x42);
+        serial.write_register(SC_ADDRESS, 0x80);
+        assert_eq!(step(&mut serial, 100 * 512), 0);
+        assert_eq!(serial.read_register(SC_ADDRESS), 0xFE);
+        assert!(peer.borrow().received.is_empty());
+
+        peer.borrow_mut().driving = true;
+        assert_eq!(serial.step(4), SERIAL_INTERRUPT);
+        assert_eq!(peer.borrow().received, [0x42]);
+        assert_eq!(serial.read_register(SB_ADDRESS), 0x99);
+        assert_eq!(serial.read_register(SC_ADDRESS), 0x7E);
+    }
+
+    #[test]
+    fn clearing_the_transfer_bit_cancels_the_transfer() {
+        let (mut serial, _) = plugged(0xA5);
+        serial.write_register(SB_ADDRESS, 0x42);
+        serial.write_register(SC_ADDRESS, 0x81);
+        serial.write_register(SC_ADDRESS, 0x01);
+        assert_eq!(step(&mut serial, 8 * 512), 0);
+        assert_eq!(serial.read_register(SB_ADDRESS), 0x42);
+    }
+
+    // Fails on every write after the first `room` bytes.
+    struct Pipe {
+        room: usize,
+        writes: Rc<RefCell<usize>>,
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, _buffer: &[u8]) -> std::io::Result<usize> {
+            *self.writes.borrow_mut() += 1;
+            if self.room == 0 {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            self.room -= 1;
+            Ok(1)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failing_writers_are_dropped() {
+        let writes = Rc::new(RefCell::new(0));
+        let capture = SerialCapture::new("ok");
+        let mut output = SerialOutput::new(vec![Box::new(Pipe { room: 1, writes: writes.clone() }), Box::new(capture.clone())]);
+        for byte in *b"ok" {
+            assert_eq!(output.exchange(byte), 0xFF);
+        }
+        output.exchange(b'!');
+        assert_eq!(*writes.borrow(), 2);
+        assert!(capture.found());
+    }
+
+    #[test]
+    fn captures_find_text_across_writes() {
+        let capture = SerialCapture::new("Passed");
+        let mut writer = capture.clone();
+        writer.write_all(b"cpu_instrs\n\nPas").unwrap();
+        assert!(!capture.found());
+        writer.write_all(b"sed").unwrap();
+        assert!(capture.found());
+        assert_eq!(capture.0.borrow().tail.len(), "Passed".len());
+        // Found stays found, whatever comes next.
+        writer.write_all(b"\nFailed").unwrap();
+        assert!(capture.found());
+    }
+
+    #[test]
+    fn captures_keep_only_a_tail() {
+        let capture = SerialCapture::new("ab");
+        let mut writer = capture.clone();
+        for _ in 0..1000 {
+            writer.write_all(b"aa").unwrap();
+        }
+        assert_eq!(capture.0.borrow().tail.len(), 2);
+        assert!(!capture.found());
+        writer.write_all(b"b").unwrap();
+        assert!(capture.found());
+    }
+
+    #[test]
+    fn empty_text_is_never_found() {
+        let capture = SerialCapture::new("");
+        capture.clone().write_all(b"anything").unwrap();
+        assert!(!capture.found());
+    }
+}
@@ -0,0 +1,109 @@
+const MAGIC: &[u8; 7] = b"GBSTATE";
+// Bumped whenever a component saves different fields.
+const VERSION: u8 = 4;
+
+/// Builds a save state.
+///
+/// A save state is a flat list of little-endian fields that every component
+/// writes, then reads back, in the same order. Host-side things such as
+/// plugged endpoints or the audio resampler are not part of it.
+pub(crate) struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn new() -> StateWriter {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        StateWriter { bytes }
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub(crate) fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn bytes(&mut self, values: &[u8]) {
+        self.bytes.extend_from_slice(values);
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads a save state back, field by field.
+pub(crate) struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Result<StateReader<'a>, String> {
+        if !bytes.starts_with(MAGIC) {
+            return Err("Not a save state".to_string());
+        }
+        let mut reader = StateReader { bytes, position: MAGIC.len() };
+        match reader.u8()? {
+            VERSION => Ok(reader),
+            version => Err(format!("Save state version {} is not supported, expected {}", version, VERSION)),
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let bytes = self.bytes
+            .get(self.position..self.position + length)
+            .ok_or_else(|| "Save state is truncated".to_string())?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn bytes(&mut self, values: &mut [u8]) -> Result<(), String> {
+        values.copy_from_slice(self.take(values.len())?);
+        Ok(())
+    }
+
+    /// Makes sure everything was read.
+    pub(crate) fn finish(self) -> Result<(), String> {
+        if self.position == self.bytes.len() {
+            Ok(())
+        } else {
+            Err("Save state is too long".to_string())
+        }
+    }
+}
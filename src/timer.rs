@@ -0,0 +1,366 @@
+use crate::state::{StateReader, StateWriter};
+
+pub(crate) const DIV_ADDRESS: usize = 0xFF04;
+pub(crate) const TIMA_ADDRESS: usize = 0xFF05;
+pub(crate) const TMA_ADDRESS: usize = 0xFF06;
+pub(crate) const TAC_ADDRESS: usize = 0xFF07;
+
+pub(crate) const TIMER_INTERRUPT: u8 = 1 << 2;
+
+const TAC_ENABLE: u8 = 1 << 2;
+const M_CYCLE: u16 = 4;
+// The APU frame sequencer is clocked when this divider bit falls, at 512 Hz.
+const FRAME_SEQUENCER_BIT: u16 = 12;
+
+/// DIV, TIMA, TMA and TAC.
+///
+/// Everything is driven by a 16-bit counter incremented every clock cycle,
+/// DIV being its upper byte. TIMA is incremented when the counter bit picked
+/// by TAC, ANDed with the enable bit, goes from 1 to 0. This is why writing
+/// to DIV or TAC can increment TIMA out of the blue.
+pub(crate) struct Timer {
+    divider: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA overflowed during the last M-cycle, it reads 0 until TMA is
+    // copied to it at the end of the next one.
+    overflow: bool,
+    // TMA was copied to TIMA during the last M-cycle.
+    reloading: bool,
+    // Falling edges of the frame sequencer bit not yet handed to the APU.
+    frame_sequencer_ticks: u8,
+}
+
+impl Timer {
+    pub(crate) fn new() -> Timer {
+        Timer {
+            divider: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow: false,
+            reloading: false,
+            frame_sequencer_ticks: 0,
+        }
+    }
+
+    pub(crate) fn read_register(&self, address: usize) -> u8 {
+        match address {
+            DIV_ADDRESS => (self.divider >> 8) as u8,
+            TIMA_ADDRESS => self.tima,
+            TMA_ADDRESS => self.tma,
+            // Only the three lowest bits exist.
+            TAC_ADDRESS => 0xF8 | self.tac,
+            _ => panic!("Not a timer register {:x}", address),
+        }
+    }
+
+    pub(crate) fn write_register(&mut self, address: usize, value: u8) {
+        match address {
+            DIV_ADDRESS => {
+                let signal = self.signal();
+                self.count_frame_sequencer_tick(self.divider, 0);
+                self.divider = 0;
+                self.detect_falling_edge(signal);
+            }
+            TIMA_ADDRESS => {
+                // Writing during the overflow cycle cancels the reload and
+                // the interrupt, writing while TMA is copied has no effect.
+                if !self.reloading {
+                    self.tima = value;
+                    self.overflow = false;
+                }
+            }
+            TMA_ADDRESS => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            TAC_ADDRESS => {
+                let signal = self.signal();
+                self.tac = value & 0b111;
+                self.detect_falling_edge(signal);
+            }
+            _ => panic!("Not a timer register {:x}", address),
+        }
+    }
+
+    /// Advances the timer by `cycles` clock cycles and returns the
+    /// interrupts it requested, as IF bits.
+    pub(crate) fn step(&mut self, cycles: u16) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..cycles / M_CYCLE {
+            interrupts |= self.tick();
+        }
+        interrupts
+    }
+
+    fn tick(&mut self) -> u8 {
+        let mut interrupts = 0;
+        self.reloading = false;
+        if self.overflow {
+            self.overflow = false;
+            self.reloading = true;
+            self.tima = self.tma;
+            interrupts |= TIMER_INTERRUPT;
+        }
+        let signal = self.signal();
+        let divider = self.divider.wrapping_add(M_CYCLE);
+        self.count_frame_sequencer_tick(self.divider, divider);
+        self.divider = divider;
+        self.detect_falling_edge(signal);
+        interrupts
+    }
+
+    fn count_frame_sequencer_tick(&mut self, previous: u16, next: u16) {
+        if (previous >> FRAME_SEQUENCER_BIT) & 1 != 0 && (next >> FRAME_SEQUENCER_BIT) & 1 == 0 {
+            self.frame_sequencer_ticks += 1;
+        }
+    }
+
+    /// How many times the APU frame sequencer should have been clocked
+    /// since the last call.
+    pub(crate) fn take_frame_sequencer_ticks(&mut self) -> u8 {
+        std::mem::take(&mut self.frame_sequencer_ticks)
+    }
+
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0b11 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        self.tac & TAC_ENABLE != 0 && (self.divider >> bit) & 1 != 0
+    }
+
+    fn detect_falling_edge(&mut self, previous_signal: bool) {
+        if previous_signal && !self.signal() {
+            let (tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = tima;
+            self.overflow |= overflow;
+        }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.divider);
+        state.u8(self.tima);
+        state.u8(self.tma);
+        state.u8(self.tac);
+        state.bool(self.overflow);
+        state.bool(self.reloading);
+        state.u8(self.frame_sequencer_ticks);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.divider = state.u16()?;
+        self.tima = state.u8()?;
+        self.tma = state.u8()?;
+        self.tac = state.u8()?;
+        self.overflow = state.bool()?;
+        self.reloading = state.bool()?;
+        self.frame_sequencer_ticks = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // TAC values, with the timer enabled.
+    const EVERY_1024_CYCLES: u8 = TAC_ENABLE;
+    const EVERY_16_CYCLES: u8 = TAC_ENABLE | 0b01;
+    const EVERY_64_CYCLES: u8 = TAC_ENABLE | 0b10;
+
+    // Runs until TIMA overflows, leaving the timer in the M-cycle right
+    // after the overflow, when TIMA reads 0.
+    fn overflowing_timer(tma: u8) -> (Timer, u8) {
+        let mut timer = Timer::new();
+        timer.write_register(TMA_ADDRESS, tma);
+        timer.write_register(TIMA_ADDRESS, 0xFF);
+        timer.write_register(TAC_ADDRESS, EVERY_16_CYCLES);
+        let interrupts = timer.step(16);
+        (timer, interrupts)
+    }
+
+    #[test]
+    fn div_is_the_upper_byte_of_the_counter() {
+        let mut timer = Timer::new();
+        timer.step(252);
+        assert_eq!(timer.read_register(DIV_ADDRESS), 0);
+        timer.step(4);
+        assert_eq!(timer.read_register(DIV_ADDRESS), 1);
+        timer.step(256 * 10);
+        assert_eq!(timer.read_register(DIV_ADDRESS), 11);
+    }
+
+    #[test]
+    fn writing_div_resets_it() {
+        let mut timer = Timer::new();
+        timer.step(1024);
+        timer.write_register(DIV_ADDRESS, 0x42);
+        assert_eq!(timer.read_register(DIV_ADDRESS), 0);
+    }
+
+    #[test]
+    fn tima_follows_the_selected_frequency() {
+        for (tac, period) in [(EVERY_1024_CYCLES, 1024), (EVERY_16_CYCLES, 16), (EVERY_64_CYCLES, 64), (TAC_ENABLE | 0b11, 256)] {
+            let mut timer = Timer::new();
+            timer.write_register(TAC_ADDRESS, tac);
+            timer.step(period - 4);
+            assert_eq!(timer.read_register(TIMA_ADDRESS), 0);
+            timer.step(4);
+            assert_eq!(timer.read_register(TIMA_ADDRESS), 1);
+            timer.step(period * 9);
+            assert_eq!(timer.read_register(TIMA_ADDRESS), 10);
+        }
+    }
+
+    #[test]
+    fn tima_does_not_move_when_disabled() {
+        let mut timer = Timer::new();
+        timer.write_register(TAC_ADDRESS, 0b01);
+        timer.step(4096);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 0);
+        assert_eq!(timer.read_register(TAC_ADDRESS), 0xF9);
+    }
+
+    #[test]
+    fn tima_reload_is_delayed_by_one_m_cycle() {
+        // mooneye: acceptance/timer/tima_reload
+        let (mut timer, interrupts) = overflowing_timer(0x42);
+        assert_eq!(interrupts, 0);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 0);
+        assert_eq!(timer.step(4), TIMER_INTERRUPT);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 0x42);
+    }
+
+    #[test]
+    fn writing_tima_during_the_overflow_cycle_cancels_the_reload() {
+        // mooneye: acceptance/timer/tima_write_reloading
+        let (mut timer, _) = overflowing_timer(0x42);
+        timer.write_register(TIMA_ADDRESS, 0x10);
+        assert_eq!(timer.step(4), 0);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 0x10);
+    }
+
+    #[test]
+    fn writing_tima_while_tma_is_copied_is_ignored() {
+        // mooneye: acceptance/timer/tima_write_reloading
+        let (mut timer, _) = overflowing_timer(0x42);
+        timer.step(4);
+        timer.write_register(TIMA_ADDRESS, 0x10);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 0x42);
+    }
+
+    #[test]
+    fn writing_tma_while_it_is_copied_also_updates_tima() {
+        // mooneye: acceptance/timer/tma_write_reloading
+        let (mut timer, _) = overflowing_timer(0x42);
+        timer.step(4);
+        timer.write_register(TMA_ADDRESS, 0x24);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 0x24);
+    }
+
+    #[test]
+    fn writing_tma_before_the_reload_uses_the_new_value() {
+        let (mut timer, _) = overflowing_timer(0x42);
+        timer.write_register(TMA_ADDRESS, 0x24);
+        timer.step(4);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 0x24);
+    }
+
+    #[test]
+    fn writing_div_with_the_selected_bit_set_increments_tima() {
+        // mooneye: acceptance/timer/div_write and tim01_div_trigger
+        let mut timer = Timer::new();
+        timer.write_register(TAC_ADDRESS, EVERY_16_CYCLES);
+        timer.step(8);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 0);
+        timer.write_register(DIV_ADDRESS, 0);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 1);
+
+        // With the bit cleared nothing happens.
+        timer.step(4);
+        timer.write_register(DIV_ADDRESS, 0);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 1);
+    }
+
+    #[test]
+    fn div_write_resets_the_timer_period() {
+        // mooneye: acceptance/timer/tim00_div_trigger
+        let mut timer = Timer::new();
+        timer.write_register(TAC_ADDRESS, EVERY_1024_CYCLES);
+        timer.step(256);
+        timer.write_register(DIV_ADDRESS, 0);
+        timer.step(1020);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 0);
+        timer.step(4);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 1);
+    }
+
+    #[test]
+    fn disabling_the_timer_with_the_selected_bit_set_increments_tima() {
+        // mooneye: acceptance/timer/rapid_toggle
+        let mut timer = Timer::new();
+        timer.write_register(TAC_ADDRESS, EVERY_16_CYCLES);
+        timer.step(8);
+        timer.write_register(TAC_ADDRESS, 0b01);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 1);
+    }
+
+    #[test]
+    fn changing_frequency_can_increment_tima() {
+        // Bit 3 is set and bit 9 is not.
+        let mut timer = Timer::new();
+        timer.write_register(TAC_ADDRESS, EVERY_16_CYCLES);
+        timer.step(8);
+        timer.write_register(TAC_ADDRESS, EVERY_1024_CYCLES);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 1);
+    }
+
+    #[test]
+    fn overflow_from_a_div_write_is_reloaded_too() {
+        let mut timer = Timer::new();
+        timer.write_register(TMA_ADDRESS, 0x80);
+        timer.write_register(TIMA_ADDRESS, 0xFF);
+        timer.write_register(TAC_ADDRESS, EVERY_16_CYCLES);
+        timer.step(8);
+        timer.write_register(DIV_ADDRESS, 0);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 0);
+        assert_eq!(timer.step(4), TIMER_INTERRUPT);
+        assert_eq!(timer.read_register(TIMA_ADDRESS), 0x80);
+    }
+
+    #[test]
+    fn frame_sequencer_ticks_when_div_bit_12_falls() {
+        let mut timer = Timer::new();
+        timer.step(8188);
+        assert_eq!(timer.take_frame_sequencer_ticks(), 0);
+        timer.step(4);
+        assert_eq!(timer.take_frame_sequencer_ticks(), 1);
+        assert_eq!(timer.take_frame_sequencer_ticks(), 0);
+        // 512 times per second of 4194304 cycles.
+        let ticks: u32 = (0..4194304 / 4096)
+            .map(|_| {
+                timer.step(4096);
+                timer.take_frame_sequencer_ticks() as u32
+            })
+            .sum();
+        assert_eq!(ticks, 512);
+    }
+
+    #[test]
+    fn writing_div_with_bit_12_set_ticks_the_frame_sequencer() {
+        let mut timer = Timer::new();
+        timer.step(4096);
+        timer.write_register(DIV_ADDRESS, 0);
+        assert_eq!(timer.take_frame_sequencer_ticks(), 1);
+        timer.step(4092);
+        timer.write_register(DIV_ADDRESS, 0);
+        assert_eq!(timer.take_frame_sequencer_ticks(), 0);
+    }
+}
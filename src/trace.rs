@@ -0,0 +1,123 @@
+use std::io::{BufWriter, Write};
+use crate::CpuRegisters;
+
+/// When a trace starts and stops. Tracing starts as soon as any start
+/// condition is met, right away if there are none, and stops for good as
+/// soon as any stop condition is met.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct TraceWindow {
+    pub start_pc: Option<u16>,
+    pub start_cycle: Option<u64>,
+    /// The instruction at this address is not traced anymore.
+    pub stop_pc: Option<u16>,
+    pub stop_cycle: Option<u64>,
+}
+
+/// Writes one line per instruction, with the registers and the four bytes
+/// at PC before it runs, in the format of gameboy-doctor, so runs can be
+/// diffed against reference logs:
+///
+/// ```text
+/// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+/// ```
+pub struct Tracer {
+    writer: BufWriter<Box<dyn Write>>,
+    window: TraceWindow,
+    started: bool,
+    stopped: bool,
+    error: Option<String>,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write>, window: TraceWindow) -> Tracer {
+        let started = window.start_pc.is_none() && window.start_cycle.is_none();
+        Tracer { writer: BufWriter::new(writer), window, started, stopped: false, error: None }
+    }
+
+    pub fn line(registers: &CpuRegisters, pcmem: [u8; 4]) -> String {
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            registers.a, registers.f, registers.b, registers.c, registers.d, registers.e, registers.h, registers.l,
+            registers.sp, registers.pc, pcmem[0], pcmem[1], pcmem[2], pcmem[3]
+        )
+    }
+
+    /// Called before every instruction, `cycles` clock cycles after power on.
+    pub(crate) fn trace(&mut self, registers: &CpuRegisters, pcmem: [u8; 4], cycles: u64) {
+        if self.stopped {
+            return;
+        }
+        let window = &self.window;
+        if window.stop_pc == Some(registers.pc) || window.stop_cycle.is_some_and(|stop| cycles >= stop) {
+            self.stopped = true;
+            return;
+        }
+        self.started |= window.start_pc == Some(registers.pc) || window.start_cycle.is_some_and(|start| cycles >= start);
+        if !self.started {
+            return;
+        }
+        if let Err(error) = writeln!(self.writer, "{}", Tracer::line(registers, pcmem)) {
+            // Give up on the first error rather than leave a log with holes.
+            self.error = Some(format!("Could not write the trace: {}", error));
+            self.stopped = true;
+        }
+    }
+
+    /// Flushes what is left and tells whether the whole trace was written.
+    pub fn finish(mut self) -> Result<(), String> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.writer.flush().map_err(|error| format!("Could not write the trace: {}", error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GameBoy;
+
+    fn trace(window: TraceWindow) -> Vec<String> {
+        let path = std::env::temp_dir().join(format!("trace-{}-{:?}.log", std::process::id(), std::thread::current().id()));
+        let file = std::fs::File::create(&path).unwrap();
+        // XOR A over and over, one byte and four cycles each.
+        let mut game_boy = GameBoy::new(None, vec![0xAF; 0x8000]);
+        game_boy.start_trace(Tracer::new(Box::new(file), window));
+        for _ in 0..10 {
+            game_boy.step_instruction();
+        }
+        game_boy.stop_trace().unwrap().unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        log.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn lines_follow_gameboy_doctor() {
+        let registers = CpuRegisters { a: 0x01, f: 0xB0, b: 0, c: 0x13, d: 0, e: 0xD8, h: 0x01, l: 0x4D, sp: 0xFFFE, pc: 0x0100 };
+        assert_eq!(
+            Tracer::line(&registers, [0x00, 0xC3, 0x13, 0x02]),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02"
+        );
+    }
+
+    #[test]
+    fn every_instruction_is_traced_before_it_runs() {
+        let lines = trace(TraceWindow::default());
+        assert_eq!(lines.len(), 10);
+        // The first line of every gameboy-doctor reference log, but for the
+        // ROM.
+        assert_eq!(lines[0], "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:AF,AF,AF,AF");
+        // XOR A clears A and sets the zero flag alone.
+        assert!(lines[1].starts_with("A:00 F:80 ") && lines[1].contains(" PC:0101 "));
+    }
+
+    #[test]
+    fn tracing_starts_and_stops_on_conditions() {
+        let pcs = |window| trace(window).iter().map(|line| line[line.find("PC:").unwrap() + 3..][..4].to_string()).collect::<Vec<_>>();
+        assert_eq!(pcs(TraceWindow { start_cycle: Some(8), stop_pc: Some(0x105), ..TraceWindow::default() }), ["0102", "0103", "0104"]);
+        assert_eq!(pcs(TraceWindow { start_pc: Some(0x107), ..TraceWindow::default() }), ["0107", "0108", "0109"]);
+        // The instruction starting on the stop cycle is left out.
+        assert_eq!(pcs(TraceWindow { stop_cycle: Some(4), ..TraceWindow::default() }), ["0100"]);
+    }
+}
@@ -1,8 +1,42 @@
-use std::io::Read;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::Path;
 
-pub(crate) fn buffer_from_file(path: &str) -> Vec<u8> {
+pub fn buffer_from_file(path: &str) -> Vec<u8> {
     let mut file = std::fs::File::open(path).expect("File not there");
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer).expect("Could not read file");
     buffer
-}
\ No newline at end of file
+}
+
+/// Saves [R, G, B, A] pixels as a PNG image.
+pub fn write_png(path: &Path, width: usize, height: usize, pixels: &[[u8; 4]]) -> Result<(), String> {
+    let file = std::fs::File::create(path).map_err(|error| format!("Could not create {}: {}", path.display(), error))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(pixels.as_flattened()))
+        .map_err(|error| format!("Could not write {}: {}", path.display(), error))
+}
+
+/// Reads a PNG image as [R, G, B, A] pixels, with its width and height.
+pub fn read_png(path: &Path) -> Result<(usize, usize, Vec<[u8; 4]>), String> {
+    let failed = |error: &dyn std::fmt::Display| format!("Could not read {}: {}", path.display(), error);
+    let file = std::fs::File::open(path).map_err(|error| failed(&error))?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    // Palettes are expanded and 16-bit channels stripped to 8 bits.
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|error| failed(&error))?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(|error| failed(&error))?;
+    let bytes = &buffer[..info.buffer_size()];
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => bytes.chunks(4).map(|pixel| [pixel[0], pixel[1], pixel[2], pixel[3]]).collect(),
+        png::ColorType::Rgb => bytes.chunks(3).map(|pixel| [pixel[0], pixel[1], pixel[2], 0xFF]).collect(),
+        png::ColorType::GrayscaleAlpha => bytes.chunks(2).map(|pixel| [pixel[0], pixel[0], pixel[0], pixel[1]]).collect(),
+        png::ColorType::Grayscale => bytes.iter().map(|&grey| [grey, grey, grey, 0xFF]).collect(),
+        png::ColorType::Indexed => return Err(failed(&"indexed colours were not expanded")),
+    };
+    Ok((info.width as usize, info.height as usize, pixels))
+}
@@ -0,0 +1,172 @@
+use emulator::audio::{ChannelStatus, CHANNEL_COUNT};
+use crate::filters::Frame;
+
+pub(crate) const VISUALISER_WIDTH: usize = 320;
+pub(crate) const VISUALISER_HEIGHT: usize = 288;
+
+const LANE_HEIGHT: usize = VISUALISER_HEIGHT / CHANNEL_COUNT;
+// Room for a rising edge to be found before the samples on screen.
+const HISTORY: usize = VISUALISER_WIDTH * 3;
+const LABELS: [&str; CHANNEL_COUNT] = ["SQ1", "SQ2", "WAVE", "NOISE"];
+const COLORS: [u32; CHANNEL_COUNT] = [0xFFE05050, 0xFFE0A030, 0xFF40C0E0, 0xFFA0E060];
+const MUTED_COLOR: u32 = 0xFF505050;
+const TEXT_COLOR: u32 = 0xFFE0E0E0;
+const BACKGROUND: u32 = 0xFF101010;
+const AXIS_COLOR: u32 = 0xFF282828;
+const SEPARATOR_COLOR: u32 = 0xFF404040;
+const FONT_SCALE: usize = 2;
+
+/// An oscilloscope showing the waveform of every channel, one above the
+/// other, with what each one plays.
+///
+/// It is fed the per-channel taps of the APU, where every channel is
+/// already panned and scaled by the master volume.
+pub(crate) struct Visualiser {
+    history: [Vec<f32>; CHANNEL_COUNT],
+}
+
+impl Visualiser {
+    pub(crate) fn new() -> Visualiser {
+        Visualiser { history: std::array::from_fn(|_| vec![0.0; HISTORY]) }
+    }
+
+    pub(crate) fn push(&mut self, channel_samples: &[[[f32; 2]; CHANNEL_COUNT]]) {
+        for (channel, history) in self.history.iter_mut().enumerate() {
+            // A channel alone goes from -0.25 to 0.25 on each side.
+            history.extend(channel_samples.iter().map(|channels| (channels[channel][0] + channels[channel][1]) * 2.0));
+            let excess = history.len().saturating_sub(HISTORY);
+            history.drain(..excess);
+        }
+    }
+
+    pub(crate) fn render(&self, statuses: &[ChannelStatus; CHANNEL_COUNT], muted: [bool; CHANNEL_COUNT]) -> Frame {
+        let mut pixels = vec![BACKGROUND; VISUALISER_WIDTH * VISUALISER_HEIGHT];
+        for (channel, history) in self.history.iter().enumerate() {
+            let top = channel * LANE_HEIGHT;
+            let middle = top + LANE_HEIGHT / 2;
+            let amplitude = (LANE_HEIGHT / 2 - 2) as f32;
+            pixels[middle * VISUALISER_WIDTH..(middle + 1) * VISUALISER_WIDTH].fill(AXIS_COLOR);
+            if channel > 0 {
+                pixels[top * VISUALISER_WIDTH..(top + 1) * VISUALISER_WIDTH].fill(SEPARATOR_COLOR);
+            }
+
+            let color = if muted[channel] { MUTED_COLOR } else { COLORS[channel] };
+            let start = trigger(history);
+            let mut previous = None;
+            for (x, sample) in history[start..start + VISUALISER_WIDTH].iter().enumerate() {
+                let y = (middle as f32 - sample.clamp(-1.0, 1.0) * amplitude) as usize;
+                // Join consecutive samples so edges are drawn as lines.
+                let (from, to) = match previous {
+                    Some(previous) if previous < y => (previous + 1, y),
+                    Some(previous) if previous > y => (y, previous - 1),
+                    _ => (y, y),
+                };
+                for row in from..=to {
+                    pixels[row * VISUALISER_WIDTH + x] = color;
+                }
+                previous = Some(y);
+            }
+
+            let status = statuses[channel];
+            let mut label = if status.playing {
+                format!("{} {}HZ V{}", LABELS[channel], status.frequency.round() as u32, status.volume)
+            } else {
+                format!("{} OFF", LABELS[channel])
+            };
+            if muted[channel] {
+                label.push_str(" MUTED");
+            }
+            draw_text(&mut pixels, &label, 4, top + 4, TEXT_COLOR);
+        }
+        Frame { pixels, width: VISUALISER_WIDTH, height: VISUALISER_HEIGHT }
+    }
+}
+
+/// Where to start drawing so the waveform stands still: on the last rising
+/// edge that leaves a whole screen of samples after it.
+fn trigger(history: &[f32]) -> usize {
+    let latest = history.len() - VISUALISER_WIDTH;
+    (1..=latest)
+        .rev()
+        .find(|&index| history[index - 1] < 0.0 && history[index] >= 0.0)
+        .unwrap_or(latest)
+}
+
+fn draw_text(pixels: &mut [u32], text: &str, x: usize, y: usize, color: u32) {
+    for (index, character) in text.chars().enumerate() {
+        let left = x + index * 4 * FONT_SCALE;
+        for (row, bits) in glyph(character).iter().enumerate() {
+            for column in 0..3 {
+                if bits >> (2 - column) & 1 == 0 {
+                    continue;
+                }
+                for dy in 0..FONT_SCALE {
+                    for dx in 0..FONT_SCALE {
+                        let (px, py) = (left + column * FONT_SCALE + dx, y + row * FONT_SCALE + dy);
+                        if px < VISUALISER_WIDTH && py < VISUALISER_HEIGHT {
+                            pixels[py * VISUALISER_WIDTH + px] = color;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A 3×5 glyph, one row per byte. Only what the labels need is there.
+fn glyph(character: char) -> [u8; 5] {
+    match character {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        _ => [0; 5],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waveforms_stay_in_their_lane() {
+        let mut visualiser = Visualiser::new();
+        let samples: Vec<_> = (0..2000).map(|index| {
+            let level = if index / 50 % 2 == 0 { 0.25 } else { -0.25 };
+            [[level, level], [0.0; 2], [0.0; 2], [0.0; 2]]
+        }).collect();
+        visualiser.push(&samples);
+        let status = ChannelStatus { playing: true, frequency: 440.0, volume: 15 };
+        let frame = visualiser.render(&[status; CHANNEL_COUNT], [false, false, false, true]);
+
+        let lane_of = |color| frame.pixels.iter().enumerate()
+            .filter(|(_, &pixel)| pixel == color)
+            .map(|(index, _)| index / VISUALISER_WIDTH / LANE_HEIGHT)
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(lane_of(COLORS[0]), [0].into());
+        assert_eq!(lane_of(COLORS[1]), [1].into());
+        assert_eq!(lane_of(MUTED_COLOR), [3].into());
+    }
+}
@@ -0,0 +1,311 @@
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use emulator::graphics::{FrameBuffer, Palette, SCREEN_HEIGHT, SCREEN_WIDTH};
+use emulator::joypad::Button;
+use emulator::report::{guarded, Outcome, Report};
+use emulator::screenshot;
+use emulator::utils::{read_png, write_png};
+use emulator::GameBoy;
+
+const MISMATCH_COLOR: [u8; 4] = [0xFF, 0x00, 0x00, 0xFF];
+
+/// ROMs to run and what their screen should show at the end, read from a
+/// TOML file. Paths are relative to the manifest.
+///
+/// ```toml
+/// [[rom]]
+/// name = "tetris-title"
+/// rom = "roms/tetris.gb"
+/// frames = 300
+/// input = "inputs/tetris-start.txt"
+/// image = "expected/tetris-title.png"
+/// palette = "pocket"
+///
+/// [[rom]]
+/// rom = "roms/dmg-acid2.gb"
+/// frames = 60
+/// hash = "0x1f3c5a7e9b2d4f60"
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default, rename = "rom")]
+    pub tests: Vec<GoldenTest>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GoldenTest {
+    /// Names the reports and the images left behind, the ROM file name by
+    /// default.
+    pub name: Option<String>,
+    pub rom: PathBuf,
+    pub frames: u64,
+    /// Buttons to press and release along the way, see `InputScript`.
+    pub input: Option<PathBuf>,
+    /// The expected screen as a PNG image, at the native size or a whole
+    /// multiple of it.
+    pub image: Option<PathBuf>,
+    /// The expected `frame_hash`, which does not depend on any palette.
+    pub hash: Option<String>,
+    /// The palette `image` was taken with, greyscale by default.
+    pub palette: Option<String>,
+}
+
+impl Manifest {
+    /// Reads a manifest and makes its paths absolute.
+    pub fn from_file(path: &Path) -> Result<Manifest, String> {
+        let content = std::fs::read_to_string(path).map_err(|error| format!("Could not read manifest {}: {}", path.display(), error))?;
+        let mut manifest: Manifest = toml::from_str(&content).map_err(|error| format!("Invalid manifest {}: {}", path.display(), error))?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for test in &mut manifest.tests {
+            if test.image.is_some() == test.hash.is_some() {
+                return Err(format!("ROM {} should have either an expected image or a hash", test.rom.display()));
+            }
+            test.rom = directory.join(&test.rom);
+            test.input = test.input.as_ref().map(|input| directory.join(input));
+            test.image = test.image.as_ref().map(|image| directory.join(image));
+        }
+        Ok(manifest)
+    }
+}
+
+impl GoldenTest {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            self.rom.file_stem().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+        })
+    }
+}
+
+/// Button presses and releases, one per line, applied when a frame number
+/// of the run is reached. Lines starting with `#` are comments.
+///
+/// ```text
+/// # Skip the title screen
+/// 120 press start
+/// 125 release start
+/// ```
+pub struct InputScript {
+    // Sorted by frame.
+    events: Vec<(u64, Button, bool)>,
+}
+
+impl InputScript {
+    pub fn parse(script: &str) -> Result<InputScript, String> {
+        let mut events = Vec::new();
+        for (number, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("Line {} of the input script should look like `120 press start`: {}", number + 1, line);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let [frame, action, button] = words[..] else {
+                return Err(invalid());
+            };
+            let frame = frame.parse::<u64>().map_err(|_| invalid())?;
+            let pressed = match action {
+                "press" => true,
+                "release" => false,
+                _ => return Err(invalid()),
+            };
+            let button = Button::from_name(button).ok_or_else(invalid)?;
+            events.push((frame, button, pressed));
+        }
+        events.sort_by_key(|(frame, _, _)| *frame);
+        Ok(InputScript { events })
+    }
+
+    /// Runs `frames` frames, pressing and releasing buttons on the way.
+    pub fn play(&self, game_boy: &mut GameBoy, frames: u64) {
+        let mut events = self.events.iter().peekable();
+        while game_boy.frames() < frames {
+            while let Some((_, button, pressed)) = events.next_if(|(frame, _, _)| *frame <= game_boy.frames()) {
+                game_boy.set_button(*button, *pressed);
+            }
+            game_boy.step_frame();
+        }
+    }
+}
+
+/// A 64-bit FNV-1a hash of the shades or CGB colours of a frame.
+pub fn frame_hash(frame: &FrameBuffer) -> u64 {
+    frame.pixels().iter().flat_map(|pixel| pixel.to_le_bytes()).fold(0xCBF29CE484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001B3)
+    })
+}
+
+/// Runs every ROM of the manifest. What was on screen is saved to `output`
+/// as `NAME-actual.png` when it does not match, next to a `NAME-diff.png`
+/// where differing pixels are red.
+pub fn run(manifest: &Manifest, output: &Path) -> Vec<Report> {
+    manifest
+        .tests
+        .iter()
+        .map(|test| Report { name: test.name(), outcome: run_test(test, output) })
+        .collect()
+}
+
+fn run_test(test: &GoldenTest, output: &Path) -> Outcome {
+    if !test.rom.exists() {
+        return Outcome::Skipped(format!("{} not found", test.rom.display()));
+    }
+    let result = (|| {
+        let input = match &test.input {
+            Some(path) => InputScript::parse(&std::fs::read_to_string(path)
+                .map_err(|error| format!("Could not read {}: {}", path.display(), error))?)?,
+            None => InputScript { events: Vec::new() },
+        };
+        let rom = std::fs::read(&test.rom).map_err(|error| format!("Could not read {}: {}", test.rom.display(), error))?;
+        let game_boy = guarded(|| {
+            let mut game_boy = GameBoy::new(None, rom);
+            input.play(&mut game_boy, test.frames);
+            game_boy
+        })?;
+        check(test, game_boy.frame_buffer(), output)
+    })();
+    match result {
+        Ok(()) => Outcome::Passed,
+        Err(error) => Outcome::Failed(error),
+    }
+}
+
+fn check(test: &GoldenTest, frame: &FrameBuffer, output: &Path) -> Result<(), String> {
+    let palette = test.palette.as_deref().map(Palette::parse).transpose()?.unwrap_or(Palette::GREYSCALE);
+    let save_actual = |scale| {
+        std::fs::create_dir_all(output).map_err(|error| format!("Could not create {}: {}", output.display(), error))?;
+        let path = output.join(format!("{}-actual.png", test.name()));
+        screenshot::save(&path, frame, palette, scale)?;
+        Ok::<PathBuf, String>(path)
+    };
+    if let Some(expected) = &test.hash {
+        let hash = frame_hash(frame);
+        let expected = u64::from_str_radix(expected.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("Invalid hash {}", expected))?;
+        if hash != expected {
+            let actual = save_actual(1)?;
+            return Err(format!("Hash is {:#018x} instead of {:#018x}, see {}", hash, expected, actual.display()));
+        }
+        return Ok(());
+    }
+    let path = test.image.as_ref().unwrap();
+    if !path.exists() {
+        let actual = save_actual(1)?;
+        return Err(format!("{} not found, the screen was saved to {}", path.display(), actual.display()));
+    }
+    let (width, height, expected) = read_png(path)?;
+    let scale = width / SCREEN_WIDTH;
+    if scale == 0 || width != SCREEN_WIDTH * scale || height != SCREEN_HEIGHT * scale {
+        return Err(format!("{} is {}×{}, not a multiple of {}×{}", path.display(), width, height, SCREEN_WIDTH, SCREEN_HEIGHT));
+    }
+    let actual = screenshot::render(frame, palette, scale);
+    let (pixels, mismatches) = diff(&expected, &actual);
+    if mismatches == 0 {
+        return Ok(());
+    }
+    let actual = save_actual(scale)?;
+    let diff_path = output.join(format!("{}-diff.png", test.name()));
+    write_png(&diff_path, width, height, &pixels)?;
+    Err(format!("{} pixels differ, see {} and {}", mismatches, actual.display(), diff_path.display()))
+}
+
+/// Draws matching pixels faded and differing ones in red, and counts them.
+fn diff(expected: &[[u8; 4]], actual: &[[u8; 4]]) -> (Vec<[u8; 4]>, usize) {
+    let mut mismatches = 0;
+    let pixels = expected
+        .iter()
+        .zip(actual)
+        .map(|(expected, actual)| {
+            if expected == actual {
+                let grey = ((expected[0] as u16 + expected[1] as u16 + expected[2] as u16) / 3 / 4 + 0xC0) as u8;
+                [grey, grey, grey, 0xFF]
+            } else {
+                mismatches += 1;
+                MISMATCH_COLOR
+            }
+        })
+        .collect();
+    (pixels, mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Workspace(PathBuf);
+
+    impl Workspace {
+        fn new(name: &str) -> Workspace {
+            let directory = std::env::temp_dir().join(format!("golden-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&directory).unwrap();
+            // XOR A over and over.
+            std::fs::write(directory.join("xor.gb"), vec![0xAF; 0x8000]).unwrap();
+            Workspace(directory)
+        }
+
+        fn run(&self, manifest: &str) -> Vec<Report> {
+            let path = self.0.join("manifest.toml");
+            std::fs::write(&path, manifest).unwrap();
+            run(&Manifest::from_file(&path).unwrap(), &self.0.join("output"))
+        }
+    }
+
+    impl Drop for Workspace {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn hash_of_one_frame() -> u64 {
+        let mut game_boy = GameBoy::new(None, vec![0xAF; 0x8000]);
+        game_boy.step_frame();
+        frame_hash(game_boy.frame_buffer())
+    }
+
+    #[test]
+    fn hashes_are_compared() {
+        let workspace = Workspace::new("hash");
+        let reports = workspace.run(&format!(
+            "[[rom]]\nname = \"right\"\nrom = \"xor.gb\"\nframes = 1\nhash = \"{:#x}\"\n\n\
+             [[rom]]\nname = \"wrong\"\nrom = \"xor.gb\"\nframes = 1\nhash = \"0x1234\"\n\n\
+             [[rom]]\nrom = \"missing.gb\"\nframes = 1\nhash = \"0x1234\"\n",
+            hash_of_one_frame()
+        ));
+        let table = emulator::report::table(&reports);
+        assert!(table.starts_with("PASS  right\nFAIL  wrong    Hash is"), "{}", table);
+        assert!(table.contains("SKIP  missing  "), "{}", table);
+        assert!(table.ends_with("1 passed, 1 failed, 1 skipped"), "{}", table);
+        assert!(workspace.0.join("output/wrong-actual.png").exists());
+    }
+
+    #[test]
+    fn images_are_compared_with_a_diff() {
+        let workspace = Workspace::new("image");
+        let mut game_boy = GameBoy::new(None, vec![0xAF; 0x8000]);
+        game_boy.step_frame();
+        screenshot::save(&workspace.0.join("right.png"), game_boy.frame_buffer(), Palette::LIGHT, 2).unwrap();
+        let mut pixels = screenshot::render(game_boy.frame_buffer(), Palette::GREYSCALE, 1);
+        pixels[0] = [1, 2, 3, 0xFF];
+        write_png(&workspace.0.join("wrong.png"), SCREEN_WIDTH, SCREEN_HEIGHT, &pixels).unwrap();
+
+        let reports = workspace.run(
+            "[[rom]]\nname = \"right\"\nrom = \"xor.gb\"\nframes = 1\nimage = \"right.png\"\npalette = \"light\"\n\n\
+             [[rom]]\nname = \"wrong\"\nrom = \"xor.gb\"\nframes = 1\nimage = \"wrong.png\"\n",
+        );
+        assert!(matches!(reports[0].outcome, Outcome::Passed));
+        assert!(matches!(&reports[1].outcome, Outcome::Failed(reason) if reason.starts_with("1 pixels differ")));
+        let (_, _, diff) = read_png(&workspace.0.join("output/wrong-diff.png")).unwrap();
+        assert_eq!(diff.iter().filter(|&&pixel| pixel == MISMATCH_COLOR).count(), 1);
+        assert_eq!(diff[0], MISMATCH_COLOR);
+    }
+
+    #[test]
+    fn input_scripts_are_sorted_by_frame() {
+        let script = InputScript::parse("# comment\n\n5 release start\n2 press START\n").unwrap();
+        assert_eq!(script.events.len(), 2);
+        assert_eq!(script.events[0], (2, Button::Start, true));
+        assert!(InputScript::parse("2 push start").is_err());
+        assert!(InputScript::parse("2 press turbo").is_err());
+    }
+}
@@ -0,0 +1,25 @@
+//! Runs the ROMs of `tests/golden/manifest.toml`, or of the manifest given
+//! in `GOLDEN_MANIFEST`, and compares their screens with the expected ones.
+//! ROMs can't be shipped with the sources, those missing are skipped.
+
+mod harness;
+
+use std::path::{Path, PathBuf};
+use emulator::report;
+use crate::harness::Manifest;
+
+#[test]
+fn screens_match_the_golden_images() {
+    let path = std::env::var_os("GOLDEN_MANIFEST")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/manifest.toml"));
+    if !path.exists() {
+        eprintln!("No manifest at {}, skipping golden images", path.display());
+        return;
+    }
+    let manifest = Manifest::from_file(&path).unwrap();
+    let reports = harness::run(&manifest, &Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden"));
+    let table = report::table(&reports);
+    eprintln!("{}", table);
+    assert!(reports.iter().all(|report| !report.failed()), "\n{}", table);
+}
@@ -0,0 +1,113 @@
+//! Plays recorded inputs on two linked Game Boys and checks what went over
+//! the link cable.
+
+use emulator::joypad::Button;
+use emulator::{GameBoy, InputEvent, LinkedPair, Side};
+
+// Right waits for the left one to drive the clock, then the left one sends
+// its byte: the order a trade goes in.
+const TRADE: &str = "
+# cycle side action button
+10000 right press a
+10400 right release a
+20000 left press start
+20400 left release start
+";
+
+// The left one sends before the right one is listening.
+const TOO_EARLY: &str = "
+10000 left press start
+20000 right press a
+";
+
+const RUN_CYCLES: u64 = 40000;
+
+fn parse_script(script: &str) -> Vec<InputEvent> {
+    script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let [cycle, side, action, button] = words[..] else {
+                panic!("Bad input line {}", line);
+            };
+            InputEvent {
+                cycle: cycle.parse().unwrap(),
+                side: if side == "left" { Side::Left } else { Side::Right },
+                button: Button::from_name(button).unwrap(),
+                pressed: action == "press",
+            }
+        })
+        .collect()
+}
+
+// LD BC,d16 with the value in B, LD A,B and LD (a16),A.
+fn write(address: u16, value: u8) -> Vec<u8> {
+    vec![0x01, 0x00, value, 0x78, 0xEA, address as u8, (address >> 8) as u8]
+}
+
+// Waits for a button with the joypad interrupt. That handler puts `byte` in
+// SB, starts a transfer with `control` and halts until the serial interrupt,
+// whose handler hits LD B,B and halts for good.
+fn game(byte: u8, control: u8) -> GameBoy {
+    let mut rom = vec![0xAF; 0x8000];
+    let mut main = write(0xFF00, 0x10);
+    main.extend(write(0xFFFF, 0x10));
+    main.extend([0xFB, 0x76]);
+    rom[0x100..0x100 + main.len()].copy_from_slice(&main);
+    let mut handler = write(0xFF01, byte);
+    handler.extend(write(0xFF02, control));
+    handler.extend(write(0xFFFF, 0x08));
+    handler.extend([0xFB, 0x76]);
+    rom[0x60..0x60 + handler.len()].copy_from_slice(&handler);
+    rom[0x58..0x5A].copy_from_slice(&[0x40, 0x76]);
+    GameBoy::new(None, rom)
+}
+
+fn pair() -> LinkedPair {
+    LinkedPair::new(game(0x42, 0x81), game(0x99, 0x80))
+}
+
+#[test]
+fn recorded_trades_go_through() {
+    let inputs = parse_script(TRADE);
+    let mut pair = pair();
+    pair.run(15000, &inputs);
+    assert!(!pair.game_boy_mut(Side::Right).take_breakpoint());
+    assert_eq!(pair.game_boy(Side::Left).read_memory(0xFF02) & 0x80, 0);
+
+    pair.run(RUN_CYCLES - 15000, &inputs);
+    for (side, received) in [(Side::Left, 0x99), (Side::Right, 0x42)] {
+        let game_boy = pair.game_boy_mut(side);
+        assert_eq!(game_boy.read_memory(0xFF01), received, "{:?} side", side);
+        assert!(game_boy.take_breakpoint(), "{:?} side", side);
+    }
+}
+
+#[test]
+fn replays_give_the_same_result() {
+    let inputs = parse_script(TRADE);
+    let states: Vec<[Vec<u8>; 2]> = (0..2)
+        .map(|_| {
+            let mut pair = pair();
+            pair.run(RUN_CYCLES, &inputs);
+            [Side::Left, Side::Right].map(|side| pair.game_boy(side).save_state())
+        })
+        .collect();
+    assert!(states[0] == states[1]);
+}
+
+#[test]
+fn sending_to_nobody_reads_high() {
+    let mut pair = pair();
+    pair.run(RUN_CYCLES, &parse_script(TOO_EARLY));
+    let left = pair.game_boy_mut(Side::Left);
+    assert_eq!(left.read_memory(0xFF01), 0xFF);
+    assert!(left.take_breakpoint());
+    // The right one is still waiting for someone to drive the clock.
+    let right = pair.game_boy_mut(Side::Right);
+    assert_eq!(right.read_memory(0xFF01), 0x99);
+    assert!(!right.take_breakpoint());
+}
+